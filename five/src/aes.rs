@@ -4,6 +4,8 @@ use std::arch::x86_64::{
     _mm_slli_si128, _mm_xor_si128,
 };
 
+use crate::bitsliced::BitslicedAES;
+
 pub const BLOCK_SIZE: usize = 16;
 
 union U8x16 {
@@ -17,6 +19,7 @@ union U32x4 {
 }
 
 pub const ZERO: __m128i = unsafe { (U8x16 { bytes: [0; 16] }).vector };
+pub const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
 const ISOLATE_SBOX_MASK: __m128i = unsafe {
     (U32x4 {
         bytes: [0x070A0D00, 0x0B0E0104, 0x0F020508, 0x0306090C],
@@ -31,19 +34,40 @@ const ISOLATE_SROWS_MASK: __m128i = unsafe {
 };
 
 pub type Block = [u8; BLOCK_SIZE];
-type State = __m128i;
+pub type State = __m128i;
 pub type RoundKey = __m128i;
 
+/// The implementation `AES128::encrypt`, `AES128::encrypt_batch` and
+/// `AES128::decrypt` go through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    AesNi,
+    /// `BitslicedAES`, which runs in constant time in software.
+    Bitsliced,
+}
+
 pub struct AES128 {
     round_keys: Vec<RoundKey>,
     num_rounds: usize,
+    bitsliced: Option<BitslicedAES>,
 }
 
 impl AES128 {
     pub unsafe fn new(key: [u8; BLOCK_SIZE], num_rounds: usize) -> Self {
+        Self::with_backend(key, num_rounds, Backend::AesNi)
+    }
+
+    /// Faults, traces and the single steps always use AES-NI, only whole
+    /// encryptions and decryptions go through `backend`.
+    pub unsafe fn with_backend(key: [u8; BLOCK_SIZE], num_rounds: usize, backend: Backend) -> Self {
         Self {
             round_keys: Self::key_expansion(Self::block_to_state(key)),
             num_rounds,
+            bitsliced: match backend {
+                Backend::AesNi => None,
+                Backend::Bitsliced => Some(BitslicedAES::new(key, num_rounds)),
+            },
         }
     }
 
@@ -100,9 +124,31 @@ impl AES128 {
         round_keys
     }
 
+    /// Walks the key schedule backwards from the round key of round `round`
+    /// and returns the original cipher key.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn invert_key_expansion(round_key: RoundKey, round: usize) -> Block {
+        let mut key = Self::state_to_block(round_key);
+        for r in (1..=round).rev() {
+            for i in (4..BLOCK_SIZE).rev() {
+                key[i] ^= key[i - 4];
+            }
+            let mut rot_word = [0; BLOCK_SIZE];
+            for i in 0..4 {
+                rot_word[i] = key[12 + ((i + 1) & 3)];
+            }
+            let sub_word = Self::state_to_block(Self::sub_bytes(Self::block_to_state(rot_word)));
+            for i in 0..4 {
+                key[i] ^= sub_word[i];
+            }
+            key[0] ^= RCON[r - 1];
+        }
+        key
+    }
+
     #[inline]
     #[target_feature(enable = "avx2,aes")]
-    unsafe fn sub_bytes(state: State) -> State {
+    pub unsafe fn sub_bytes(state: State) -> State {
         let res = _mm_shuffle_epi8(state, ISOLATE_SBOX_MASK);
         _mm_aesenclast_si128(res, ZERO)
     }
@@ -128,7 +174,7 @@ impl AES128 {
 
     #[inline]
     #[target_feature(enable = "avx2,aes")]
-    unsafe fn mix_columns(state: State) -> State {
+    pub unsafe fn mix_columns(state: State) -> State {
         let res = _mm_aesdeclast_si128(state, ZERO);
         _mm_aesenc_si128(res, ZERO)
     }
@@ -141,7 +187,7 @@ impl AES128 {
 
     #[inline]
     #[target_feature(enable = "avx2,aes")]
-    unsafe fn add_round_key(state: State, round_key: RoundKey) -> State {
+    pub unsafe fn add_round_key(state: State, round_key: RoundKey) -> State {
         _mm_xor_si128(state, round_key)
     }
 
@@ -200,6 +246,9 @@ impl AES128 {
     #[inline]
     #[target_feature(enable = "avx2,aes")]
     pub unsafe fn encrypt(&self, msg: Block) -> Block {
+        if let Some(bitsliced) = &self.bitsliced {
+            return bitsliced.encrypt(msg);
+        }
         let msg = Self::block_to_state(msg);
 
         let mut ct = Self::add_round_key(msg, self.round_keys[0]);
@@ -210,9 +259,40 @@ impl AES128 {
         Self::state_to_block(_mm_aesenclast_si128(ct, self.round_keys[self.num_rounds]))
     }
 
+    /// Same as `encrypt` on many blocks, eight at a time to keep the AES-NI
+    /// pipeline busy.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn encrypt_batch(&self, msgs: &[Block]) -> Vec<Block> {
+        if let Some(bitsliced) = &self.bitsliced {
+            return msgs.iter().map(|&msg| bitsliced.encrypt(msg)).collect();
+        }
+        let mut encs = Vec::with_capacity(msgs.len());
+        for chunk in msgs.chunks(8) {
+            let mut states = [ZERO; 8];
+            for (state, &msg) in states.iter_mut().zip(chunk) {
+                *state = Self::add_round_key(Self::block_to_state(msg), self.round_keys[0]);
+            }
+            for i in 1..self.num_rounds {
+                for state in states.iter_mut() {
+                    *state = _mm_aesenc_si128(*state, self.round_keys[i]);
+                }
+            }
+            encs.extend(states[..chunk.len()].iter().map(|&state| {
+                Self::state_to_block(_mm_aesenclast_si128(
+                    state,
+                    self.round_keys[self.num_rounds],
+                ))
+            }));
+        }
+        encs
+    }
+
     #[inline]
     #[target_feature(enable = "avx2,aes")]
     pub unsafe fn decrypt(&self, enc_msg: Block) -> Block {
+        if let Some(bitsliced) = &self.bitsliced {
+            return bitsliced.decrypt(enc_msg);
+        }
         let enc_msg = Self::block_to_state(enc_msg);
 
         let mut pt = Self::inv_sub_bytes(Self::inv_shift_rows(Self::inv_add_round_key(
@@ -227,6 +307,159 @@ impl AES128 {
 
         Self::state_to_block(Self::inv_add_round_key(pt, self.round_keys[0]))
     }
+
+    /// Same as `encrypt`, but hands the state before the MixColumns of round
+    /// `round` to `fault`, which may change it, like a glitch on the device.
+    /// Round `round` must have a MixColumns, i.e. come before the last one.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn encrypt_with_fault<F: FnOnce(&mut Block)>(
+        &self,
+        msg: Block,
+        round: usize,
+        fault: F,
+    ) -> Block {
+        assert!((1..self.num_rounds).contains(&round));
+        let mut state = Self::add_round_key(Self::block_to_state(msg), self.round_keys[0]);
+        for i in 1..round {
+            state = _mm_aesenc_si128(state, self.round_keys[i]);
+        }
+
+        let mut block = Self::state_to_block(Self::shift_rows(Self::sub_bytes(state)));
+        fault(&mut block);
+        state = Self::add_round_key(
+            Self::mix_columns(Self::block_to_state(block)),
+            self.round_keys[round],
+        );
+        for i in round + 1..self.num_rounds {
+            state = _mm_aesenc_si128(state, self.round_keys[i]);
+        }
+
+        Self::state_to_block(_mm_aesenclast_si128(
+            state,
+            self.round_keys[self.num_rounds],
+        ))
+    }
+
+    /// Same as `encrypt`, but records the state after every step of every
+    /// round. Round 0 consists of the initial key addition only.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn encrypt_traced(&self, msg: Block) -> Trace {
+        let mut trace = Trace::default();
+        let mut state = Self::block_to_state(msg);
+
+        state = Self::add_round_key(state, self.round_keys[0]);
+        trace.record(0, Step::AddRoundKey, state);
+        for i in 1..=self.num_rounds {
+            state = Self::sub_bytes(state);
+            trace.record(i, Step::SubBytes, state);
+            state = Self::shift_rows(state);
+            trace.record(i, Step::ShiftRows, state);
+            if i != self.num_rounds {
+                state = Self::mix_columns(state);
+                trace.record(i, Step::MixColumns, state);
+            }
+            state = Self::add_round_key(state, self.round_keys[i]);
+            trace.record(i, Step::AddRoundKey, state);
+        }
+
+        trace
+    }
+
+    /// Same as `decrypt`, but records the state after every step. Rounds are
+    /// numbered as in encryption, so round `num_rounds` is undone first.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn decrypt_traced(&self, enc_msg: Block) -> Trace {
+        let mut trace = Trace::default();
+        let mut state = Self::block_to_state(enc_msg);
+
+        for i in (1..=self.num_rounds).rev() {
+            state = Self::inv_add_round_key(state, self.round_keys[i]);
+            trace.record(i, Step::InvAddRoundKey, state);
+            if i != self.num_rounds {
+                state = Self::inv_mix_columns(state);
+                trace.record(i, Step::InvMixColumns, state);
+            }
+            state = Self::inv_shift_rows(state);
+            trace.record(i, Step::InvShiftRows, state);
+            state = Self::inv_sub_bytes(state);
+            trace.record(i, Step::InvSubBytes, state);
+        }
+        state = Self::inv_add_round_key(state, self.round_keys[0]);
+        trace.record(0, Step::InvAddRoundKey, state);
+
+        trace
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    SubBytes,
+    ShiftRows,
+    MixColumns,
+    AddRoundKey,
+    InvSubBytes,
+    InvShiftRows,
+    InvMixColumns,
+    InvAddRoundKey,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub round: usize,
+    pub step: Step,
+    pub state: Block,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    #[target_feature(enable = "avx2,aes")]
+    unsafe fn record(&mut self, round: usize, step: Step, state: State) {
+        self.entries.push(TraceEntry {
+            round,
+            step,
+            state: AES128::state_to_block(state),
+        });
+    }
+
+    pub fn state(&self, round: usize, step: Step) -> Option<Block> {
+        self.entries
+            .iter()
+            .find(|entry| entry.round == round && entry.step == step)
+            .map(|entry| entry.state)
+    }
+
+    /// One line per step: round, step and the state as a hex string.
+    pub fn to_hex_table(&self) -> String {
+        let mut table = format!("{:<6}{:<16}{}\n", "round", "step", "state");
+        for entry in &self.entries {
+            let state: String = entry.state.iter().map(|b| format!("{b:02x}")).collect();
+            table += &format!(
+                "{:<6}{:<16}{}\n",
+                entry.round,
+                format!("{:?}", entry.step),
+                state
+            );
+        }
+        table
+    }
+
+    /// The state of a single step laid out as the usual 4x4 matrix, with the
+    /// bytes of the block filling it column by column.
+    pub fn to_hex_matrix(state: Block) -> String {
+        (0..4)
+            .map(|row| {
+                (0..4)
+                    .map(|col| format!("{:02x}", state[4 * col + row]))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    + "\n"
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -274,6 +507,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_invert_key_expansion() {
+        unsafe {
+            let key = decode_hex("2b7e151628aed2a6abf7158809cf4f3c");
+            let round_keys = AES128::key_expansion(AES128::block_to_state(key));
+            for (round, round_key) in round_keys.iter().enumerate() {
+                assert_eq!(AES128::invert_key_expansion(*round_key, round), key);
+            }
+        }
+    }
+
     #[test]
     fn test_shift_rows() {
         unsafe {
@@ -408,4 +652,115 @@ mod tests {
             assert_eq!(aes.decrypt(decode_hex(enc_msg)), expected)
         }
     }
+
+    #[test]
+    fn test_encrypt_batch() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 10);
+            let msgs: Vec<Block> = (0..19).map(|i| [i; BLOCK_SIZE]).collect();
+            let expected: Vec<Block> = msgs.iter().map(|&msg| aes.encrypt(msg)).collect();
+            assert_eq!(aes.encrypt_batch(&msgs), expected);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_with_fault() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 10);
+            let msg = decode_hex("000102030405060708090a0b0c0d0e0f");
+            for round in 1..10 {
+                assert_eq!(aes.encrypt_with_fault(msg, round, |_| ()), aes.encrypt(msg));
+            }
+
+            // The fault shows up in the state before MixColumns of its round.
+            let trace = aes.encrypt_traced(msg);
+            let mut faulty_state = trace.state(9, Step::ShiftRows).unwrap();
+            faulty_state[5] ^= 0x80;
+            let expected = AES128::add_round_key(
+                AES128::mix_columns(AES128::block_to_state(faulty_state)),
+                aes.round_keys[9],
+            );
+            let expected =
+                AES128::state_to_block(_mm_aesenclast_si128(expected, aes.round_keys[10]));
+            let faulty = aes.encrypt_with_fault(msg, 9, |block| block[5] ^= 0x80);
+            assert_eq!(faulty, expected);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_traced() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 10);
+            let msg = decode_hex("3243f6a8885a308d313198a2e0370734");
+            let trace = aes.encrypt_traced(msg);
+            assert_eq!(trace.entries.len(), 1 + 9 * 4 + 3);
+            for (round, step, expected) in [
+                (0, Step::AddRoundKey, "193de3bea0f4e22b9ac68d2ae9f84808"),
+                (1, Step::SubBytes, "d42711aee0bf98f1b8b45de51e415230"),
+                (1, Step::ShiftRows, "d4bf5d30e0b452aeb84111f11e2798e5"),
+                (1, Step::MixColumns, "046681e5e0cb199a48f8d37a2806264c"),
+                (1, Step::AddRoundKey, "a49c7ff2689f352b6b5bea43026a5049"),
+                (10, Step::AddRoundKey, "3925841d02dc09fbdc118597196a0b32"),
+            ] {
+                assert_eq!(trace.state(round, step), Some(decode_hex(expected)));
+            }
+            assert_eq!(trace.entries.last().unwrap().state, aes.encrypt(msg));
+        }
+    }
+
+    #[test]
+    fn test_decrypt_traced() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 10);
+            let msg = decode_hex("3243f6a8885a308d313198a2e0370734");
+            let trace = aes.decrypt_traced(aes.encrypt(msg));
+            let enc_trace = aes.encrypt_traced(msg);
+            assert_eq!(trace.entries.last().unwrap().state, msg);
+            // Undoing the first round's key addition lands on the state after
+            // its MixColumns.
+            assert_eq!(
+                trace.state(1, Step::InvAddRoundKey),
+                enc_trace.state(1, Step::MixColumns)
+            );
+            assert_eq!(
+                trace.state(1, Step::InvSubBytes),
+                enc_trace.state(0, Step::AddRoundKey)
+            );
+        }
+    }
+
+    #[test]
+    fn test_balanced_after_three_rounds() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 4);
+            let mut msg = decode_hex("3243f6a8885a308d313198a2e0370734");
+            let mut sum = [0; BLOCK_SIZE];
+            for i in 0..=255 {
+                msg[0] = i;
+                let state = aes.encrypt_traced(msg).state(3, Step::AddRoundKey).unwrap();
+                for (acc, b) in sum.iter_mut().zip(state) {
+                    *acc ^= b;
+                }
+            }
+            assert_eq!(sum, [0; BLOCK_SIZE]);
+        }
+    }
+
+    #[test]
+    fn test_to_hex_table() {
+        unsafe {
+            let aes = AES128::new(decode_hex("2b7e151628aed2a6abf7158809cf4f3c"), 1);
+            let trace = aes.encrypt_traced(decode_hex("3243f6a8885a308d313198a2e0370734"));
+            let table = trace.to_hex_table();
+            assert_eq!(table.lines().count(), 1 + trace.entries.len());
+            assert_eq!(
+                table.lines().nth(1),
+                Some("0     AddRoundKey     193de3bea0f4e22b9ac68d2ae9f84808")
+            );
+            assert_eq!(
+                Trace::to_hex_matrix(trace.entries[0].state),
+                "19 a0 9a e9\n3d f4 c6 f8\ne3 e2 8d 48\nbe 2b 2a 08\n"
+            );
+        }
+    }
 }
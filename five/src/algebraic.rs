@@ -0,0 +1,553 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::aes::{AES128, BLOCK_SIZE};
+use crate::sat::{self, Cnf};
+use crate::sbox_analysis::{aes_sbox, coordinate_anfs};
+use crate::small_aes::SmallAES;
+
+/// XORs of more variables are cut into pieces of this many with auxiliary
+/// variables in the CNF, as an XOR of `n` variables takes `2^(n - 1)`
+/// clauses.
+const MAX_XOR_LEN: usize = 4;
+
+/// A variable of an equation system, counted from 0.
+pub type Var = usize;
+
+/// An AES-like cipher as the equations see it: the S-box as a table, the
+/// linear layers as the input bits of every output bit and the round
+/// constants of the key schedule, all derived from the implementation in
+/// `aes.rs` or `small_aes.rs`. Blocks and keys are bit vectors packed into a
+/// `u128`, bit `j` of cell `i` at `cell_bits * i + j`.
+pub struct Description {
+    rows: usize,
+    cols: usize,
+    cell_bits: usize,
+    num_rounds: usize,
+    sbox: Vec<u8>,
+    shift_rows: Vec<u128>,
+    /// ShiftRows followed by MixColumns, the linear layer of the inner
+    /// rounds.
+    mix_columns: Vec<u128>,
+    rcons: Vec<u8>,
+}
+
+/// The input bits of every output bit of the linear map `f` on `num_bits`
+/// bits.
+fn linear_layer<F: Fn(u128) -> u128>(num_bits: usize, f: F) -> Vec<u128> {
+    let mut inputs = vec![0; num_bits];
+    for bit in 0..num_bits {
+        let image = f(1 << bit);
+        for (out, mask) in inputs.iter_mut().enumerate() {
+            *mask |= (image >> out & 1) << bit;
+        }
+    }
+    inputs
+}
+
+impl Description {
+    /// AES-128 reduced to `num_rounds`, at most 10.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn aes(num_rounds: usize) -> Self {
+        assert!(num_rounds <= 10, "AES-128 has 10 rounds");
+        let apply = |f: unsafe fn(_) -> _, bits: u128| {
+            let state = AES128::block_to_state(bits.to_le_bytes());
+            u128::from_le_bytes(AES128::state_to_block(f(state)))
+        };
+        let round_keys: Vec<u128> = AES128::key_expansion(AES128::block_to_state([0; BLOCK_SIZE]))
+            .into_iter()
+            .map(|round_key| u128::from_le_bytes(AES128::state_to_block(round_key)))
+            .collect();
+        Self::new(
+            4,
+            4,
+            8,
+            num_rounds,
+            aes_sbox().to_vec(),
+            linear_layer(128, |bits| apply(AES128::shift_rows, bits)),
+            linear_layer(128, |bits| {
+                apply(AES128::mix_columns, apply(AES128::shift_rows, bits))
+            }),
+            &round_keys,
+        )
+    }
+
+    /// The small-scale variant with `rows` x `cols` cells of 4 bits reduced
+    /// to `num_rounds`.
+    pub fn small_aes(rows: usize, cols: usize, num_rounds: usize) -> Self {
+        let num_cells = rows * cols;
+        let cipher = SmallAES::new(&vec![0; num_cells], rows, cols, num_rounds);
+        let cells = |bits: u128| SmallAES::unpack(bits, num_cells);
+        let bits = |cells: Vec<u8>| SmallAES::pack(&cells);
+        let sbox = cipher.sub_bytes(&(0..16).collect::<Vec<u8>>());
+        let round_keys: Vec<u128> = cipher
+            .key_expansion(&vec![0; num_cells])
+            .into_iter()
+            .map(bits)
+            .collect();
+        Self::new(
+            rows,
+            cols,
+            4,
+            num_rounds,
+            sbox,
+            linear_layer(4 * num_cells, |x| bits(cipher.shift_rows(&cells(x)))),
+            linear_layer(4 * num_cells, |x| {
+                bits(cipher.mix_columns(&cipher.shift_rows(&cells(x))))
+            }),
+            &round_keys,
+        )
+    }
+
+    /// Recovers the round constants from the round keys of some key: the
+    /// first cell of a round key is that of the previous one plus the S-box
+    /// of a cell of its last column plus the constant.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rows: usize,
+        cols: usize,
+        cell_bits: usize,
+        num_rounds: usize,
+        sbox: Vec<u8>,
+        shift_rows: Vec<u128>,
+        mix_columns: Vec<u128>,
+        round_keys: &[u128],
+    ) -> Self {
+        let cell = |bits: u128, i: usize| (bits >> (cell_bits * i)) as u8 & (sbox.len() - 1) as u8;
+        let rcons = round_keys
+            .windows(2)
+            .take(num_rounds)
+            .map(|pair| {
+                let rotated = cell(pair[0], rows * (cols - 1) + 1 % rows);
+                cell(pair[1], 0) ^ cell(pair[0], 0) ^ sbox[rotated as usize]
+            })
+            .collect();
+        Self {
+            rows,
+            cols,
+            cell_bits,
+            num_rounds,
+            sbox,
+            shift_rows,
+            mix_columns,
+            rcons,
+        }
+    }
+
+    pub fn num_bits(&self) -> usize {
+        self.rows * self.cols * self.cell_bits
+    }
+}
+
+/// The variables summing to `constant` over GF(2).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Xor {
+    pub vars: Vec<Var>,
+    pub constant: bool,
+}
+
+/// `outputs` is the S-box of `inputs`, bit `j` of a cell being variable `j`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SBoxEquation {
+    pub inputs: Vec<Var>,
+    pub outputs: Vec<Var>,
+}
+
+/// A polynomial over GF(2) as a sum of monomials, a monomial being a product
+/// of variables and the empty one the constant 1.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Polynomial {
+    pub monomials: Vec<Vec<Var>>,
+}
+
+impl Polynomial {
+    pub fn evaluate(&self, assignment: &[bool]) -> bool {
+        self.monomials
+            .iter()
+            .filter(|monomial| monomial.iter().all(|&v| assignment[v]))
+            .count()
+            % 2
+            == 1
+    }
+}
+
+/// Variable `v` is written `x<v>`, e.g. `x0*x3 + x1 + 1`, the syntax of
+/// SageMath and PolyBoRi.
+impl fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.monomials.is_empty() {
+            return write!(f, "0");
+        }
+        let terms: Vec<String> = self
+            .monomials
+            .iter()
+            .map(|monomial| {
+                if monomial.is_empty() {
+                    "1".to_string()
+                } else {
+                    let vars: Vec<String> = monomial.iter().map(|v| format!("x{v}")).collect();
+                    vars.join("*")
+                }
+            })
+            .collect();
+        write!(f, "{}", terms.join(" + "))
+    }
+}
+
+/// The equations of the encryptions of known plaintexts to known
+/// ciphertexts under one key: every bit of the key, of the round keys and of
+/// the states before and after each S-box of every encryption is a variable,
+/// the S-boxes are `SBoxEquation`s and everything else is linear.
+pub struct System {
+    pub num_vars: usize,
+    /// The variables of the key bits, in the order of the bits of the key.
+    pub key_vars: Vec<Var>,
+    pub xors: Vec<Xor>,
+    pub sboxes: Vec<SBoxEquation>,
+    sbox: Vec<u8>,
+}
+
+impl System {
+    /// The equations of the encryption of every plaintext of `pairs` to its
+    /// ciphertext.
+    pub fn new(description: &Description, pairs: &[(u128, u128)]) -> Self {
+        let Description {
+            rows,
+            cols,
+            cell_bits,
+            num_rounds,
+            ..
+        } = *description;
+        let num_bits = description.num_bits();
+        let mut system = Self {
+            num_vars: 0,
+            key_vars: vec![],
+            xors: vec![],
+            sboxes: vec![],
+            sbox: description.sbox.clone(),
+        };
+
+        system.key_vars = system.fresh_vars(num_bits);
+        let mut round_keys = vec![system.key_vars.clone()];
+        for round in 1..=num_rounds {
+            let prev = round_keys[round - 1].clone();
+            let last_col = rows * (cols - 1);
+            let sub_word: Vec<Vec<Var>> = (0..rows)
+                .map(|row| {
+                    let cell = last_col + (row + 1) % rows;
+                    system.sub_cell(&prev[cell_bits * cell..cell_bits * (cell + 1)])
+                })
+                .collect();
+            let next = system.fresh_vars(num_bits);
+            for bit in 0..num_bits {
+                let (cell, j) = (bit / cell_bits, bit % cell_bits);
+                let chained = if cell < rows {
+                    sub_word[cell][j]
+                } else {
+                    next[bit - cell_bits * rows]
+                };
+                let rcon = cell == 0 && description.rcons[round - 1] >> j & 1 == 1;
+                system.add_xor(vec![next[bit], prev[bit], chained], rcon);
+            }
+            round_keys.push(next);
+        }
+
+        for &(msg, enc) in pairs {
+            let mut state = system.fresh_vars(num_bits);
+            for bit in 0..num_bits {
+                system.add_xor(vec![state[bit], round_keys[0][bit]], msg >> bit & 1 == 1);
+            }
+            for (round, round_key) in round_keys.iter().enumerate().skip(1) {
+                let substituted: Vec<Var> = state
+                    .chunks(cell_bits)
+                    .flat_map(|cell| system.sub_cell(cell))
+                    .collect();
+                let layer = if round == num_rounds {
+                    &description.shift_rows
+                } else {
+                    &description.mix_columns
+                };
+                let next = system.fresh_vars(if round == num_rounds { 0 } else { num_bits });
+                for bit in 0..num_bits {
+                    let mut vars: Vec<Var> = (0..num_bits)
+                        .filter(|&input| layer[bit] >> input & 1 == 1)
+                        .map(|input| substituted[input])
+                        .collect();
+                    vars.push(round_key[bit]);
+                    if round == num_rounds {
+                        system.add_xor(vars, enc >> bit & 1 == 1);
+                    } else {
+                        vars.push(next[bit]);
+                        system.add_xor(vars, false);
+                    }
+                }
+                state = next;
+            }
+        }
+        system
+    }
+
+    fn fresh_vars(&mut self, count: usize) -> Vec<Var> {
+        self.num_vars += count;
+        (self.num_vars - count..self.num_vars).collect()
+    }
+
+    fn add_xor(&mut self, vars: Vec<Var>, constant: bool) {
+        self.xors.push(Xor { vars, constant });
+    }
+
+    /// New variables for the S-box of the cell `inputs`.
+    fn sub_cell(&mut self, inputs: &[Var]) -> Vec<Var> {
+        let outputs = self.fresh_vars(inputs.len());
+        self.sboxes.push(SBoxEquation {
+            inputs: inputs.to_vec(),
+            outputs: outputs.clone(),
+        });
+        outputs
+    }
+
+    /// The key in a solution of the system.
+    pub fn key(&self, assignment: &[bool]) -> u128 {
+        self.key_vars
+            .iter()
+            .enumerate()
+            .fold(0, |key, (bit, &v)| key | (assignment[v] as u128) << bit)
+    }
+
+    /// The system as a CNF over the same variables, shifted by one as DIMACS
+    /// wants, plus auxiliary ones after them. Every S-box output bit gets a
+    /// clause per S-box input saying which value it takes.
+    pub fn to_cnf(&self) -> Cnf {
+        let mut cnf = Cnf {
+            num_vars: self.num_vars,
+            clauses: vec![],
+        };
+        let lit = |v: Var, value: bool| if value { v as i32 + 1 } else { -(v as i32 + 1) };
+
+        for xor in &self.xors {
+            let mut vars = xor.vars.clone();
+            while vars.len() > MAX_XOR_LEN {
+                let aux = cnf.num_vars;
+                cnf.num_vars += 1;
+                let mut piece: Vec<Var> = vars.drain(..MAX_XOR_LEN - 1).collect();
+                piece.push(aux);
+                xor_clauses(&mut cnf, &piece, false);
+                vars.push(aux);
+            }
+            xor_clauses(&mut cnf, &vars, xor.constant);
+        }
+
+        for equation in &self.sboxes {
+            for (x, &y) in self.sbox.iter().enumerate() {
+                let differs: Vec<i32> = equation
+                    .inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &v)| lit(v, x >> j & 1 == 0))
+                    .collect();
+                for (j, &v) in equation.outputs.iter().enumerate() {
+                    let mut clause = differs.clone();
+                    clause.push(lit(v, y >> j & 1 == 1));
+                    cnf.clauses.push(clause);
+                }
+            }
+        }
+        cnf
+    }
+
+    /// The system as polynomials over GF(2) which vanish on its solutions:
+    /// the XORs as they are and every S-box output bit plus its algebraic
+    /// normal form in the input bits.
+    pub fn to_anf(&self) -> Vec<Polynomial> {
+        let mut polynomials: Vec<Polynomial> = self
+            .xors
+            .iter()
+            .map(|xor| {
+                let mut monomials: Vec<Vec<Var>> = xor.vars.iter().map(|&v| vec![v]).collect();
+                if xor.constant {
+                    monomials.push(vec![]);
+                }
+                Polynomial { monomials }
+            })
+            .collect();
+
+        let coefficients = coordinate_anfs(&self.sbox);
+        for equation in &self.sboxes {
+            for (&output, coefficients) in equation.outputs.iter().zip(&coefficients) {
+                let mut monomials = vec![vec![output]];
+                for (mask, _) in coefficients.iter().enumerate().skip(1).filter(|(_, &c)| c) {
+                    monomials.push(
+                        (0..equation.inputs.len())
+                            .filter(|&j| mask >> j & 1 == 1)
+                            .map(|j| equation.inputs[j])
+                            .collect(),
+                    );
+                }
+                if coefficients[0] {
+                    monomials.push(vec![]);
+                }
+                polynomials.push(Polynomial { monomials });
+            }
+        }
+        polynomials
+    }
+
+    /// Writes the polynomials of `to_anf`, one per line.
+    pub fn write_anf_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for polynomial in self.to_anf() {
+            writeln!(writer, "{polynomial}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Clauses ruling out every assignment of `vars` of the wrong parity.
+fn xor_clauses(cnf: &mut Cnf, vars: &[Var], constant: bool) {
+    for assignment in 0..1usize << vars.len() {
+        if (assignment.count_ones() % 2 == 1) != constant {
+            cnf.clauses.push(
+                vars.iter()
+                    .enumerate()
+                    .map(|(j, &v)| {
+                        let v = v as i32 + 1;
+                        if assignment >> j & 1 == 1 {
+                            -v
+                        } else {
+                            v
+                        }
+                    })
+                    .collect(),
+            );
+        }
+    }
+}
+
+/// Algebraic key recovery: solves the equations of `pairs` with the CDCL
+/// solver of `sat`. Returns a key encrypting every plaintext to its
+/// ciphertext, the key if there are enough pairs.
+pub fn crack_key_sat(description: &Description, pairs: &[(u128, u128)]) -> Option<u128> {
+    let system = System::new(description, pairs);
+    sat::solve(&system.to_cnf()).map(|model| system.key(&model))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attack::gen_random_block;
+    use rand::{thread_rng, Rng};
+
+    fn encrypt_small_aes(
+        key: u128,
+        rows: usize,
+        cols: usize,
+        num_rounds: usize,
+        msg: u128,
+    ) -> u128 {
+        let cells = |bits: u128| SmallAES::unpack(bits, rows * cols);
+        SmallAES::pack(&SmallAES::new(&cells(key), rows, cols, num_rounds).encrypt(&cells(msg)))
+    }
+
+    /// Unit clauses fixing the key variables to `key`.
+    fn fix_key(system: &System, cnf: &mut Cnf, key: u128) {
+        for (bit, &v) in system.key_vars.iter().enumerate() {
+            let v = v as i32 + 1;
+            cnf.clauses
+                .push(vec![if key >> bit & 1 == 1 { v } else { -v }]);
+        }
+    }
+
+    #[test]
+    fn test_polynomial() {
+        let polynomial = Polynomial {
+            monomials: vec![vec![0, 3], vec![1], vec![]],
+        };
+        assert_eq!(polynomial.to_string(), "x0*x3 + x1 + 1");
+        assert!(polynomial.evaluate(&[true, true, false, true]));
+    }
+
+    #[test]
+    fn test_description_matches_small_aes() {
+        let description = Description::small_aes(4, 4, 10);
+        assert_eq!(description.num_bits(), 64);
+        // 1, 2, 4, 8, then reduced by x^4 + x + 1.
+        assert_eq!(description.rcons, [1, 2, 4, 8, 3, 6, 0xc, 0xb, 5, 0xa]);
+    }
+
+    #[test]
+    fn test_description_matches_aes() {
+        let description = unsafe { Description::aes(10) };
+        assert_eq!(
+            description.rcons,
+            [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36]
+        );
+        assert_eq!(description.sbox[0], 0x63);
+        // Byte 5 moves to byte 1.
+        assert_eq!(description.shift_rows[8], 1 << 40);
+    }
+
+    #[test]
+    fn test_crack_key_small_aes() {
+        let mut rng = thread_rng();
+        for (rows, cols, num_rounds) in [(2, 2, 2), (4, 4, 1)] {
+            let description = Description::small_aes(rows, cols, num_rounds);
+            let mask = (1 << description.num_bits()) - 1;
+            let key = rng.gen::<u128>() & mask;
+            let pairs: Vec<(u128, u128)> = (0..2)
+                .map(|_| {
+                    let msg = rng.gen::<u128>() & mask;
+                    (msg, encrypt_small_aes(key, rows, cols, num_rounds, msg))
+                })
+                .collect();
+            // Two pairs may leave more than one key.
+            let recovered = crack_key_sat(&description, &pairs).unwrap();
+            for (msg, enc) in pairs {
+                assert_eq!(
+                    encrypt_small_aes(recovered, rows, cols, num_rounds, msg),
+                    enc
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_aes_equations() {
+        // With the key fixed, unit propagation alone solves the system, and a
+        // wrong ciphertext makes it unsatisfiable.
+        let description = unsafe { Description::aes(2) };
+        let key = gen_random_block();
+        let msg = gen_random_block();
+        let enc = unsafe { AES128::new(key, 2).encrypt(msg) };
+        for wrong_bit in [None, Some(77)] {
+            let enc = u128::from_le_bytes(enc) ^ wrong_bit.map_or(0, |bit| 1 << bit);
+            let system = System::new(&description, &[(u128::from_le_bytes(msg), enc)]);
+            let mut cnf = system.to_cnf();
+            fix_key(&system, &mut cnf, u128::from_le_bytes(key));
+            let mut solver = sat::Solver::new(&cnf);
+            let model = solver.solve();
+            assert_eq!(model.is_some(), wrong_bit.is_none());
+            assert_eq!(solver.num_conflicts(), wrong_bit.is_some() as usize);
+            if let Some(model) = model {
+                assert_eq!(system.key(&model), u128::from_le_bytes(key));
+                assert!(system
+                    .to_anf()
+                    .iter()
+                    .all(|polynomial| !polynomial.evaluate(&model)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_aes_equations() {
+        let description = Description::small_aes(4, 4, 2);
+        let key = thread_rng().gen::<u64>() as u128;
+        let msg = thread_rng().gen::<u64>() as u128;
+        let system = System::new(&description, &[(msg, encrypt_small_aes(key, 4, 4, 2, msg))]);
+        let mut cnf = system.to_cnf();
+        fix_key(&system, &mut cnf, key);
+        let model = sat::solve(&cnf).unwrap();
+        assert!(system
+            .to_anf()
+            .iter()
+            .all(|polynomial| !polynomial.evaluate(&model)));
+    }
+}
@@ -21,29 +21,77 @@ impl SIMDBytes256 {
 
 impl Index<usize> for SIMDBytes256 {
     type Output = u8;
-    fn index<'a>(&'a self, i: usize) -> &'a u8 {
+    fn index(&self, i: usize) -> &u8 {
         unsafe { &self.bytes[i] }
     }
 }
 
 impl IndexMut<usize> for SIMDBytes256 {
-    fn index_mut<'a>(&'a mut self, i: usize) -> &'a mut u8 {
+    fn index_mut(&mut self, i: usize) -> &mut u8 {
         unsafe { &mut self.bytes[i] }
     }
 }
 
+/// Anything that encrypts chosen blocks under a fixed secret key, e.g. a bare
+/// `AES128` or a mode of operation wrapped around one.
+pub trait EncryptionService {
+    unsafe fn encrypt(&self, msg: Block) -> Block;
+
+    /// Encrypts many blocks at once, for services that are faster that way.
+    unsafe fn encrypt_batch(&self, msgs: &[Block]) -> Vec<Block> {
+        msgs.iter().map(|&msg| self.encrypt(msg)).collect()
+    }
+}
+
+impl EncryptionService for AES128 {
+    #[target_feature(enable = "avx2,aes")]
+    unsafe fn encrypt(&self, msg: Block) -> Block {
+        AES128::encrypt(self, msg)
+    }
+
+    #[target_feature(enable = "avx2,aes")]
+    unsafe fn encrypt_batch(&self, msgs: &[Block]) -> Vec<Block> {
+        AES128::encrypt_batch(self, msgs)
+    }
+}
+
+impl<F: Fn(Block) -> Block> EncryptionService for F {
+    unsafe fn encrypt(&self, msg: Block) -> Block {
+        self(msg)
+    }
+}
+
+/// The counterpart of `EncryptionService` for attacks which also get to
+/// decrypt chosen ciphertexts.
+pub trait DecryptionService {
+    unsafe fn decrypt(&self, enc_msg: Block) -> Block;
+}
+
+impl DecryptionService for AES128 {
+    #[target_feature(enable = "avx2,aes")]
+    unsafe fn decrypt(&self, enc_msg: Block) -> Block {
+        AES128::decrypt(self, enc_msg)
+    }
+}
+
+impl<F: Fn(Block) -> Block> DecryptionService for F {
+    unsafe fn decrypt(&self, enc_msg: Block) -> Block {
+        self(enc_msg)
+    }
+}
+
 #[target_feature(enable = "avx2,aes")]
-pub unsafe fn crack_key(encryption_service: &AES128) -> [u8; BLOCK_SIZE] {
+pub unsafe fn crack_key<E: EncryptionService>(encryption_service: &E) -> [u8; BLOCK_SIZE] {
     let mut recovered_key = [0; BLOCK_SIZE];
 
-    for pos in 0..BLOCK_SIZE {
+    for (pos, recovered_byte) in recovered_key.iter_mut().enumerate() {
         let mut candidates = (0..1u64 << 40)
             .map(|mask| {
                 let guess = mask as u8;
 
                 let mut guessed_round_key = [0; BLOCK_SIZE];
                 let col = (pos & 3) << 2;
-                guessed_round_key[col + 0] = (mask >> 8) as u8;
+                guessed_round_key[col] = (mask >> 8) as u8;
                 guessed_round_key[col + 1] = (mask >> 16) as u8;
                 guessed_round_key[col + 2] = (mask >> 24) as u8;
                 guessed_round_key[col + 3] = (mask >> 32) as u8;
@@ -78,30 +126,203 @@ pub unsafe fn crack_key(encryption_service: &AES128) -> [u8; BLOCK_SIZE] {
             .unwrap()
             .0;
 
-        recovered_key[pos] = correct_byte;
+        *recovered_byte = correct_byte;
     }
 
     recovered_key
 }
 
-fn gen_random_block() -> Block {
+/// Recovers the key of 4-round AES. Every byte of the last round key is
+/// guessed separately against a delta set, after which the key schedule is
+/// inverted.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_key_four_rounds<E: EncryptionService>(
+    encryption_service: &E,
+) -> [u8; BLOCK_SIZE] {
+    let mut last_round_key = [0; BLOCK_SIZE];
+
+    for (pos, recovered_byte) in last_round_key.iter_mut().enumerate() {
+        let mut candidates: Vec<u8> = (0..=255).collect();
+        while candidates.len() > 1 {
+            let enc_delta_set = setup(encryption_service);
+            candidates
+                .retain(|&guess| is_valid_guess(reverse_last_round(guess, pos, enc_delta_set)));
+        }
+        *recovered_byte = candidates[0];
+    }
+
+    AES128::invert_key_expansion(AES128::block_to_state(last_round_key), 4)
+}
+
+/// Delta sets `crack_secret_sbox_four_rounds` encrypts before giving up. The
+/// equations on the inverse S-box alone take at least 247.
+const MAX_DELTA_SETS: usize = 1024;
+
+/// What a Square attack on 4-round AES with a secret S-box `S` can learn.
+/// Sums of zero are invariant under affine maps, so the inverse S-box is only
+/// determined up to one, and the last round key only relative to its first
+/// byte.
+pub struct SecretSboxRecovery {
+    /// `k ^ k[0]` for the last round key `k`.
+    pub key_differences: Block,
+    /// `x -> A(S^-1(x ^ k[0])) ^ b` for some unknown invertible linear map
+    /// `A` and constant `b`.
+    pub inv_sbox: [u8; 256],
+}
+
+type BitVector = [u64; 4];
+
+fn bit(vector: &BitVector, i: usize) -> bool {
+    (vector[i / 64] >> (i % 64)) & 1 == 1
+}
+
+fn xor_into(acc: &mut BitVector, other: &BitVector) {
+    for (x, y) in acc.iter_mut().zip(other) {
+        *x ^= y;
+    }
+}
+
+/// Which byte values occur an odd number of times in `bytes`.
+fn parity_vector(bytes: impl Iterator<Item = u8>) -> BitVector {
+    let mut vector = [0; 4];
+    for x in bytes {
+        vector[x as usize / 64] ^= 1 << (x % 64);
+    }
+    vector
+}
+
+/// Kernel of the GF(2) system whose rows are `equations`, in reduced row
+/// echelon form with pivots given by `pivots`.
+fn kernel(equations: &[BitVector], pivots: &[usize]) -> Vec<BitVector> {
+    (0..256)
+        .filter(|i| !pivots.contains(i))
+        .map(|free| {
+            let mut vector = [0; 4];
+            vector[free / 64] |= 1 << (free % 64);
+            for (row, &pivot) in equations.iter().zip(pivots) {
+                if bit(row, free) {
+                    vector[pivot / 64] |= 1 << (pivot % 64);
+                }
+            }
+            vector
+        })
+        .collect()
+}
+
+/// Recovers the inverse S-box up to an affine map and the differences of the
+/// last round key of 4-round AES with a secret S-box, in the spirit of Tiessen
+/// et al. Every delta set gives one linear equation on the 256 entries of the
+/// inverse S-box: the entries at ciphertext bytes occurring an odd number of
+/// times sum to zero. The 8 coordinate functions of the inverse S-box and the
+/// constant function span the kernel of these equations once enough delta
+/// sets are collected, and a table built from any basis of it sums to zero
+/// exactly where the inverse S-box does.
+///
+/// Returns `None` if `MAX_DELTA_SETS` delta sets do not determine them, as
+/// when the encryption service is not such a cipher.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_secret_sbox_four_rounds<E: EncryptionService>(
+    encryption_service: &E,
+) -> Option<SecretSboxRecovery> {
+    const KERNEL_DIM: usize = 9;
+
+    let mut num_delta_sets = 0;
+    let mut next_delta_set = || {
+        num_delta_sets += 1;
+        (num_delta_sets <= MAX_DELTA_SETS).then(|| setup(encryption_service))
+    };
+    let mut enc_delta_sets = vec![];
+    let mut equations: Vec<BitVector> = vec![];
+    let mut pivots = vec![];
+    while equations.len() < 256 - KERNEL_DIM {
+        let enc_delta_set = next_delta_set()?;
+        let mut equation = parity_vector(enc_delta_set.iter().map(|enc| enc[0]));
+        enc_delta_sets.push(enc_delta_set);
+        for (row, &pivot) in equations.iter().zip(&pivots) {
+            if bit(&equation, pivot) {
+                xor_into(&mut equation, row);
+            }
+        }
+        let Some(pivot) = (0..256).find(|&i| bit(&equation, i)) else {
+            continue;
+        };
+        for row in equations.iter_mut() {
+            if bit(row, pivot) {
+                xor_into(row, &equation);
+            }
+        }
+        equations.push(equation);
+        pivots.push(pivot);
+    }
+
+    // Fix b by making every coordinate vanish at 0, which removes the
+    // constant function from the kernel.
+    let ones = [u64::MAX; 4];
+    let mut coordinates: Vec<BitVector> = vec![];
+    for mut vector in kernel(&equations, &pivots) {
+        if bit(&vector, 0) {
+            xor_into(&mut vector, &ones);
+        }
+        for other in &coordinates {
+            let lead = (0..256).find(|&i| bit(other, i)).unwrap();
+            if bit(&vector, lead) {
+                xor_into(&mut vector, other);
+            }
+        }
+        if vector != [0; 4] {
+            coordinates.push(vector);
+        }
+    }
+    let inv_sbox: [u8; 256] = std::array::from_fn(|x| {
+        coordinates
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, vector)| acc | ((bit(vector, x) as u8) << i))
+    });
+
+    let mut key_differences = [0; BLOCK_SIZE];
+    for (pos, recovered_byte) in key_differences.iter_mut().enumerate().skip(1) {
+        let is_balanced = |enc_delta_set: &[Block; 256], guess: u8| {
+            enc_delta_set
+                .iter()
+                .fold(0, |acc, enc| acc ^ inv_sbox[(enc[pos] ^ guess) as usize])
+                == 0
+        };
+        let mut candidates: Vec<u8> = (0..=255).collect();
+        for enc_delta_set in &enc_delta_sets {
+            candidates.retain(|&guess| is_balanced(enc_delta_set, guess));
+        }
+        while candidates.len() > 1 {
+            let enc_delta_set = next_delta_set()?;
+            candidates.retain(|&guess| is_balanced(&enc_delta_set, guess));
+        }
+        *recovered_byte = *candidates.first()?;
+    }
+
+    Some(SecretSboxRecovery {
+        key_differences,
+        inv_sbox,
+    })
+}
+
+pub(crate) fn gen_random_block() -> Block {
     let mut block = [0; BLOCK_SIZE];
     thread_rng().fill(&mut block);
     block
 }
 
 #[target_feature(enable = "avx2,aes")]
-unsafe fn setup(encryption_service: &AES128) -> [Block; 256] {
+pub(crate) unsafe fn setup<E: EncryptionService>(encryption_service: &E) -> [Block; 256] {
     let mut delta_set = [gen_random_block(); 256];
-    for i in 0..256 {
-        delta_set[i][0] = i as u8;
+    for (i, block) in delta_set.iter_mut().enumerate() {
+        block[0] = i as u8;
     }
     delta_set.map(|block| encryption_service.encrypt(block))
 }
 
 #[target_feature(enable = "avx2,aes")]
-unsafe fn crack_given_candidates(
-    encryption_service: &AES128,
+unsafe fn crack_given_candidates<E: EncryptionService>(
+    encryption_service: &E,
     pos: usize,
     candidates: Vec<(u8, RoundKey)>,
 ) -> Option<(u8, RoundKey)> {
@@ -149,6 +370,21 @@ unsafe fn reverse_state(
     reversed_bytes
 }
 
+#[target_feature(enable = "avx2,aes")]
+unsafe fn reverse_last_round(guess: u8, pos: usize, enc_delta_set: [Block; 256]) -> SIMDBytes256 {
+    let mut reversed_bytes = SIMDBytes256::new();
+    let mut guessed_key = [0; BLOCK_SIZE];
+    guessed_key[pos] = guess;
+    let guessed_key = AES128::block_to_state(guessed_key);
+    for (i, enc) in enc_delta_set.iter().enumerate() {
+        let mut state = AES128::block_to_state(*enc);
+        state = AES128::inv_add_round_key(state, guessed_key);
+        state = AES128::inv_sub_bytes(state);
+        reversed_bytes[i] = AES128::state_to_block(state)[pos];
+    }
+    reversed_bytes
+}
+
 #[inline]
 unsafe fn m128i_to_u8x16(vector: __m128i) -> [u8; 16] {
     [
@@ -185,9 +421,16 @@ unsafe fn is_valid_guess(recovered_bytes: SIMDBytes256) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use crate::aes::AES128;
+    use crate::aes::{Block, Step, AES128};
+
+    use crate::aes_like::{AesLike, AesLikeParams};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
 
-    use super::{is_valid_guess, reverse_state, setup, SIMDBytes256};
+    use super::{
+        crack_key_four_rounds, crack_secret_sbox_four_rounds, is_valid_guess, reverse_last_round,
+        reverse_state, setup, SIMDBytes256,
+    };
 
     #[test]
     fn test_is_valid_guess() {
@@ -224,4 +467,106 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_reverse_last_round() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let aes = AES128::new(key, 4);
+            let enc_delta_set = setup(&aes);
+            let round_keys = AES128::key_expansion(AES128::block_to_state(key));
+            for pos in 0..16 {
+                let key_guess = AES128::state_to_block(round_keys[4])[pos];
+                assert!(is_valid_guess(reverse_last_round(
+                    key_guess,
+                    pos,
+                    enc_delta_set
+                )))
+            }
+        }
+    }
+
+    #[test]
+    fn test_crack_key_four_rounds() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let aes = AES128::new(key, 4);
+            assert_eq!(crack_key_four_rounds(&aes), key);
+        }
+    }
+
+    #[test]
+    fn test_crack_secret_sbox_four_rounds() {
+        unsafe {
+            let key = *b"sixteen byte key";
+            let params = AesLikeParams::with_random_sbox(&mut ChaCha20Rng::seed_from_u64(7));
+            let sbox = params.sbox;
+            let cipher = AesLike::new(key, 4, params);
+            let recovery =
+                crack_secret_sbox_four_rounds(&|msg: Block| cipher.encrypt(msg)).unwrap();
+
+            let last_round_key = cipher.key_expansion(key)[4];
+            assert_eq!(
+                recovery.key_differences,
+                last_round_key.map(|x| x ^ last_round_key[0])
+            );
+            // The recovered table composed with S is affine.
+            let f = |u: usize| recovery.inv_sbox[(sbox[u] ^ last_round_key[0]) as usize];
+            for u in 0..256 {
+                for v in 0..256 {
+                    assert_eq!(f(u) ^ f(v), f(u ^ v) ^ f(0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_crack_secret_sbox_gives_up() {
+        unsafe {
+            // Every delta set of the identity gives the same equation.
+            assert!(crack_secret_sbox_four_rounds(&|msg: Block| msg).is_none());
+        }
+    }
+
+    #[test]
+    fn test_reverse_state_against_trace() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let num_rounds = 5;
+            let aes = AES128::new(key, num_rounds);
+            let round_keys = AES128::key_expansion(AES128::block_to_state(key));
+            let traces: Vec<_> = (0..=255u8).map(|i| aes.encrypt_traced([i; 16])).collect();
+            let enc_delta_set: [Block; 256] = traces
+                .iter()
+                .map(|trace| trace.entries.last().unwrap().state)
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            for pos in 0..16 {
+                let key_guess =
+                    AES128::state_to_block(AES128::inv_mix_columns(round_keys[num_rounds - 1]))
+                        [pos];
+                let reversed = reverse_state(key_guess, pos, round_keys[num_rounds], enc_delta_set);
+                // Peeling off the last two rounds lands just before the
+                // SubBytes of round 4, but with ShiftRows still applied.
+                for (i, trace) in traces.iter().enumerate() {
+                    let shifted = trace.state(num_rounds - 1, Step::ShiftRows).unwrap();
+                    let expected = AES128::inv_sub_bytes(AES128::block_to_state(shifted));
+                    assert_eq!(reversed[i], AES128::state_to_block(expected)[pos]);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,388 @@
+use crate::aes::{Block, BLOCK_SIZE, RCON};
+use crate::attack::EncryptionService;
+
+/// A block as eight slices: bit `i` of slice `b` is bit `b` of byte `i`.
+/// Every step of the cipher is then a fixed sequence of logic operations on
+/// the slices, with no lookup or branch depending on the data.
+type Slices = [u16; 8];
+
+fn bitslice(block: &Block) -> Slices {
+    std::array::from_fn(|b| {
+        (0..BLOCK_SIZE).fold(0, |slice, i| slice | (((block[i] >> b) & 1) as u16) << i)
+    })
+}
+
+fn unbitslice(slices: &Slices) -> Block {
+    std::array::from_fn(|i| (0..8).fold(0, |byte, b| byte | (((slices[b] >> i) & 1) as u8) << b))
+}
+
+/// The S-box circuit of Boyar and Peralta: 113 gates, 32 of them AND, with a
+/// depth of 16. The top linear layer maps the input to 22 signals, the
+/// middle layer inverts in GF(2^4)^2 and the bottom one maps back and adds
+/// the affine constant.
+fn sub_bytes(q: &mut Slices) {
+    let (x0, x1, x2, x3) = (q[7], q[6], q[5], q[4]);
+    let (x4, x5, x6, x7) = (q[3], q[2], q[1], q[0]);
+
+    let y14 = x3 ^ x5;
+    let y13 = x0 ^ x6;
+    let y9 = x0 ^ x3;
+    let y8 = x0 ^ x5;
+    let t0 = x1 ^ x2;
+    let y1 = t0 ^ x7;
+    let y4 = y1 ^ x3;
+    let y12 = y13 ^ y14;
+    let y2 = y1 ^ x0;
+    let y5 = y1 ^ x6;
+    let y3 = y5 ^ y8;
+    let t1 = x4 ^ y12;
+    let y15 = t1 ^ x5;
+    let y20 = t1 ^ x1;
+    let y6 = y15 ^ x7;
+    let y10 = y15 ^ t0;
+    let y11 = y20 ^ y9;
+    let y7 = x7 ^ y11;
+    let y17 = y10 ^ y11;
+    let y19 = y10 ^ y8;
+    let y16 = t0 ^ y11;
+    let y21 = y13 ^ y16;
+    let y18 = x0 ^ y16;
+
+    let t2 = y12 & y15;
+    let t3 = y3 & y6;
+    let t4 = t3 ^ t2;
+    let t5 = y4 & x7;
+    let t6 = t5 ^ t2;
+    let t7 = y13 & y16;
+    let t8 = y5 & y1;
+    let t9 = t8 ^ t7;
+    let t10 = y2 & y7;
+    let t11 = t10 ^ t7;
+    let t12 = y9 & y11;
+    let t13 = y14 & y17;
+    let t14 = t13 ^ t12;
+    let t15 = y8 & y10;
+    let t16 = t15 ^ t12;
+    let t17 = t4 ^ t14;
+    let t18 = t6 ^ t16;
+    let t19 = t9 ^ t14;
+    let t20 = t11 ^ t16;
+    let t21 = t17 ^ y20;
+    let t22 = t18 ^ y19;
+    let t23 = t19 ^ y21;
+    let t24 = t20 ^ y18;
+
+    let t25 = t21 ^ t22;
+    let t26 = t21 & t23;
+    let t27 = t24 ^ t26;
+    let t28 = t25 & t27;
+    let t29 = t28 ^ t22;
+    let t30 = t23 ^ t24;
+    let t31 = t22 ^ t26;
+    let t32 = t31 & t30;
+    let t33 = t32 ^ t24;
+    let t34 = t23 ^ t33;
+    let t35 = t27 ^ t33;
+    let t36 = t24 & t35;
+    let t37 = t36 ^ t34;
+    let t38 = t27 ^ t36;
+    let t39 = t29 & t38;
+    let t40 = t25 ^ t39;
+
+    let t41 = t40 ^ t37;
+    let t42 = t29 ^ t33;
+    let t43 = t29 ^ t40;
+    let t44 = t33 ^ t37;
+    let t45 = t42 ^ t41;
+    let z0 = t44 & y15;
+    let z1 = t37 & y6;
+    let z2 = t33 & x7;
+    let z3 = t43 & y16;
+    let z4 = t40 & y1;
+    let z5 = t29 & y7;
+    let z6 = t42 & y11;
+    let z7 = t45 & y17;
+    let z8 = t41 & y10;
+    let z9 = t44 & y12;
+    let z10 = t37 & y3;
+    let z11 = t33 & y4;
+    let z12 = t43 & y13;
+    let z13 = t40 & y5;
+    let z14 = t29 & y2;
+    let z15 = t42 & y9;
+    let z16 = t45 & y14;
+    let z17 = t41 & y8;
+
+    let t46 = z15 ^ z16;
+    let t47 = z10 ^ z11;
+    let t48 = z5 ^ z13;
+    let t49 = z9 ^ z10;
+    let t50 = z2 ^ z12;
+    let t51 = z2 ^ z5;
+    let t52 = z7 ^ z8;
+    let t53 = z0 ^ z3;
+    let t54 = z6 ^ z7;
+    let t55 = z16 ^ z17;
+    let t56 = z12 ^ t48;
+    let t57 = t50 ^ t53;
+    let t58 = z4 ^ t46;
+    let t59 = z3 ^ t54;
+    let t60 = t46 ^ t57;
+    let t61 = z14 ^ t57;
+    let t62 = t52 ^ t58;
+    let t63 = t49 ^ t58;
+    let t64 = z4 ^ t59;
+    let t65 = t61 ^ t62;
+    let t66 = z1 ^ t63;
+    let s0 = t59 ^ t63;
+    let s6 = t56 ^ !t62;
+    let s7 = t48 ^ !t60;
+    let t67 = t64 ^ t65;
+    let s3 = t53 ^ t66;
+    let s4 = t51 ^ t66;
+    let s5 = t47 ^ t65;
+    let s1 = t64 ^ !s3;
+    let s2 = t55 ^ !t67;
+
+    *q = [s7, s6, s5, s4, s3, s2, s1, s0];
+}
+
+/// The inverse of the affine map of the S-box, `x <<< 1 + x <<< 3 + x <<< 6`
+/// plus 5.
+fn inv_affine(q: &mut Slices) {
+    *q = std::array::from_fn(|b| q[(b + 7) % 8] ^ q[(b + 5) % 8] ^ q[(b + 2) % 8]);
+    q[0] = !q[0];
+    q[2] = !q[2];
+}
+
+/// The S-box is inversion followed by the affine map, so its inverse is the
+/// inverse affine map, then inversion, which is the S-box circuit followed
+/// by the inverse affine map once more.
+fn inv_sub_bytes(q: &mut Slices) {
+    inv_affine(q);
+    sub_bytes(q);
+    inv_affine(q);
+}
+
+/// Row `r` of every column moves `r` columns to the left, i.e. the bits of
+/// that row rotate by `4 * r` positions.
+fn shift_rows(q: &mut Slices) {
+    for slice in q.iter_mut() {
+        *slice = (0..4).fold(0, |shifted, r| {
+            shifted | (*slice & 0x1111 << r).rotate_right(4 * r)
+        });
+    }
+}
+
+fn inv_shift_rows(q: &mut Slices) {
+    for slice in q.iter_mut() {
+        *slice = (0..4).fold(0, |shifted, r| {
+            shifted | (*slice & 0x1111 << r).rotate_left(4 * r)
+        });
+    }
+}
+
+/// Moves every byte of a column up one row.
+fn rotate_rows(slice: u16) -> u16 {
+    (slice >> 1) & 0x7777 | (slice << 3) & 0x8888
+}
+
+/// Multiplies every byte by 2, a shift of the slices with the reduction
+/// folded back in.
+fn double(q: &Slices) -> Slices {
+    [
+        q[7],
+        q[0] ^ q[7],
+        q[1],
+        q[2] ^ q[7],
+        q[3] ^ q[7],
+        q[4],
+        q[5],
+        q[6],
+    ]
+}
+
+/// Each row becomes `2 * (a_r + a_{r+1}) + a_{r+1} + a_{r+2} + a_{r+3}`.
+fn mix_columns(q: &mut Slices) {
+    let rotated: Slices = q.map(rotate_rows);
+    let doubled = double(&std::array::from_fn(|b| q[b] ^ rotated[b]));
+    for b in 0..8 {
+        let rotated_twice = rotate_rows(rotated[b]);
+        q[b] = doubled[b] ^ rotated[b] ^ rotated_twice ^ rotate_rows(rotated_twice);
+    }
+}
+
+/// InvMixColumns is MixColumns after multiplying by `5 + 4x^2`, i.e. each row
+/// becomes `a_r + 4 * (a_r + a_{r+2})` first.
+fn inv_mix_columns(q: &mut Slices) {
+    let sum: Slices = std::array::from_fn(|b| q[b] ^ rotate_rows(rotate_rows(q[b])));
+    let quadrupled = double(&double(&sum));
+    for b in 0..8 {
+        q[b] ^= quadrupled[b];
+    }
+    mix_columns(q);
+}
+
+fn add_round_key(q: &mut Slices, round_key: &Slices) {
+    for (slice, key_slice) in q.iter_mut().zip(round_key) {
+        *slice ^= key_slice;
+    }
+}
+
+/// AES-128 in software without secret-dependent memory accesses or branches:
+/// the state is bitsliced and SubBytes is the Boyar-Peralta circuit, so its
+/// running time does not depend on the key or the data, unlike `TTableAES`.
+/// It implements `EncryptionService`, so it can be the oracle of the attacks
+/// in place of `AES128`, and is the `Backend::Bitsliced` of `AES128`.
+pub struct BitslicedAES {
+    round_keys: Vec<Slices>,
+    num_rounds: usize,
+}
+
+impl BitslicedAES {
+    pub fn new(key: Block, num_rounds: usize) -> Self {
+        Self {
+            round_keys: Self::key_expansion(key)
+                .iter()
+                .take(num_rounds + 1)
+                .map(bitslice)
+                .collect(),
+            num_rounds,
+        }
+    }
+
+    /// The round keys of all 10 rounds, SubWord going through the same
+    /// circuit as the state.
+    pub fn key_expansion(key: Block) -> Vec<Block> {
+        let mut round_keys = vec![key];
+        for rcon in RCON {
+            let prev = round_keys.last().unwrap();
+            let mut rot_word = [0; BLOCK_SIZE];
+            for i in 0..4 {
+                rot_word[i] = prev[12 + (i + 1) % 4];
+            }
+            let mut slices = bitslice(&rot_word);
+            sub_bytes(&mut slices);
+            let mut sub_word = unbitslice(&slices);
+            sub_word[0] ^= rcon;
+
+            let mut round_key = [0; BLOCK_SIZE];
+            for i in 0..BLOCK_SIZE {
+                let prev_word = if i < 4 { sub_word[i] } else { round_key[i - 4] };
+                round_key[i] = prev[i] ^ prev_word;
+            }
+            round_keys.push(round_key);
+        }
+        round_keys
+    }
+
+    pub fn encrypt(&self, msg: Block) -> Block {
+        let mut state = bitslice(&msg);
+        add_round_key(&mut state, &self.round_keys[0]);
+        for round in 1..=self.num_rounds {
+            sub_bytes(&mut state);
+            shift_rows(&mut state);
+            if round != self.num_rounds {
+                mix_columns(&mut state);
+            }
+            add_round_key(&mut state, &self.round_keys[round]);
+        }
+        unbitslice(&state)
+    }
+
+    pub fn decrypt(&self, enc_msg: Block) -> Block {
+        let mut state = bitslice(&enc_msg);
+        for round in (1..=self.num_rounds).rev() {
+            add_round_key(&mut state, &self.round_keys[round]);
+            if round != self.num_rounds {
+                inv_mix_columns(&mut state);
+            }
+            inv_shift_rows(&mut state);
+            inv_sub_bytes(&mut state);
+        }
+        add_round_key(&mut state, &self.round_keys[0]);
+        unbitslice(&state)
+    }
+}
+
+impl EncryptionService for BitslicedAES {
+    unsafe fn encrypt(&self, msg: Block) -> Block {
+        BitslicedAES::encrypt(self, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::{Backend, AES128};
+    use crate::attack::{crack_key_four_rounds, gen_random_block};
+    use crate::rijndael::sbox;
+
+    #[test]
+    fn test_sub_bytes() {
+        let sbox = sbox();
+        for chunk in 0..16 {
+            let block: Block = std::array::from_fn(|i| (16 * chunk + i) as u8);
+            let mut slices = bitslice(&block);
+            sub_bytes(&mut slices);
+            assert_eq!(unbitslice(&slices), block.map(|byte| sbox[byte as usize]));
+        }
+    }
+
+    #[test]
+    fn test_matches_aes() {
+        for num_rounds in [1, 4, 10] {
+            let key = gen_random_block();
+            let bitsliced = BitslicedAES::new(key, num_rounds);
+            let msg = gen_random_block();
+            unsafe {
+                assert_eq!(
+                    bitsliced.encrypt(msg),
+                    AES128::new(key, num_rounds).encrypt(msg)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_decrypt() {
+        for num_rounds in [1, 4, 10] {
+            let bitsliced = BitslicedAES::new(gen_random_block(), num_rounds);
+            let msg = gen_random_block();
+            assert_eq!(bitsliced.decrypt(bitsliced.encrypt(msg)), msg);
+        }
+    }
+
+    #[test]
+    fn test_aes128_backend() {
+        let (key, msg) = (gen_random_block(), gen_random_block());
+        unsafe {
+            let aes = AES128::new(key, 10);
+            let bitsliced = AES128::with_backend(key, 10, Backend::Bitsliced);
+            let enc_msg = aes.encrypt(msg);
+            assert_eq!(bitsliced.encrypt(msg), enc_msg);
+            assert_eq!(bitsliced.encrypt_batch(&[msg; 9]), vec![enc_msg; 9]);
+            assert_eq!(bitsliced.decrypt(enc_msg), msg);
+        }
+    }
+
+    #[test]
+    fn test_key_expansion() {
+        let key = gen_random_block();
+        unsafe {
+            let expected = AES128::key_expansion(AES128::block_to_state(key));
+            for (round_key, expected) in BitslicedAES::key_expansion(key).iter().zip(expected) {
+                assert_eq!(*round_key, AES128::state_to_block(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn test_crack_key_four_rounds() {
+        let key = gen_random_block();
+        let bitsliced = BitslicedAES::new(key, 4);
+        unsafe {
+            assert_eq!(crack_key_four_rounds(&bitsliced), key);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::aes::{Block, BLOCK_SIZE};
+use crate::attack::gen_random_block;
+use crate::rijndael::{gf256_mul, inv_sbox, sbox, Rijndael, MIX_COLUMNS};
+use crate::ttable::{CacheTrace, LAST_ROUND_TABLE, LINE_ENTRIES};
+
+/// The byte of the key which the first round key passes through the S-box
+/// in row 0. Its low nibble changes the lookups of the second round by a
+/// constant, which may or may not move them to another line.
+const SCHEDULE_BYTE: usize = 13;
+
+/// What a spy process sharing the cache learns from one encryption of a
+/// random plaintext: the plaintext, the ciphertext and which lines of every
+/// table were loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Observation {
+    pub msg: Block,
+    pub enc: Block,
+    pub touched: [u16; 5],
+}
+
+impl Observation {
+    /// Encrypts a random plaintext with `service`, typically
+    /// `TTableAES::encrypt_with_cache_trace`.
+    pub fn new<S: Fn(Block) -> (Block, CacheTrace)>(service: &S) -> Self {
+        let msg = gen_random_block();
+        let (enc, trace) = service(msg);
+        Self {
+            msg,
+            enc,
+            touched: std::array::from_fn(|table| trace.touched_lines(table)),
+        }
+    }
+
+    /// Whether the line holding entry `index` of `table` was loaded.
+    fn touches(&self, table: usize, index: u8) -> bool {
+        self.touched[table] >> (index as usize / LINE_ENTRIES) & 1 == 1
+    }
+}
+
+/// Byte `pos` of the first round key, from the key.
+fn first_round_key_byte(sbox: &[u8; 256], key: &Block, pos: usize) -> u8 {
+    let row = pos % 4;
+    let rcon = if row == 0 { 1 } else { 0 };
+    let first = key[row] ^ sbox[key[12 + (row + 1) % 4] as usize] ^ rcon;
+    (1..=pos / 4).fold(first, |byte, col| byte ^ key[4 * col + row])
+}
+
+/// Byte `pos` of the state after the first round, which the second round
+/// looks up in table `pos % 4`.
+fn first_round_output(sbox: &[u8; 256], key: &Block, msg: &Block, pos: usize) -> u8 {
+    let (col, row) = (pos / 4, pos % 4);
+    let mixed = (0..4).fold(0, |mixed, r| {
+        let shifted = 4 * ((col + r) % 4) + r;
+        mixed
+            ^ gf256_mul(
+                MIX_COLUMNS[(4 + r - row) % 4],
+                sbox[(msg[shifted] ^ key[shifted]) as usize],
+            )
+    });
+    mixed ^ first_round_key_byte(sbox, key, pos)
+}
+
+/// `key` with the low nibbles at `positions` taken from `guess`.
+fn with_low_nibbles(key: &Block, positions: &[usize], guess: usize) -> Block {
+    let mut key = *key;
+    for (i, &pos) in positions.iter().enumerate() {
+        key[pos] = key[pos] & 0xf0 | ((guess >> (4 * i)) & 0xf) as u8;
+    }
+    key
+}
+
+/// The one-round attack of Osvik, Shamir and Tromer: the first round looks
+/// up byte `i` of the plaintext xored with the key in table `i % 4`, so a
+/// guess of the high nibble of key byte `i` is wrong when the line it
+/// predicts was not loaded. A table has 16 lines and 36 lookups in 10
+/// rounds, so a wrong guess survives about 9 observations out of 10 and a
+/// hundred or so settle every nibble. Returns the key with its low nibbles
+/// cleared, which share a line and do not leak in the first round.
+pub fn crack_high_nibbles<S: Fn(Block) -> (Block, CacheTrace)>(service: &S) -> Block {
+    let mut candidates = [u16::MAX; BLOCK_SIZE];
+    while candidates.iter().any(|nibbles| nibbles.count_ones() > 1) {
+        let observation = Observation::new(service);
+        for (i, nibbles) in candidates.iter_mut().enumerate() {
+            for nibble in 0..16 {
+                if !observation.touches(i % 4, observation.msg[i] ^ nibble << 4) {
+                    *nibbles &= !(1 << nibble);
+                }
+            }
+        }
+    }
+    candidates.map(|nibbles| (nibbles.trailing_zeros() as u8) << 4)
+}
+
+/// The two-round attack of Osvik, Shamir and Tromer: the high nibbles from
+/// the first round, then the low ones from lookups of the second round.
+///
+/// The first byte of column `col` after the first round depends on the
+/// diagonal `col` of the key through MixColumns and on `SCHEDULE_BYTE`
+/// through the first round key, whose other bytes are xored in and only
+/// move the lookup within its line. The low nibbles of both are guessed,
+/// 2^20 at a time, and ruled out like the high nibbles were until the
+/// diagonal is settled; the constant `SCHEDULE_BYTE` adds may leave several
+/// of its own guesses, so it is settled with its diagonal. It takes a few
+/// hundred observations in total.
+pub fn crack_key_first_two_rounds<S: Fn(Block) -> (Block, CacheTrace)>(service: &S) -> Block {
+    let sbox = sbox();
+    let mut key = crack_high_nibbles(service);
+    let mut observations: Vec<Observation> = (0..64).map(|_| Observation::new(service)).collect();
+    for col in 0..4 {
+        let mut positions: Vec<usize> = (0..4).map(|r| 4 * ((col + r) % 4) + r).collect();
+        if !positions.contains(&SCHEDULE_BYTE) {
+            positions.push(SCHEDULE_BYTE);
+        }
+        let mut guesses: Vec<usize> = (0..1 << (4 * positions.len())).collect();
+        let mut checked = 0;
+        loop {
+            guesses.retain(|&guess| {
+                let key = with_low_nibbles(&key, &positions, guess);
+                observations[checked..].iter().all(|observation| {
+                    let index = first_round_output(&sbox, &key, &observation.msg, 4 * col);
+                    observation.touches(0, index)
+                })
+            });
+            if guesses
+                .iter()
+                .all(|guess| guess & 0xffff == guesses[0] & 0xffff)
+            {
+                break;
+            }
+            checked = observations.len();
+            observations.extend((0..64).map(|_| Observation::new(service)));
+        }
+        key = with_low_nibbles(&key, &positions[..4], guesses[0]);
+    }
+    key
+}
+
+/// The last-round attack: byte `i` of the ciphertext is an entry of the last
+/// table xored with byte `i` of the last round key, and that table is only
+/// used in the last round. A guess of the key byte is wrong when the entry
+/// it predicts lies on a line that was not loaded, which happens for about a
+/// third of the lines, so some 30 observations give the whole last round key
+/// and the key schedule gives the key.
+pub fn crack_key_last_round<S: Fn(Block) -> (Block, CacheTrace)>(
+    service: &S,
+    num_rounds: usize,
+) -> Block {
+    let inv_sbox = inv_sbox();
+    let mut candidates: Vec<Vec<u8>> = vec![(0..=u8::MAX).collect(); BLOCK_SIZE];
+    while candidates.iter().any(|bytes| bytes.len() > 1) {
+        let observation = Observation::new(service);
+        for (i, bytes) in candidates.iter_mut().enumerate() {
+            bytes.retain(|&byte| {
+                let index = inv_sbox[(observation.enc[i] ^ byte) as usize];
+                observation.touches(LAST_ROUND_TABLE, index)
+            });
+        }
+    }
+    let last_round_key: Vec<u8> = candidates.iter().map(|bytes| bytes[0]).collect();
+    // Only the key schedule of the cipher is used.
+    Rijndael::new(&[0; BLOCK_SIZE], 4, num_rounds)
+        .invert_key_expansion(&last_round_key, num_rounds)
+        .try_into()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ttable::TTableAES;
+
+    #[test]
+    fn test_first_round_output() {
+        let key = gen_random_block();
+        let ttable = TTableAES::new(key, 10);
+        let msg = gen_random_block();
+        let (_, trace) = ttable.encrypt_with_cache_trace(msg);
+        let sbox = sbox();
+        // The second round looks up column by column, row by row.
+        for pos in 0..BLOCK_SIZE {
+            let (col, row) = (pos / 4, pos % 4);
+            let access = trace.accesses[16 + 4 * ((col + 4 - row) % 4) + row];
+            assert_eq!(access.table, row);
+            let index = first_round_output(&sbox, &key, &msg, pos);
+            assert_eq!(access.line, index as usize / LINE_ENTRIES);
+        }
+    }
+
+    #[test]
+    fn test_crack_high_nibbles() {
+        let key = gen_random_block();
+        let ttable = TTableAES::new(key, 10);
+        let service = |msg: Block| ttable.encrypt_with_cache_trace(msg);
+        assert_eq!(crack_high_nibbles(&service), key.map(|byte| byte & 0xf0));
+    }
+
+    #[test]
+    fn test_crack_key_first_two_rounds() {
+        let key = gen_random_block();
+        let ttable = TTableAES::new(key, 10);
+        let service = |msg: Block| ttable.encrypt_with_cache_trace(msg);
+        assert_eq!(crack_key_first_two_rounds(&service), key);
+    }
+
+    #[test]
+    fn test_crack_key_last_round() {
+        let key = gen_random_block();
+        let ttable = TTableAES::new(key, 10);
+        let service = |msg: Block| ttable.encrypt_with_cache_trace(msg);
+        assert_eq!(crack_key_last_round(&service, 10), key);
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use rand::{thread_rng, Rng};
+
+use crate::generic_attack::{completions, peel_last_round, setup, CellCipher};
+use crate::impossible::{diagonal, shifted_column, unmixed_service};
+
+/// Structures tried for a diagonal while more than one guess survives.
+const MAX_STRUCTURES: usize = 8;
+
+/// How many values `c` of the other cells of the column are tried. The
+/// functions only depend on a column's worth of cells computed from `c`, so
+/// this gives about 32 pairs of equal functions.
+fn num_parameters<C: CellCipher>(cipher: &C) -> usize {
+    let bits = cipher.cell_bits() as usize;
+    let all = 1 << (bits * (cipher.num_rows() - 1));
+    usize::min(all, 1 << (bits * cipher.num_rows() / 2 + 3))
+}
+
+/// A column with `y` in its first cell and the cells of `c` below it.
+fn column_cells<C: CellCipher>(cipher: &C, y: usize, c: usize) -> Vec<u8> {
+    let bits = cipher.cell_bits() as usize;
+    let mask = (1 << bits) - 1;
+    let mut cells = vec![y as u8];
+    cells.extend((0..cipher.num_rows() - 1).map(|row| ((c >> (bits * row)) & mask) as u8));
+    cells
+}
+
+/// Whether two of the functions, each given by its values on every `y`, are
+/// equal.
+fn has_colliding_functions(functions: impl Iterator<Item = Vec<u8>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut functions = functions;
+    !functions.all(|function| seen.insert(function))
+}
+
+/// The 4-round distinguisher of Gilbert and Minier: a column with one
+/// variable cell `y` and parameters `c` in the other cells.
+///
+/// After the first round `y` alone determines one column and `c` the other
+/// three, so every cell after two rounds is an affine function of one
+/// S-box output depending on `y`, plus a constant depending on `c`. The
+/// first cell after three rounds is therefore a function of `y` fixed by
+/// just a column's worth of these constants, which collide between values
+/// of `c` long before the functions of a random permutation would. The
+/// last round maps that cell to an output cell through a fixed S-box and
+/// key.
+pub fn is_four_round_collision<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+) -> bool {
+    let out_cell = shifted_column(cipher, 0)[0];
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let base: Vec<u8> = (0..cipher.num_cells())
+        .map(|_| thread_rng().gen_range(0..1u16 << cipher.cell_bits()) as u8)
+        .collect();
+    let functions = (0..num_parameters(cipher)).map(|c| {
+        (0..1 << cipher.cell_bits())
+            .map(|y| {
+                let mut msg = base.clone();
+                msg[..cipher.num_rows()].copy_from_slice(&column_cells(cipher, y, c));
+                encryption_service(&msg)[out_cell]
+            })
+            .collect()
+    });
+    has_colliding_functions(functions)
+}
+
+/// The guesses of the first-round key cells of diagonal `col`, packed like
+/// the values of a structure, under which the encryptions one round deep
+/// show the 4-round collisions. Cells of the first round key given in
+/// `planted` are not guessed.
+///
+/// A structure over the diagonal contains every column after the first
+/// round, so under a guess the texts with the first cell `y` and the others
+/// `c` after it are looked up rather than encrypted. The round key added
+/// after the first round only relabels `y` and `c`.
+fn surviving_diagonal_keys<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    col: usize,
+    planted: &[Option<u8>],
+) -> Vec<usize> {
+    let out_cell = shifted_column(cipher, col)[0];
+    let outputs: Vec<u8> = setup(cipher, encryption_service, &diagonal(cipher, col))
+        .iter()
+        .map(|enc| enc[out_cell])
+        .collect();
+    surviving_keys(cipher, &outputs, col, planted)
+}
+
+/// `surviving_diagonal_keys` given the distinguished cell of every text of
+/// the structure.
+fn surviving_keys<C: CellCipher>(
+    cipher: &C,
+    outputs: &[u8],
+    col: usize,
+    planted: &[Option<u8>],
+) -> Vec<usize> {
+    let (rows, bits) = (cipher.num_rows(), cipher.cell_bits() as usize);
+    let diagonal = diagonal(cipher, col);
+
+    // InvMixColumns is linear, so a column (y, c) is the sum of (y, 0) and
+    // (0, c).
+    let inv_mixed = |cells: Vec<u8>| {
+        let mut state = vec![0; cipher.num_cells()];
+        state[rows * col..rows * (col + 1)].copy_from_slice(&cells);
+        cipher.inv_mix_columns(&state)[rows * col..rows * (col + 1)].to_vec()
+    };
+    let ys: Vec<Vec<u8>> = (0..1 << bits)
+        .map(|y| inv_mixed(column_cells(cipher, y, 0)))
+        .collect();
+    let cs: Vec<Vec<u8>> = (0..num_parameters(cipher))
+        .map(|c| inv_mixed(column_cells(cipher, 0, c)))
+        .collect();
+
+    let mask = (1 << bits) - 1;
+    (0..1usize << (bits * rows))
+        .filter(|&guess| {
+            diagonal.iter().enumerate().all(|(row, &pos)| {
+                planted[pos].is_none_or(|cell| (guess >> (bits * row)) & mask == cell as usize)
+            })
+        })
+        .filter(|&guess| {
+            let functions = cs.iter().map(|c| {
+                ys.iter()
+                    .map(|y| {
+                        let index = (0..rows).fold(0, |index, row| {
+                            let cell = cipher.inv_sbox(y[row] ^ c[row]) as usize;
+                            let key_cell = (guess >> (bits * row)) & mask;
+                            index | (cell ^ key_cell) << (bits * row)
+                        });
+                        outputs[index]
+                    })
+                    .collect()
+            });
+            has_colliding_functions(functions)
+        })
+        .collect()
+}
+
+/// Gilbert-Minier attack on 5 rounds: one round of key guessing in front of
+/// the 4-round distinguisher, one diagonal of the first round key at a time.
+///
+/// Every guess looks up the functions of some 2^19 parameters on AES, so
+/// guessing a whole diagonal is far out of reach here; `planted` gives the
+/// cells of the first round key the attacker is told to scale it down. On
+/// the 4x4 small-scale variant a structure of 2^16 chosen plaintexts per
+/// diagonal suffices.
+///
+/// Returns `None` if no guess of some diagonal survives, as happens with a
+/// wrong planted cell, or if several still do after `MAX_STRUCTURES`
+/// structures.
+pub fn crack_key_collision<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    planted: &[Option<u8>],
+) -> Option<Vec<u8>> {
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let bits = cipher.cell_bits() as usize;
+    let mut first_round_key = vec![0; cipher.num_cells()];
+    for col in 0..cipher.num_cols() {
+        let mut guesses = vec![];
+        for _ in 0..MAX_STRUCTURES {
+            guesses = surviving_diagonal_keys(cipher, &encryption_service, col, planted);
+            if guesses.len() <= 1 {
+                break;
+            }
+        }
+        let [guess] = guesses[..] else {
+            return None;
+        };
+        for (row, pos) in diagonal(cipher, col).into_iter().enumerate() {
+            first_round_key[pos] = ((guess >> (bits * row)) % (1 << bits)) as u8;
+        }
+    }
+    Some(cipher.invert_key_expansion(&first_round_key, 0))
+}
+
+/// Gilbert-Minier attack on 7 rounds: the 5-round attack behind two peeled
+/// rounds. The distinguished cell lies before the MixColumns of the fifth
+/// round, so reaching it takes the whole last round key and those cells of
+/// the equivalent key of the round before, added after InvMixColumns, which
+/// ShiftRows fills from the column of that cell. These are guessed but for
+/// the cells given in `planted_last` and `planted_mixed`, and a guess is
+/// wrong if no guess of the first diagonal survives. One structure serves
+/// every guess, and the last round is only undone once per guess of its key.
+pub fn crack_key_collision_seven_rounds<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    planted: &[Option<u8>],
+    planted_last: &[Option<u8>],
+    planted_mixed: &[Option<u8>],
+) -> Option<Vec<u8>> {
+    let rows = cipher.num_rows();
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let out_cell = shifted_column(cipher, 0)[0];
+    let out_col = out_cell / rows;
+    let needed = shifted_column(cipher, out_col);
+    // The distinguished cell is linear in the column after InvMixColumns.
+    let inv_mix_table: Vec<Vec<u8>> = (0..rows)
+        .map(|row| {
+            (0..1 << cipher.cell_bits())
+                .map(|value| {
+                    let mut state = vec![0; cipher.num_cells()];
+                    state[rows * out_col + row] = value as u8;
+                    cipher.inv_mix_columns(&state)[out_cell]
+                })
+                .collect()
+        })
+        .collect();
+    // The other cells of the mixed key do not matter.
+    let planted_mixed: Vec<Option<u8>> = (0..cipher.num_cells())
+        .map(|pos| match needed.contains(&pos) {
+            true => planted_mixed[pos],
+            false => Some(0),
+        })
+        .collect();
+    let structure = setup(cipher, &encryption_service, &diagonal(cipher, 0));
+    completions(cipher, planted_last).find_map(|last_round_key| {
+        let equivalent_key = if cipher.final_mix_columns() {
+            cipher.inv_mix_columns(&last_round_key)
+        } else {
+            last_round_key.clone()
+        };
+        let peeled = peel_last_round(cipher, &structure, &equivalent_key, out_col);
+        completions(cipher, &planted_mixed).find_map(|mixed_key| {
+            let outputs: Vec<u8> = peeled
+                .iter()
+                .map(|cells| {
+                    (0..rows).fold(0, |acc, row| {
+                        let cell = cipher.inv_sbox(cells[row] ^ mixed_key[needed[row]]);
+                        acc ^ inv_mix_table[row][cell as usize]
+                    })
+                })
+                .collect();
+            (!surviving_keys(cipher, &outputs, 0, planted).is_empty())
+                .then(|| cipher.invert_key_expansion(&last_round_key, 7))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::small_aes::SmallAES;
+
+    /// Plants all but two cells of the first round key.
+    fn planted(key: &[u8]) -> Vec<Option<u8>> {
+        key.iter()
+            .enumerate()
+            .map(|(pos, &cell)| (pos != 0 && pos != 5).then_some(cell))
+            .collect()
+    }
+
+    #[test]
+    fn test_four_round_collision() {
+        let key: Vec<u8> = (0..16).map(|i| (7 * i + 2) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 4);
+        assert!(is_four_round_collision(&cipher, &|msg: &[u8]| cipher.encrypt(msg)));
+        let cipher = SmallAES::new(&key, 4, 4, 5);
+        assert!(!is_four_round_collision(&cipher, &|msg: &[u8]| cipher.encrypt(msg)));
+    }
+
+    #[test]
+    fn test_surviving_diagonal_keys() {
+        let key: Vec<u8> = (0..16).map(|i| (5 * i + 3) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 5);
+        let guesses = surviving_diagonal_keys(
+            &cipher,
+            &|msg: &[u8]| cipher.encrypt(msg),
+            0,
+            &planted(&key),
+        );
+        let right_guess = diagonal(&cipher, 0)
+            .iter()
+            .enumerate()
+            .fold(0, |guess, (row, &pos)| {
+                guess | (key[pos] as usize) << (4 * row)
+            });
+        assert_eq!(guesses, vec![right_guess]);
+    }
+
+    #[test]
+    fn test_crack_key_collision() {
+        let key: Vec<u8> = (0..16).map(|i| (11 * i + 4) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 5);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        // Only the first cell of the first round key is guessed.
+        let mut planted: Vec<Option<u8>> = key.iter().map(|&cell| Some(cell)).collect();
+        planted[0] = None;
+        assert_eq!(
+            crack_key_collision(&cipher, &encryption_service, &planted),
+            Some(key.clone())
+        );
+        planted[10] = Some(key[10] ^ 1);
+        assert_eq!(
+            crack_key_collision(&cipher, &encryption_service, &planted),
+            None
+        );
+    }
+
+    #[test]
+    fn test_crack_key_collision_seven_rounds() {
+        let key: Vec<u8> = (0..16).map(|i| (3 * i + 1) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 7);
+        let round_keys = cipher.key_expansion(&key);
+        // Only one cell of each of the last two round keys is guessed, and
+        // none of the first.
+        let plant_all_but = |round_key: &[u8], unknown| -> Vec<Option<u8>> {
+            (0..16)
+                .map(|pos| (pos != unknown).then_some(round_key[pos]))
+                .collect()
+        };
+        assert_eq!(
+            crack_key_collision_seven_rounds(
+                &cipher,
+                &|msg: &[u8]| cipher.encrypt(msg),
+                &plant_all_but(&key, 16),
+                &plant_all_but(&round_keys[7], 3),
+                &plant_all_but(&cipher.inv_mix_columns(&round_keys[6]), 5),
+            ),
+            Some(key)
+        );
+    }
+}
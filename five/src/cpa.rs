@@ -0,0 +1,173 @@
+use std::ops::Range;
+
+use crate::aes::Block;
+use crate::leakage::{LeakageModel, TraceSet};
+use crate::rijndael::sbox;
+
+/// The values that leak for byte `byte` of the first SubBytes of every trace
+/// if `guess` is that byte of the first round key, as input and output.
+fn first_sbox<'a>(
+    sbox: &'a [u8; 256],
+    traces: &'a TraceSet,
+    byte: usize,
+    guess: u8,
+) -> impl Iterator<Item = (u8, u8)> + 'a {
+    traces.plaintexts.iter().map(move |msg| {
+        let input = msg[byte] ^ guess;
+        (input, sbox[input as usize])
+    })
+}
+
+/// The guess of every byte of the first round key scoring highest.
+fn best_guesses(score: impl Fn(usize, u8) -> f64) -> Block {
+    std::array::from_fn(|byte| {
+        (0..=u8::MAX)
+            .map(|guess| (score(byte, guess), guess))
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .unwrap()
+            .1
+    })
+}
+
+/// The samples in `window` of every trace, centred on their mean, with their
+/// norms.
+fn centred_samples(traces: &TraceSet, window: Range<usize>) -> Vec<(Vec<f64>, f64)> {
+    window
+        .map(|i| {
+            let samples: Vec<f64> = traces.traces.iter().map(|trace| trace[i] as f64).collect();
+            centred(samples)
+        })
+        .collect()
+}
+
+fn centred(values: Vec<f64>) -> (Vec<f64>, f64) {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let values: Vec<f64> = values.into_iter().map(|value| value - mean).collect();
+    let norm = values.iter().map(|value| value * value).sum::<f64>().sqrt();
+    (values, norm)
+}
+
+/// Correlation power analysis: recovers the first round key from traces of
+/// encryptions leaking by `model`.
+///
+/// Every guess of a key byte predicts the leakage of its S-box in every
+/// trace, and the right one correlates with the samples where that S-box
+/// is computed. The guess with the highest absolute Pearson correlation with
+/// any sample in `window` wins, so the attacker needs to know roughly where
+/// the first round happens but not which sample belongs to which byte.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_first_round_key_cpa(
+    traces: &TraceSet,
+    model: LeakageModel,
+    window: Range<usize>,
+) -> Block {
+    let sbox = sbox();
+    let samples = centred_samples(traces, window);
+    best_guesses(|byte, guess| {
+        let (hypotheses, norm) = centred(
+            first_sbox(&sbox, traces, byte, guess)
+                .map(|(input, output)| model.leak(input, output) as f64)
+                .collect(),
+        );
+        samples
+            .iter()
+            .map(|(samples, samples_norm)| {
+                let covariance: f64 = hypotheses.iter().zip(samples).map(|(h, s)| h * s).sum();
+                (covariance / (norm * samples_norm)).abs()
+            })
+            .fold(0.0, f64::max)
+    })
+}
+
+/// Kocher's differential power analysis: recovers the first round key by
+/// splitting the traces on one predicted bit, the lowest of the S-box output
+/// or of the bits it flips depending on `model`.
+///
+/// Under the right guess of a key byte, the traces with the bit set leak
+/// more on average where that S-box is computed than those without it, and
+/// the guess with the largest difference of means at any sample in `window`
+/// wins. It needs more traces than CPA, as it throws away the other seven
+/// bits.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_first_round_key_dpa(
+    traces: &TraceSet,
+    model: LeakageModel,
+    window: Range<usize>,
+) -> Block {
+    let sbox = sbox();
+    best_guesses(|byte, guess| {
+        let selection: Vec<bool> = first_sbox(&sbox, traces, byte, guess)
+            .map(|(input, output)| match model {
+                LeakageModel::HammingWeight => output & 1 == 1,
+                LeakageModel::HammingDistance => (input ^ output) & 1 == 1,
+            })
+            .collect();
+        let num_set = selection.iter().filter(|&&bit| bit).count() as f64;
+        let num_unset = selection.len() as f64 - num_set;
+        window
+            .clone()
+            .map(|i| {
+                let (mut set, mut unset) = (0.0, 0.0);
+                for (trace, &bit) in traces.traces.iter().zip(&selection) {
+                    if bit {
+                        set += trace[i] as f64;
+                    } else {
+                        unset += trace[i] as f64;
+                    }
+                }
+                (set / num_set - unset / num_unset).abs()
+            })
+            .fold(0.0, f64::max)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+    use crate::attack::gen_random_block;
+    use crate::leakage::LeakageSimulator;
+
+    #[test]
+    fn test_cpa_hamming_weight() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let simulator = LeakageSimulator::new(LeakageModel::HammingWeight, 1.0);
+            let traces = TraceSet::collect(&aes, &simulator, 200);
+            let window = 0..traces.num_samples();
+            assert_eq!(
+                crack_first_round_key_cpa(&traces, LeakageModel::HammingWeight, window),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_cpa_hamming_distance() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let simulator = LeakageSimulator::new(LeakageModel::HammingDistance, 1.0);
+            let traces = TraceSet::collect(&aes, &simulator, 200);
+            assert_eq!(
+                crack_first_round_key_cpa(&traces, LeakageModel::HammingDistance, 0..32),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_dpa() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let simulator = LeakageSimulator::new(LeakageModel::HammingWeight, 0.5);
+            let traces = TraceSet::collect(&aes, &simulator, 2000);
+            assert_eq!(
+                crack_first_round_key_dpa(&traces, LeakageModel::HammingWeight, 0..32),
+                key
+            );
+        }
+    }
+}
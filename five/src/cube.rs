@@ -0,0 +1,423 @@
+use rand::{thread_rng, Rng};
+
+use crate::aes::AES128;
+use crate::attack::EncryptionService;
+use crate::small_aes::SmallAES;
+
+/// A cipher whose key the attacker chooses in the preprocessing phase of the
+/// cube attack. Keys and blocks are bit vectors of `num_bits` bits packed
+/// into a `u128`, bit `j` of cell `i` at `cell_bits * i + j`.
+pub trait CubeCipher {
+    fn num_bits(&self) -> usize;
+    fn cell_bits(&self) -> usize;
+    unsafe fn encrypt_batch(&self, key: u128, msgs: &[u128]) -> Vec<u128>;
+}
+
+/// AES reduced to `num_rounds`, encrypting through `AES128::encrypt_batch`.
+///
+/// Only one round is in reach of key recovery: with the S-box of degree 7,
+/// an output bit of two rounds has a degree of up to 49 in the bits of a
+/// diagonal, so a cube with a linear superpoly takes 2^48 encryptions, and
+/// three rounds can already reach the full degree of 127. `estimate_degree`
+/// still measures the degrees of reduced rounds.
+pub struct ReducedAES {
+    pub num_rounds: usize,
+}
+
+impl CubeCipher for ReducedAES {
+    fn num_bits(&self) -> usize {
+        128
+    }
+
+    fn cell_bits(&self) -> usize {
+        8
+    }
+
+    #[target_feature(enable = "avx2,aes")]
+    unsafe fn encrypt_batch(&self, key: u128, msgs: &[u128]) -> Vec<u128> {
+        encrypt_batch(&AES128::new(key.to_le_bytes(), self.num_rounds), msgs)
+    }
+}
+
+/// The 4x4 small-scale variant with 4-bit cells reduced to `num_rounds`.
+pub struct ReducedSmallAES {
+    pub num_rounds: usize,
+}
+
+impl CubeCipher for ReducedSmallAES {
+    fn num_bits(&self) -> usize {
+        64
+    }
+
+    fn cell_bits(&self) -> usize {
+        4
+    }
+
+    unsafe fn encrypt_batch(&self, key: u128, msgs: &[u128]) -> Vec<u128> {
+        let cipher = SmallAES::new(&SmallAES::unpack(key, 16), 4, 4, self.num_rounds);
+        msgs.iter()
+            .map(|&msg| SmallAES::pack(&cipher.encrypt(&SmallAES::unpack(msg, 16))))
+            .collect()
+    }
+}
+
+/// Batch encryption of packed blocks with an AES encryption service under
+/// an unknown key, for the online phase.
+pub unsafe fn encrypt_batch<E: EncryptionService>(
+    encryption_service: &E,
+    msgs: &[u128],
+) -> Vec<u128> {
+    let msgs: Vec<_> = msgs.iter().map(|msg| msg.to_le_bytes()).collect();
+    encryption_service
+        .encrypt_batch(&msgs)
+        .into_iter()
+        .map(u128::from_le_bytes)
+        .collect()
+}
+
+/// The sum of the encryptions of every plaintext taking all values on the
+/// `cube` bits and those of `base` elsewhere, each output bit giving the
+/// value of its superpoly.
+pub fn cube_sum<F: Fn(&[u128]) -> Vec<u128>>(
+    encrypt_batch: &F,
+    cube: &[usize],
+    base: u128,
+) -> u128 {
+    let base = cube.iter().fold(base, |base, &bit| base & !(1 << bit));
+    let msgs: Vec<u128> = (0..1usize << cube.len())
+        .map(|vertex| {
+            cube.iter().enumerate().fold(base, |msg, (i, &bit)| {
+                msg | (((vertex >> i) & 1) as u128) << bit
+            })
+        })
+        .collect();
+    encrypt_batch(&msgs)
+        .into_iter()
+        .fold(0, |sum, enc| sum ^ enc)
+}
+
+fn random_bits(num_bits: usize) -> u128 {
+    thread_rng().gen::<u128>() & (u128::MAX >> (128 - num_bits))
+}
+
+/// A superpoly linear in the key bits: summing output bit `output_bit` over
+/// `cube`, the other plaintext bits zero, gives `constant` plus the key bits
+/// in `key_bits`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Superpoly {
+    pub cube: Vec<usize>,
+    pub output_bit: usize,
+    pub key_bits: u128,
+    pub constant: bool,
+}
+
+/// The output bits whose superpoly over `cube` is linear and not constant,
+/// found in the preprocessing phase with chosen keys.
+///
+/// The superpolies of all output bits are tested at once with `num_tests`
+/// rounds of the linearity test of Blum, Luby and Rubinfeld,
+/// `p(x) + p(y) = p(x + y) + p(0)` for random keys `x` and `y`, and the
+/// survivors are interpolated from the cube sums under the unit keys.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn linear_superpolies<C: CubeCipher>(
+    cipher: &C,
+    cube: &[usize],
+    num_tests: usize,
+) -> Vec<Superpoly> {
+    let num_bits = cipher.num_bits();
+    let sum = |key: u128| cube_sum(&|msgs: &[u128]| cipher.encrypt_batch(key, msgs), cube, 0);
+    let at_zero = sum(0);
+    let mut linear = u128::MAX >> (128 - num_bits);
+    for _ in 0..num_tests {
+        let (x, y) = (random_bits(num_bits), random_bits(num_bits));
+        linear &= !(sum(x) ^ sum(y) ^ sum(x ^ y) ^ at_zero);
+        if linear == 0 {
+            return vec![];
+        }
+    }
+
+    let coefficients: Vec<u128> = (0..num_bits).map(|bit| sum(1 << bit) ^ at_zero).collect();
+    (0..num_bits)
+        .filter(|&output_bit| linear >> output_bit & 1 == 1)
+        .map(|output_bit| Superpoly {
+            cube: cube.to_vec(),
+            output_bit,
+            key_bits: coefficients
+                .iter()
+                .enumerate()
+                .fold(0, |key_bits, (bit, &coefficient)| {
+                    key_bits | (coefficient >> output_bit & 1) << bit
+                }),
+            constant: at_zero >> output_bit & 1 == 1,
+        })
+        .filter(|superpoly| superpoly.key_bits != 0)
+        .collect()
+}
+
+/// The cubes of all bits of one cell but two, for every cell and pair of
+/// bits left out. On one round the S-box is the only non-linear part and,
+/// being a permutation, has no monomial of full degree, so the superpoly of
+/// such a cube is quadratic in the two bits left out plus their key bits.
+/// Some output bits lack the quadratic term, which leaves a linear one.
+pub fn cell_cubes<C: CubeCipher>(cipher: &C) -> Vec<Vec<usize>> {
+    let cell_bits = cipher.cell_bits();
+    let mut cubes = vec![];
+    for cell in 0..cipher.num_bits() / cell_bits {
+        let bits = cell_bits * cell..cell_bits * (cell + 1);
+        for first in bits.clone() {
+            for second in first + 1..bits.end {
+                cubes.push(
+                    bits.clone()
+                        .filter(|&bit| bit != first && bit != second)
+                        .collect(),
+                );
+            }
+        }
+    }
+    cubes
+}
+
+/// Cubes for two rounds of a 4x4 cipher with an S-box of degree
+/// `cell_bits - 1`, which only the small-scale variant keeps low enough.
+///
+/// An output cell of two rounds is the S-box of a column after the first
+/// round, whose cells are linear in the S-boxes of a diagonal of the
+/// plaintext, so it has a degree of at most `d^2` for S-boxes of degree `d`
+/// in the bits of that diagonal and the key bits added to them, and less in
+/// the round key added in between. A cube of `d^2 - 1` bits of the diagonal
+/// thus has a superpoly linear in the key bits added to the diagonal. These
+/// cubes take all bits but one, the same in both, of two other cells and
+/// two adjacent bits of a cell, whose key bits the superpolies give.
+pub fn two_round_cubes<C: CubeCipher>(cipher: &C) -> Vec<Vec<usize>> {
+    let cell_bits = cipher.cell_bits();
+    let mut cubes = vec![];
+    for cell in 0..cipher.num_bits() / cell_bits {
+        let (col, row) = (cell / 4, cell % 4);
+        let others: Vec<usize> = (0..4)
+            .filter(|&r| r != row)
+            .map(|r| 4 * ((col + 4 + r - row) % 4) + r)
+            .collect();
+        for left_out in 0..cell_bits {
+            let mut cube: Vec<usize> = others[..2]
+                .iter()
+                .flat_map(|&other| {
+                    (0..cell_bits)
+                        .filter(move |&bit| bit != left_out)
+                        .map(move |bit| cell_bits * other + bit)
+                })
+                .collect();
+            cube.push(cell_bits * cell + left_out);
+            cube.push(cell_bits * cell + (left_out + 1) % cell_bits);
+            cubes.push(cube);
+        }
+    }
+    cubes
+}
+
+/// The linear superpolies of all `cubes` whose key bits are independent of
+/// those before, until they determine every key bit.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn find_superpolies<C: CubeCipher>(
+    cipher: &C,
+    cubes: &[Vec<usize>],
+    num_tests: usize,
+) -> Vec<Superpoly> {
+    let mut basis = vec![];
+    let mut superpolies = vec![];
+    for cube in cubes {
+        if basis.len() == cipher.num_bits() {
+            break;
+        }
+        for superpoly in linear_superpolies(cipher, cube, num_tests) {
+            if let Some(row) = reduce(&basis, superpoly.key_bits) {
+                basis.push(row);
+                superpolies.push(superpoly);
+            }
+        }
+    }
+    superpolies
+}
+
+/// `row` reduced by the rows of an echelon basis, or `None` if it is in
+/// their span. The basis stays in echelon form with the result pushed.
+fn reduce(basis: &[u128], row: u128) -> Option<u128> {
+    let row = basis.iter().fold(row, |row, &basis_row| {
+        let pivot = 127 - basis_row.leading_zeros();
+        if row >> pivot & 1 == 1 {
+            row ^ basis_row
+        } else {
+            row
+        }
+    });
+    (row != 0).then_some(row)
+}
+
+/// The online phase: evaluates the superpolies with the cube sums of the
+/// encryptions under the unknown key and solves the linear system. Returns
+/// the key bits determined by it as a mask and their values.
+pub fn recover_key_bits<F: Fn(&[u128]) -> Vec<u128>>(
+    superpolies: &[Superpoly],
+    encrypt_batch: &F,
+) -> (u128, u128) {
+    // Rows of the system: the key bits and the value they sum to.
+    let mut rows: Vec<(u128, bool)> = vec![];
+    let mut sums: Vec<(&[usize], u128)> = vec![];
+    for superpoly in superpolies {
+        let sum = match sums.iter().find(|(cube, _)| *cube == superpoly.cube) {
+            Some(&(_, sum)) => sum,
+            None => {
+                let sum = cube_sum(encrypt_batch, &superpoly.cube, 0);
+                sums.push((&superpoly.cube, sum));
+                sum
+            }
+        };
+        let value = (sum >> superpoly.output_bit & 1 == 1) ^ superpoly.constant;
+        rows.push((superpoly.key_bits, value));
+    }
+
+    // Gauss-Jordan elimination.
+    let mut pivots = vec![];
+    for i in 0..rows.len() {
+        let (row, value) = rows[i];
+        if row == 0 {
+            continue;
+        }
+        let pivot = row.trailing_zeros();
+        for (j, other) in rows.iter_mut().enumerate() {
+            if j != i && other.0 >> pivot & 1 == 1 {
+                other.0 ^= row;
+                other.1 ^= value;
+            }
+        }
+        pivots.push(i);
+    }
+    pivots
+        .into_iter()
+        .map(|i| rows[i])
+        .filter(|(row, _)| row.count_ones() == 1)
+        .fold((0, 0), |(mask, key), (row, value)| {
+            (mask | row, key | if value { row } else { 0 })
+        })
+}
+
+/// The first `dim` bits of the diagonals, all bits of each cell but the
+/// last: a whole cell would only run through the S-box and sum to zero.
+fn degree_cube<C: CubeCipher>(cipher: &C, dim: usize) -> Vec<usize> {
+    let cell_bits = cipher.cell_bits();
+    (0..cipher.num_bits() / cell_bits)
+        .map(|i| {
+            let (diagonal, row) = (i / 4, i % 4);
+            4 * ((diagonal + row) % 4) + row
+        })
+        .flat_map(|cell| (0..cell_bits - 1).map(move |bit| cell_bits * cell + bit))
+        .take(dim)
+        .collect()
+}
+
+/// `dim` random bits out of `num_bits`.
+fn random_cube(num_bits: usize, dim: usize) -> Vec<usize> {
+    let mut bits: Vec<usize> = (0..num_bits).collect();
+    let mut rng = thread_rng();
+    for i in 0..dim {
+        let j = rng.gen_range(i..num_bits);
+        bits.swap(i, j);
+    }
+    bits.truncate(dim);
+    bits
+}
+
+/// A lower bound on the algebraic degree of the cipher in the plaintext
+/// bits, up to `max_dim`: the largest dimension of a cube whose sum is
+/// non-zero for one of `num_tests` random keys and values of the other
+/// bits. A polynomial of degree below the dimension of a cube always sums
+/// to zero over it. Every other test takes a cube over the diagonals, which
+/// finds the degree of the first rounds whose outputs depend on only one
+/// diagonal, and the others random cubes, which avoid the integral
+/// properties that make sums over diagonals vanish in later rounds.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn estimate_degree<C: CubeCipher>(
+    cipher: &C,
+    max_dim: usize,
+    num_tests: usize,
+) -> usize {
+    let num_bits = cipher.num_bits();
+    (1..=max_dim)
+        .filter(|&dim| {
+            (0..num_tests).any(|test| {
+                let cube = if test % 2 == 0 {
+                    degree_cube(cipher, dim)
+                } else {
+                    random_cube(num_bits, dim)
+                };
+                let key = random_bits(num_bits);
+                let sum = cube_sum(
+                    &|msgs: &[u128]| cipher.encrypt_batch(key, msgs),
+                    &cube,
+                    random_bits(num_bits),
+                );
+                sum != 0
+            })
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cube_sum_of_full_cell_is_zero() {
+        unsafe {
+            // One round maps a cell through the S-box, a permutation.
+            let cipher = ReducedAES { num_rounds: 1 };
+            let key = random_bits(128);
+            let encrypt = |msgs: &[u128]| cipher.encrypt_batch(key, msgs);
+            let cube: Vec<usize> = (8..16).collect();
+            assert_eq!(cube_sum(&encrypt, &cube, random_bits(128)), 0);
+        }
+    }
+
+    #[test]
+    fn test_recover_key_one_round() {
+        unsafe {
+            let cipher = ReducedAES { num_rounds: 1 };
+            let superpolies = find_superpolies(&cipher, &cell_cubes(&cipher), 8);
+            assert_eq!(superpolies.len(), 128);
+
+            let key = random_bits(128);
+            let aes = AES128::new(key.to_le_bytes(), 1);
+            let (mask, recovered) =
+                recover_key_bits(&superpolies, &|msgs: &[u128]| encrypt_batch(&aes, msgs));
+            assert_eq!(mask, u128::MAX);
+            assert_eq!(recovered, key);
+        }
+    }
+
+    #[test]
+    fn test_recover_key_two_rounds_small() {
+        unsafe {
+            let cipher = ReducedSmallAES { num_rounds: 2 };
+            let superpolies = find_superpolies(&cipher, &two_round_cubes(&cipher), 8);
+            assert_eq!(superpolies.len(), 64);
+
+            let key = random_bits(64);
+            let (mask, recovered) = recover_key_bits(&superpolies, &|msgs: &[u128]| {
+                cipher.encrypt_batch(key, msgs)
+            });
+            assert_eq!(mask, u64::MAX as u128);
+            assert_eq!(recovered, key);
+        }
+    }
+
+    #[test]
+    fn test_estimate_degree() {
+        unsafe {
+            assert_eq!(estimate_degree(&ReducedAES { num_rounds: 1 }, 10, 4), 7);
+            assert_eq!(estimate_degree(&ReducedSmallAES { num_rounds: 1 }, 6, 4), 3);
+            // At most 3^2, and cubes of 9 bits may be too few to see it.
+            let degree = estimate_degree(&ReducedSmallAES { num_rounds: 2 }, 12, 4);
+            assert!((8..=9).contains(&degree), "{degree}");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use rand::{thread_rng, Rng};
+
+use crate::aes::{Block, AES128, BLOCK_SIZE};
+use crate::attack::{gen_random_block, EncryptionService};
+use crate::rijndael::{gf256_mul, inv_sbox, MIX_COLUMNS};
+
+/// A fault flipping the bits of `mask` in byte `pos`, for
+/// `AES128::encrypt_with_fault`.
+pub fn flip_byte(pos: usize, mask: u8) -> impl FnOnce(&mut Block) {
+    move |block| block[pos] ^= mask
+}
+
+/// A fault setting byte `pos` to a random value, the usual model of a glitch
+/// whose effect the attacker does not control.
+pub fn randomize_byte(pos: usize) -> impl FnOnce(&mut Block) {
+    move |block| block[pos] = thread_rng().gen()
+}
+
+/// The ciphertext bytes column `col` before the last round ends up in, by row.
+fn shifted_positions(col: usize) -> [usize; 4] {
+    std::array::from_fn(|row| 4 * ((col + 4 - row) % 4) + row)
+}
+
+/// The guesses of the last round key bytes at `positions`, by row, under
+/// which `enc` and `faulty` differ by a single byte fault in their column
+/// before the last MixColumns.
+///
+/// There are 4 * 255 such differences of the column out of 2^32, and each
+/// byte of the key matches a given difference about once, so about a
+/// thousand guesses are left.
+fn column_candidates(
+    inv_sbox: &[u8; 256],
+    positions: [usize; 4],
+    enc: Block,
+    faulty: Block,
+) -> HashSet<[u8; 4]> {
+    let keys_by_difference = positions.map(|pos| {
+        let mut keys = vec![vec![]; 256];
+        for key in 0..=u8::MAX {
+            let difference =
+                inv_sbox[(enc[pos] ^ key) as usize] ^ inv_sbox[(faulty[pos] ^ key) as usize];
+            keys[difference as usize].push(key);
+        }
+        keys
+    });
+
+    let mut candidates = HashSet::new();
+    for fault_row in 0..4 {
+        for fault in 1..=u8::MAX {
+            // A fault `fault` in row `fault_row` becomes `fault` times that
+            // column of the MixColumns matrix.
+            let keys: [&Vec<u8>; 4] = std::array::from_fn(|row| {
+                let coefficient = MIX_COLUMNS[(4 + fault_row - row) % 4];
+                &keys_by_difference[row][gf256_mul(coefficient, fault) as usize]
+            });
+            for &k0 in keys[0] {
+                for &k1 in keys[1] {
+                    for &k2 in keys[2] {
+                        candidates.extend(keys[3].iter().map(|&k3| [k0, k1, k2, k3]));
+                    }
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Piret-Quisquater differential fault analysis: recovers the key of
+/// `num_rounds`-round AES from pairs of correct and faulty encryptions of the
+/// same plaintext.
+///
+/// A fault in one byte before the last MixColumns leaves one column of the
+/// state before the last SubBytes differing by a multiple of a column of the
+/// MixColumns matrix, which four bytes of the last round key have to undo
+/// from the ciphertexts. A fault one round earlier spreads to every column,
+/// so each pair then narrows down the whole last round key: two pairs
+/// usually settle it, against about eight for faults in the last
+/// MixColumns, which must hit every column twice. Pairs whose fault left a
+/// column untouched say nothing about it; a pair breaking the fault model
+/// and ruling out every guess starts that column over.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_key_dfa<E: EncryptionService, F: EncryptionService>(
+    encryption_service: &E,
+    faulty_service: &F,
+    num_rounds: usize,
+) -> Block {
+    let inv_sbox = inv_sbox();
+    let mut candidates: Vec<Option<HashSet<[u8; 4]>>> = vec![None; 4];
+    while candidates
+        .iter()
+        .any(|column| column.as_ref().is_none_or(|column| column.len() > 1))
+    {
+        let msg = gen_random_block();
+        let (enc, faulty) = (encryption_service.encrypt(msg), faulty_service.encrypt(msg));
+        for (col, column) in candidates.iter_mut().enumerate() {
+            let positions = shifted_positions(col);
+            if positions.iter().any(|&pos| enc[pos] == faulty[pos]) {
+                continue;
+            }
+            let new_candidates = column_candidates(&inv_sbox, positions, enc, faulty);
+            *column = match column.take() {
+                Some(old_candidates) => Some(&old_candidates & &new_candidates),
+                None => Some(new_candidates),
+            }
+            .filter(|column| !column.is_empty());
+        }
+    }
+
+    let mut last_round_key = [0; BLOCK_SIZE];
+    for (col, column) in candidates.iter().enumerate() {
+        let key = column.as_ref().unwrap().iter().next().unwrap();
+        for (&pos, &byte) in shifted_positions(col).iter().zip(key) {
+            last_round_key[pos] = byte;
+        }
+    }
+    AES128::invert_key_expansion(AES128::block_to_state(last_round_key), num_rounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_right_key_is_candidate() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let last_round_key =
+                AES128::state_to_block(AES128::key_expansion(AES128::block_to_state(key))[10]);
+            let inv_sbox = inv_sbox();
+            let msg = gen_random_block();
+            let (enc, faulty) = (
+                aes.encrypt(msg),
+                aes.encrypt_with_fault(msg, 9, flip_byte(6, 0x5a)),
+            );
+            // Byte 6 is in column 1 before the last MixColumns.
+            let positions = shifted_positions(1);
+            let candidates = column_candidates(&inv_sbox, positions, enc, faulty);
+            assert!(candidates.contains(&positions.map(|pos| last_round_key[pos])));
+            assert!(candidates.len() < 2000);
+        }
+    }
+
+    #[test]
+    fn test_crack_key_dfa() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let faulty_service = |msg: Block| {
+                let pos = thread_rng().gen_range(0..BLOCK_SIZE);
+                aes.encrypt_with_fault(msg, 9, randomize_byte(pos))
+            };
+            assert_eq!(crack_key_dfa(&aes, &faulty_service, 10), key);
+        }
+    }
+
+    #[test]
+    fn test_crack_key_dfa_earlier_fault() {
+        unsafe {
+            let key = gen_random_block();
+            let aes = AES128::new(key, 10);
+            let num_faults = std::cell::Cell::new(0);
+            let faulty_service = |msg: Block| {
+                num_faults.set(num_faults.get() + 1);
+                aes.encrypt_with_fault(msg, 8, flip_byte(0, 0x01))
+            };
+            assert_eq!(crack_key_dfa(&aes, &faulty_service, 10), key);
+            assert!(num_faults.get() <= 4);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::aes::{AES128, BLOCK_SIZE};
+
+const NUM_BITS: usize = 8 * BLOCK_SIZE;
+const MAX_ROUNDS: usize = 10;
+
+/// Conventional bit-based division property of AES, with the S-box, ShiftRows
+/// and MixColumns tables all derived from the implementation in `aes.rs`.
+///
+/// A set of plaintexts is described by the bits that take all values, the
+/// rest being constant. Bit `8 * i + j` is bit `j` of byte `i` of the block.
+/// An output bit is balanced after `r` rounds if there is no division trail
+/// from the active bits to the unit vector of that bit. Rounds are full
+/// rounds, i.e. they all include MixColumns.
+pub struct DivisionModel {
+    /// Minimal output division vectors of the S-box for every input vector.
+    sbox_trails: Vec<Vec<u8>>,
+    /// Input byte that ShiftRows moves to each position.
+    shift_rows: [usize; BLOCK_SIZE],
+    /// Output bits of MixColumns which depend on each input bit.
+    mix_columns: [u128; NUM_BITS],
+}
+
+fn weights(k: u128) -> [u8; BLOCK_SIZE] {
+    std::array::from_fn(|i| ((k >> (8 * i)) as u8).count_ones() as u8)
+}
+
+/// Word-level abstraction of the S-box: a bijective S-box of degree 7 keeps a
+/// full byte full and can reduce anything else to a single bit.
+fn compress(weight: u8) -> u8 {
+    match weight {
+        0 | 8 => weight,
+        _ => 1,
+    }
+}
+
+struct Search<'a> {
+    model: &'a DivisionModel,
+    target: usize,
+    visited: HashSet<(u128, usize)>,
+    /// Word-level results, keyed by the column of the target bit as well so
+    /// they can be shared between target bits.
+    word_memo: HashMap<([u8; BLOCK_SIZE], usize, usize), bool>,
+}
+
+impl DivisionModel {
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn new() -> Self {
+        let mut sbox = [0u8; 256];
+        for chunk in 0..16 {
+            let block: [u8; BLOCK_SIZE] = std::array::from_fn(|i| (16 * chunk + i) as u8);
+            let res = AES128::state_to_block(AES128::sub_bytes(AES128::block_to_state(block)));
+            sbox[16 * chunk..16 * chunk + 16].copy_from_slice(&res);
+        }
+
+        let mut shift_rows = [0; BLOCK_SIZE];
+        let block: [u8; BLOCK_SIZE] = std::array::from_fn(|i| i as u8);
+        let res = AES128::state_to_block(AES128::shift_rows(AES128::block_to_state(block)));
+        for (pos, &src) in res.iter().enumerate() {
+            shift_rows[pos] = src as usize;
+        }
+
+        let mut mix_columns = [0; NUM_BITS];
+        for (bit, mask) in mix_columns.iter_mut().enumerate() {
+            let mut block = [0; BLOCK_SIZE];
+            block[bit / 8] = 1 << (bit % 8);
+            let res = AES128::state_to_block(AES128::mix_columns(AES128::block_to_state(block)));
+            *mask = u128::from_le_bytes(res);
+        }
+
+        Self {
+            sbox_trails: sbox_division_trails(&sbox),
+            shift_rows,
+            mix_columns,
+        }
+    }
+
+    /// Whether bit `bit` sums to zero after `num_rounds` rounds over a set of
+    /// plaintexts where exactly the bits in `active` take all values.
+    pub fn is_balanced(&self, active: u128, num_rounds: usize, bit: usize) -> bool {
+        self.search().is_balanced(active, num_rounds, bit)
+    }
+
+    pub fn balanced_bits(&self, active: u128, num_rounds: usize) -> u128 {
+        let mut search = self.search();
+        (0..NUM_BITS)
+            .filter(|&bit| search.is_balanced(active, num_rounds, bit))
+            .fold(0, |acc, bit| acc | 1 << bit)
+    }
+
+    fn search(&self) -> Search<'_> {
+        Search {
+            model: self,
+            target: 0,
+            visited: HashSet::new(),
+            word_memo: HashMap::new(),
+        }
+    }
+
+    /// The largest number of rounds, up to the 10 of AES, after which some bit
+    /// is still balanced, together with the balanced bits after that many
+    /// rounds, or `(0, 0)` when no bit is balanced after one round.
+    pub fn longest_distinguisher(&self, active: u128) -> (usize, u128) {
+        let mut longest = (0, 0);
+        for num_rounds in 1..=MAX_ROUNDS {
+            let balanced = self.balanced_bits(active, num_rounds);
+            if balanced == 0 {
+                break;
+            }
+            longest = (num_rounds, balanced);
+        }
+        longest
+    }
+
+    fn shift_rows(&self, k: u128) -> u128 {
+        (0..BLOCK_SIZE).fold(0, |acc, pos| {
+            acc | (((k >> (8 * self.shift_rows[pos])) & 0xff) << (8 * pos))
+        })
+    }
+}
+
+impl Search<'_> {
+    fn is_balanced(&mut self, active: u128, num_rounds: usize, bit: usize) -> bool {
+        // A single plaintext has no trail at all, yet sums to itself.
+        if active == 0 {
+            return false;
+        }
+        self.target = bit;
+        self.visited.clear();
+        !self.trail_exists(active, num_rounds)
+    }
+
+    /// Depth-first search for a division trail from `k` at the start of a
+    /// round to the unit vector of the target bit after `rounds_left` rounds.
+    fn trail_exists(&mut self, k: u128, rounds_left: usize) -> bool {
+        if rounds_left == 0 {
+            return k == 1 << self.target;
+        }
+        if !self.word_trail_exists(weights(k).map(compress), rounds_left)
+            || !self.visited.insert((k, rounds_left))
+        {
+            return false;
+        }
+        self.sub_bytes(k, 0, 0, rounds_left)
+    }
+
+    fn sub_bytes(&mut self, k: u128, pos: usize, acc: u128, rounds_left: usize) -> bool {
+        if pos == BLOCK_SIZE {
+            let k = self.model.shift_rows(acc);
+            let column_weights =
+                std::array::from_fn(|col| (k >> (32 * col)) as u32).map(u32::count_ones);
+            return self.choose_patterns(k, column_weights, 0, [0; BLOCK_SIZE], rounds_left);
+        }
+        let model = self.model;
+        let input = (k >> (8 * pos)) as u8;
+        if input == 0 {
+            return self.sub_bytes(k, pos + 1, acc, rounds_left);
+        }
+        model.sbox_trails[input as usize].iter().any(|&output| {
+            self.sub_bytes(k, pos + 1, acc | (output as u128) << (8 * pos), rounds_left)
+        })
+    }
+
+    /// Before enumerating the bit-level MixColumns trails, decides for every
+    /// output byte whether it will be empty, full or in between, and skips
+    /// the combinations the word-level search rules out.
+    fn choose_patterns(
+        &mut self,
+        k: u128,
+        column_weights: [u32; 4],
+        col: usize,
+        pattern: [u8; BLOCK_SIZE],
+        rounds_left: usize,
+    ) -> bool {
+        if col == 4 {
+            let feasible = if rounds_left == 1 {
+                (0..BLOCK_SIZE).all(|pos| (pattern[pos] == 1) == (pos == self.target / 8))
+            } else {
+                self.word_trail_exists(pattern, rounds_left - 1)
+            };
+            return feasible && self.mix_columns(k, &pattern, 0, 0, rounds_left);
+        }
+        column_patterns(column_weights[col] as usize)
+            .into_iter()
+            .any(|column| {
+                let mut pattern = pattern;
+                pattern[4 * col..4 * col + 4].copy_from_slice(&column);
+                self.choose_patterns(k, column_weights, col + 1, pattern, rounds_left)
+            })
+    }
+
+    /// Sends every active input bit to a distinct output bit it feeds into,
+    /// such that the output bytes match `pattern`.
+    fn mix_columns(
+        &mut self,
+        k: u128,
+        pattern: &[u8; BLOCK_SIZE],
+        bit: usize,
+        used: u128,
+        rounds_left: usize,
+    ) -> bool {
+        if bit.is_multiple_of(32) && bit > 0 {
+            let col = bit / 32 - 1;
+            let matches = (4 * col..4 * col + 4).all(|pos| {
+                compress(((used >> (8 * pos)) as u8).count_ones() as u8) == pattern[pos]
+            });
+            if !matches {
+                return false;
+            }
+        }
+        if bit == NUM_BITS {
+            return self.trail_exists(used, rounds_left - 1);
+        }
+        if (k >> bit) & 1 == 0 {
+            return self.mix_columns(k, pattern, bit + 1, used, rounds_left);
+        }
+        let allowed = (0..BLOCK_SIZE)
+            .filter(|&pos| pattern[pos] != 0)
+            .fold(0u128, |acc, pos| acc | 0xff << (8 * pos));
+        let mut targets = self.model.mix_columns[bit] & allowed & !used;
+        while targets != 0 {
+            let target = 1 << targets.trailing_zeros();
+            if self.mix_columns(k, pattern, bit + 1, used | target, rounds_left) {
+                return true;
+            }
+            targets &= !target;
+        }
+        false
+    }
+
+    /// Coarse word-level trail search used to prune the bit-level search. It
+    /// allows every trail the bit-level rules allow, so a negative answer is
+    /// final. MixColumns may distribute the weight of a column freely.
+    fn word_trail_exists(&mut self, weights: [u8; BLOCK_SIZE], rounds_left: usize) -> bool {
+        if let Some(&res) = self
+            .word_memo
+            .get(&(weights, rounds_left, self.target / 32))
+        {
+            return res;
+        }
+
+        let mut column_weights = [0; 4];
+        for pos in 0..BLOCK_SIZE {
+            column_weights[pos / 4] += compress(weights[self.model.shift_rows[pos]]) as usize;
+        }
+
+        let res = if rounds_left == 1 {
+            column_weights.iter().sum::<usize>() == 1 && column_weights[self.target / 32] == 1
+        } else {
+            let options = column_weights.map(column_outputs);
+            let mut res = false;
+            'outer: for a in &options[0] {
+                for b in &options[1] {
+                    for c in &options[2] {
+                        for d in &options[3] {
+                            let mut next = [0; BLOCK_SIZE];
+                            next[..4].copy_from_slice(a);
+                            next[4..8].copy_from_slice(b);
+                            next[8..12].copy_from_slice(c);
+                            next[12..].copy_from_slice(d);
+                            if self.word_trail_exists(next, rounds_left - 1) {
+                                res = true;
+                                break 'outer;
+                            }
+                        }
+                    }
+                }
+            }
+            res
+        };
+
+        self.word_memo
+            .insert((weights, rounds_left, self.target / 32), res);
+        res
+    }
+}
+
+/// Every word-level pattern of a column with `weight` bits spread over it,
+/// each byte being either empty, full or holding 1 to 7 bits.
+fn column_patterns(weight: usize) -> Vec<[u8; 4]> {
+    (0..81usize)
+        .map(|pattern| -> [u8; 4] {
+            std::array::from_fn(|i| [0, 1, 8][pattern / 3usize.pow(i as u32) % 3])
+        })
+        .filter(|column| {
+            let full = column.iter().filter(|&&w| w == 8).count();
+            let partial = column.iter().filter(|&&w| w == 1).count();
+            8 * full + partial <= weight && weight <= 8 * full + 7 * partial
+        })
+        .collect()
+}
+
+/// Minimal word-level outputs of MixColumns on a column of total weight
+/// `weight`, with every byte either empty, full or holding 1 to 7 bits.
+fn column_outputs(weight: usize) -> Vec<[u8; 4]> {
+    let outputs = column_patterns(weight);
+    outputs
+        .iter()
+        .filter(|a| {
+            !outputs
+                .iter()
+                .any(|b| b != *a && b.iter().zip(a.iter()).all(|(x, y)| x <= y))
+        })
+        .copied()
+        .collect()
+}
+
+/// For every input division vector `k` of the S-box, the minimal output
+/// vectors `l` such that the product of the output bits in `l` has a monomial
+/// in its algebraic normal form containing every input bit in `k`.
+fn sbox_division_trails(sbox: &[u8; 256]) -> Vec<Vec<u8>> {
+    let mut valid = vec![[false; 256]; 256];
+    for l in 0..256 {
+        let mut anf: Vec<bool> = sbox.iter().map(|&y| y as usize & l == l).collect();
+        for i in 0..8 {
+            for x in 0..256 {
+                if x & (1 << i) != 0 {
+                    anf[x] ^= anf[x ^ (1 << i)];
+                }
+            }
+        }
+        // Propagate monomials down to all of their subsets.
+        for i in 0..8 {
+            for x in 0..256 {
+                if x & (1 << i) != 0 {
+                    anf[x ^ (1 << i)] |= anf[x];
+                }
+            }
+        }
+        for (row, &has_monomial) in valid.iter_mut().zip(&anf) {
+            row[l] = has_monomial;
+        }
+    }
+
+    valid
+        .iter()
+        .map(|outputs| {
+            let mut minimal: Vec<u8> = (0..=255u8)
+                .filter(|&l| outputs[l as usize])
+                .filter(|&l| !(0..=255u8).any(|m| m != l && m & l == m && outputs[m as usize]))
+                .collect();
+            minimal.sort_by_key(|l| l.count_ones());
+            minimal
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::Step;
+    use crate::integral::distinguisher_length;
+
+    #[test]
+    fn test_sbox_division_trails() {
+        unsafe {
+            let model = DivisionModel::new();
+            assert_eq!(model.sbox_trails[0], vec![0]);
+            // A permutation maps the full input set to the full output set.
+            assert_eq!(model.sbox_trails[0xff], vec![0xff]);
+            for k in 1..0xff {
+                assert!(!model.sbox_trails[k].is_empty());
+                assert!(!model.sbox_trails[k].contains(&0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_three_rounds_empirical() {
+        unsafe {
+            let model = DivisionModel::new();
+            assert_eq!(model.longest_distinguisher(0xff), (3, u128::MAX));
+            assert_eq!(model.longest_distinguisher(0), (0, 0));
+            assert!(!model.is_balanced(0, 1, 0));
+            // The whole codebook stays balanced, up to the cap.
+            assert_eq!(
+                model.longest_distinguisher(u128::MAX),
+                (MAX_ROUNDS, u128::MAX)
+            );
+
+            let aes = AES128::new(*b"sixteen byte key", 4);
+            let mut msg = *b"theblockbreakers";
+            let mut sum = 0;
+            for i in 0..=255 {
+                msg[0] = i;
+                let state = aes.encrypt_traced(msg).state(3, Step::AddRoundKey).unwrap();
+                sum ^= u128::from_le_bytes(state);
+            }
+            assert_eq!(sum, 0);
+        }
+    }
+
+    #[test]
+    fn test_partial_byte_empirical() {
+        unsafe {
+            let model = DivisionModel::new();
+            let aes = AES128::new(*b"sixteen byte key", 2);
+            let active = 0x7f;
+            let balanced = model.balanced_bits(active, 1);
+            assert_ne!(balanced, 0);
+            let mut sum = 0;
+            for i in 0..=0x7f {
+                let mut msg = *b"theblockbreakers";
+                msg[0] = i;
+                let state = aes.encrypt_traced(msg).state(1, Step::AddRoundKey).unwrap();
+                sum ^= u128::from_le_bytes(state);
+            }
+            assert_eq!(sum & balanced, 0);
+        }
+    }
+
+    #[test]
+    fn test_active_diagonal() {
+        unsafe {
+            let model = DivisionModel::new();
+            let diagonal = 0xff | 0xff << 40 | 0xff << 80 | 0xff << 120;
+            // The byte-level propagation only sees 3 rounds.
+            assert_eq!(distinguisher_length(&[0, 5, 10, 15]), 3);
+            assert!(model.is_balanced(diagonal, 4, 0));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::hint::black_box;
+use std::time::Instant;
+
+use rand::{thread_rng, Rng};
+
+use crate::aes::Block;
+use crate::attack::gen_random_block;
+
+/// The t-value above which dudect considers a function to leak.
+pub const THRESHOLD: f64 = 4.5;
+
+/// The share of the slowest measurements dropped for the cropped test, to
+/// get rid of interrupts and context switches.
+const CROP_PERCENTILE: f64 = 0.9;
+
+/// Mean and variance of a class of measurements, accumulated online with
+/// Welford's method.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+    count: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    pub fn push(&mut self, x: f64) {
+        self.count += 1.0;
+        let delta = x - self.mean;
+        self.mean += delta / self.count;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn variance(&self) -> f64 {
+        self.m2 / (self.count - 1.0)
+    }
+}
+
+/// Welch's t statistic between two classes of measurements.
+pub fn welch_t(a: &Moments, b: &Moments) -> f64 {
+    (a.mean() - b.mean()) / (a.variance() / a.count + b.variance() / b.count).sqrt()
+}
+
+/// The outcome of a timing test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingReport {
+    pub num_measurements: usize,
+    /// The t-value over all measurements.
+    pub t: f64,
+    /// The t-value without the slowest tenth of the measurements.
+    pub t_cropped: f64,
+}
+
+impl TimingReport {
+    pub fn max_t(&self) -> f64 {
+        self.t.abs().max(self.t_cropped.abs())
+    }
+
+    pub fn is_leaking(&self) -> bool {
+        self.max_t() > THRESHOLD
+    }
+}
+
+/// A dudect-style timing test of Reparaz, Balasch and Verbauwhede: times
+/// `encrypt` on `num_measurements` inputs, each at random either the fixed
+/// `fixed` or a random block, and compares the two classes with Welch's
+/// t-test. A t-value beyond `THRESHOLD` means the running time depends on
+/// the input; staying below it is evidence, not proof, of constant time.
+/// With fewer than two measurements in a class the t-values are NaN, which
+/// does not count as leaking.
+///
+/// Run alone, `TTableAES` usually stays below the threshold as well, since
+/// its 5 KB of tables stay in the L1 cache; it leaks once another process
+/// evicts them, which is what the attacks of `cache` model.
+pub fn measure_leakage<F: Fn(Block) -> Block>(
+    encrypt: F,
+    fixed: Block,
+    num_measurements: usize,
+) -> TimingReport {
+    // The inputs are drawn beforehand so that only `encrypt` is timed.
+    let mut rng = thread_rng();
+    let inputs: Vec<(bool, Block)> = (0..num_measurements)
+        .map(|_| {
+            if rng.gen() {
+                (true, fixed)
+            } else {
+                (false, gen_random_block())
+            }
+        })
+        .collect();
+    let measurements: Vec<(bool, f64)> = inputs
+        .into_iter()
+        .map(|(is_fixed, msg)| {
+            let start = Instant::now();
+            black_box(encrypt(black_box(msg)));
+            (is_fixed, start.elapsed().as_nanos() as f64)
+        })
+        .collect();
+
+    let mut times: Vec<f64> = measurements.iter().map(|&(_, time)| time).collect();
+    times.sort_by(f64::total_cmp);
+    let crop = match times.len() {
+        0 => f64::INFINITY,
+        len => times[((len - 1) as f64 * CROP_PERCENTILE) as usize],
+    };
+
+    let t_value = |limit: f64| {
+        let (mut fixed, mut random) = (Moments::default(), Moments::default());
+        for &(is_fixed, time) in measurements.iter().filter(|&&(_, time)| time <= limit) {
+            if is_fixed {
+                fixed.push(time);
+            } else {
+                random.push(time);
+            }
+        }
+        welch_t(&fixed, &random)
+    };
+    TimingReport {
+        num_measurements,
+        t: t_value(f64::INFINITY),
+        t_cropped: t_value(crop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_welch_t() {
+        let (mut a, mut b) = (Moments::default(), Moments::default());
+        for x in [1.0, 2.0, 3.0, 4.0] {
+            a.push(x);
+            b.push(x + 2.0);
+        }
+        assert!((a.mean() - 2.5).abs() < 1e-12);
+        assert!((a.variance() - 5.0 / 3.0).abs() < 1e-12);
+        // (2.5 - 4.5) / sqrt(2 * (5/3) / 4)
+        assert!((welch_t(&a, &b) + 2.0 / (5.0f64 / 6.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_detects_leak() {
+        // The running time grows with the first byte, which is 0 in the fixed
+        // class.
+        let leaky = |msg: Block| {
+            let mut acc = msg;
+            for _ in 0..msg[0] as usize * 20 {
+                acc[1] = black_box(acc[1].wrapping_add(1));
+            }
+            acc
+        };
+        let report = measure_leakage(leaky, [0; 16], 10000);
+        assert!(report.is_leaking(), "{report:?}");
+    }
+
+    #[test]
+    fn test_no_measurements() {
+        let report = measure_leakage(|msg| msg, [0; 16], 0);
+        assert!(report.t.is_nan() && report.t_cropped.is_nan());
+        assert!(!report.is_leaking());
+    }
+}
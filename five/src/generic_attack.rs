@@ -0,0 +1,403 @@
+use rand::{thread_rng, Rng};
+
+/// How many structures beyond the first ones `crack_column` encrypts to settle
+/// the guess of a column.
+const MAX_REFINEMENTS: usize = 8;
+
+/// An AES-like cipher as seen by the Square attack: a state of
+/// `num_rows() * num_cols()` cells of `cell_bits()` bits each, stored column
+/// by column, together with the inverse round functions needed to peel off the
+/// last rounds. None of these depend on the secret key.
+pub trait CellCipher {
+    fn num_rows(&self) -> usize;
+    fn num_cols(&self) -> usize;
+    fn cell_bits(&self) -> u32;
+    fn inv_sbox(&self, cell: u8) -> u8;
+    fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8>;
+    fn mix_columns(&self, state: &[u8]) -> Vec<u8>;
+    fn inv_mix_columns(&self, state: &[u8]) -> Vec<u8>;
+    fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8>;
+
+    /// Whether the last round ends in MixColumns, unlike the last round of
+    /// AES.
+    fn final_mix_columns(&self) -> bool {
+        false
+    }
+
+    fn num_cells(&self) -> usize {
+        self.num_rows() * self.num_cols()
+    }
+}
+
+/// The cells which ShiftRows moves into the first column.
+pub fn diagonal<C: CellCipher>(cipher: &C) -> Vec<usize> {
+    let mut marked = vec![0; cipher.num_cells()];
+    marked[..cipher.num_rows()].fill(1);
+    let marked = cipher.inv_shift_rows(&marked);
+    (0..cipher.num_cells())
+        .filter(|&i| marked[i] == 1)
+        .collect()
+}
+
+/// Moves every cell of `active` `shift` columns to the right.
+fn shift_columns<C: CellCipher>(cipher: &C, active: &[usize], shift: usize) -> Vec<usize> {
+    let num_cells = cipher.num_cells();
+    active
+        .iter()
+        .map(|&pos| (pos + cipher.num_rows() * shift) % num_cells)
+        .collect()
+}
+
+/// Encrypts a structure of plaintexts in which the cells in `active` take
+/// every combination of values and all other cells are random constants.
+pub(crate) fn setup<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    active: &[usize],
+) -> Vec<Vec<u8>> {
+    let cell_mask = (1u16 << cipher.cell_bits()) - 1;
+    let base: Vec<u8> = (0..cipher.num_cells())
+        .map(|_| (thread_rng().gen::<u16>() & cell_mask) as u8)
+        .collect();
+    setup_with_base(cipher, encryption_service, active, &base)
+}
+
+/// Same as `setup`, with the constant cells taken from `base`.
+pub(crate) fn setup_with_base<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    active: &[usize],
+    base: &[u8],
+) -> Vec<Vec<u8>> {
+    let cell_mask = (1u16 << cipher.cell_bits()) - 1;
+    let bits = cipher.cell_bits() as usize;
+    (0..1usize << (bits * active.len()))
+        .map(|i| {
+            let mut msg = base.to_vec();
+            for (j, &pos) in active.iter().enumerate() {
+                msg[pos] = ((i >> (bits * j)) & cell_mask as usize) as u8;
+            }
+            encryption_service(&msg)
+        })
+        .collect()
+}
+
+/// Adds `round_key` to `state` cell by cell.
+pub(crate) fn add_round_key(state: &[u8], round_key: &[u8]) -> Vec<u8> {
+    state
+        .iter()
+        .zip(round_key)
+        .map(|(cell, key_cell)| cell ^ key_cell)
+        .collect()
+}
+
+/// Undoes the last round under `equivalent_key`, the last round key after
+/// InvMixColumns when the cipher has a final MixColumns, and the MixColumns of
+/// the round before for every text of `structure`. Returns the cells which
+/// ShiftRows fills from column `col`, ordered by row: adding the equivalent
+/// key of that round, added after InvMixColumns, and undoing SubBytes gives
+/// column `col` of the state after the round before.
+pub(crate) fn peel_last_round<C: CellCipher>(
+    cipher: &C,
+    structure: &[Vec<u8>],
+    equivalent_key: &[u8],
+    col: usize,
+) -> Vec<Vec<u8>> {
+    let identity: Vec<u8> = (0..cipher.num_cells() as u8).collect();
+    let sources: Vec<usize> = cipher
+        .inv_shift_rows(&identity)
+        .into_iter()
+        .map(usize::from)
+        .collect();
+    let rows = cipher.num_rows();
+    // InvMixColumns of a column with one nonzero cell, by row and value, so
+    // that only the cells asked for are computed.
+    let inv_mix: Vec<Vec<Vec<u8>>> = (0..rows)
+        .map(|row| {
+            (0..1 << cipher.cell_bits())
+                .map(|value| {
+                    let mut state = vec![0; cipher.num_cells()];
+                    state[row] = value as u8;
+                    cipher.inv_mix_columns(&state)[..rows].to_vec()
+                })
+                .collect()
+        })
+        .collect();
+    structure
+        .iter()
+        .map(|enc| {
+            (0..rows)
+                .map(|row| {
+                    let pos = sources[rows * col + row];
+                    (0..rows).fold(0, |acc, from| {
+                        let source = sources[rows * (pos / rows) + from];
+                        let cell = cipher.inv_sbox(enc[source] ^ equivalent_key[source]);
+                        acc ^ inv_mix[from][cell as usize][pos % rows]
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Every way of filling in the cells of `planted` which are `None`, the
+/// given ones staying as they are.
+pub(crate) fn completions<'a, C: CellCipher>(
+    cipher: &C,
+    planted: &'a [Option<u8>],
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    let bits = cipher.cell_bits() as usize;
+    let unknown: Vec<usize> = (0..planted.len())
+        .filter(|&pos| planted[pos].is_none())
+        .collect();
+    (0..1usize << (bits * unknown.len())).map(move |guess| {
+        let mut cells: Vec<u8> = planted.iter().map(|cell| cell.unwrap_or(0)).collect();
+        for (i, &pos) in unknown.iter().enumerate() {
+            cells[pos] = ((guess >> (bits * i)) % (1 << bits)) as u8;
+        }
+        cells
+    })
+}
+
+/// Recovers the last round key, given a structure `active` that is balanced
+/// `peeled_rounds` rounds before the ciphertext. One round is peeled off by
+/// guessing single cells of the last round key, two by guessing a column of
+/// it together with a cell of the equivalent key of the round before.
+pub fn crack_last_round_key<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    active: &[usize],
+    peeled_rounds: usize,
+) -> Vec<u8> {
+    if !cipher.final_mix_columns() {
+        return crack_unmixed_last_round_key(cipher, encryption_service, active, peeled_rounds);
+    }
+    // Undoing the final MixColumns leaves a last round without it, keyed by
+    // InvMixColumns of the real round key.
+    let unmixed_service = |msg: &[u8]| cipher.inv_mix_columns(&encryption_service(msg));
+    let equivalent_key =
+        crack_unmixed_last_round_key(cipher, &unmixed_service, active, peeled_rounds);
+    cipher.mix_columns(&equivalent_key)
+}
+
+fn crack_unmixed_last_round_key<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    active: &[usize],
+    peeled_rounds: usize,
+) -> Vec<u8> {
+    let num_values = 1u32 << cipher.cell_bits();
+    let mut last_round_key = vec![0; cipher.num_cells()];
+
+    if peeled_rounds == 1 {
+        for (pos, recovered_cell) in last_round_key.iter_mut().enumerate() {
+            let mut candidates: Vec<u8> = (0..num_values).map(|g| g as u8).collect();
+            let mut shift = 0;
+            while candidates.len() > 1 {
+                let structure = shift_columns(cipher, active, shift);
+                let enc_structure = setup(cipher, encryption_service, &structure);
+                candidates.retain(|&guess| {
+                    enc_structure
+                        .iter()
+                        .fold(0, |acc, enc| acc ^ cipher.inv_sbox(enc[pos] ^ guess))
+                        == 0
+                });
+                // A cell which takes every value over the structure is
+                // balanced under every guess. When the block is wider than
+                // AES that happens to some cells, and a structure in another
+                // column is needed for them.
+                if candidates.len() == num_values as usize {
+                    shift += 1;
+                    assert!(
+                        shift < cipher.num_cols(),
+                        "no structure determines cell {pos}"
+                    );
+                }
+            }
+            *recovered_cell = candidates[0];
+        }
+        return last_round_key;
+    }
+
+    assert_eq!(peeled_rounds, 2, "only 1 or 2 rounds can be peeled off");
+    let rows = cipher.num_rows();
+    let bits = cipher.cell_bits() as usize;
+    let identity: Vec<u8> = (0..cipher.num_cells() as u8).collect();
+    let sources = cipher.inv_shift_rows(&identity);
+    for col in 0..cipher.num_cols() {
+        // As with one round, a structure in another column is tried when
+        // this one leaves the column undetermined.
+        let column_guess = (0..cipher.num_cols())
+            .find_map(|shift| {
+                let structure = shift_columns(cipher, active, shift);
+                crack_column(
+                    cipher,
+                    encryption_service,
+                    &structure,
+                    col,
+                    0..1usize << (bits * rows),
+                )
+            })
+            .unwrap_or_else(|| panic!("no structure determines column {col}"));
+        for row in 0..rows {
+            let pos = sources[rows * col + row] as usize;
+            last_round_key[pos] = ((column_guess >> (bits * row)) as u32 % num_values) as u8;
+        }
+    }
+
+    last_round_key
+}
+
+/// Recovers the cells of the last round key which ShiftRows moves into
+/// column `col`, packed `cell_bits()` bits per row, by peeling off 2 rounds:
+/// each of `column_guesses` is tried together with every cell of the
+/// equivalent key of the round before. Every guess is checked against
+/// structures encrypted up front as it comes, so only the survivors are
+/// kept, but with 8-bit cells the 2^40 guesses take some 2^48 S-box lookups.
+/// Returns `None` when `MAX_REFINEMENTS` further structures still leave
+/// several guesses of the column, as happens when one of its cells is
+/// constant over the structure.
+pub(crate) fn crack_column<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    active: &[usize],
+    col: usize,
+    column_guesses: impl Iterator<Item = usize>,
+) -> Option<usize> {
+    let num_values = 1u32 << cipher.cell_bits();
+    let rows = cipher.num_rows();
+    let bits = cipher.cell_bits() as usize;
+    let identity: Vec<u8> = (0..cipher.num_cells() as u8).collect();
+    let sources = cipher.inv_shift_rows(&identity);
+    let positions: Vec<usize> = (0..rows)
+        .map(|row| sources[rows * col + row] as usize)
+        .collect();
+    // The first cell of the column after InvMixColumns is linear in the
+    // cells of the column, so it is tabulated once per cell.
+    let inv_mix_table: Vec<Vec<u8>> = (0..rows)
+        .map(|row| {
+            (0..num_values)
+                .map(|value| {
+                    let mut state = vec![0; cipher.num_cells()];
+                    state[rows * col + row] = value as u8;
+                    cipher.inv_mix_columns(&state)[rows * col]
+                })
+                .collect()
+        })
+        .collect();
+    // The inputs of the S-box of the round before, but for the key cell.
+    let partial_decryptions = |structure: &[Vec<u8>], column_guess: usize| -> Vec<u8> {
+        structure
+            .iter()
+            .map(|enc| {
+                (0..rows).fold(0, |acc, row| {
+                    let key_cell = ((column_guess >> (bits * row)) as u32 % num_values) as u8;
+                    acc ^ inv_mix_table[row]
+                        [cipher.inv_sbox(enc[positions[row]] ^ key_cell) as usize]
+                })
+            })
+            .collect()
+    };
+    let is_balanced = |mixed: &[u8], guess: u8| {
+        mixed
+            .iter()
+            .fold(0, |acc, &x| acc ^ cipher.inv_sbox(x ^ guess))
+            == 0
+    };
+
+    // Each structure keeps a wrong guess with probability 2^-cell_bits, so
+    // with two more than the guessed cells hardly any wrong one survives.
+    let structures: Vec<Vec<Vec<u8>>> = (0..rows + 2)
+        .map(|_| setup(cipher, encryption_service, active))
+        .collect();
+    let mut candidates: Vec<(usize, u8)> = vec![];
+    for column_guess in column_guesses {
+        let mut guesses: Vec<u8> = (0..num_values).map(|g| g as u8).collect();
+        for structure in &structures {
+            let mixed = partial_decryptions(structure, column_guess);
+            guesses.retain(|&guess| is_balanced(&mixed, guess));
+            if guesses.is_empty() {
+                break;
+            }
+        }
+        candidates.extend(guesses.into_iter().map(|guess| (column_guess, guess)));
+    }
+    // The guess of the earlier round key is not needed, and is not
+    // determined when the balanced cell takes every value.
+    for _ in 0..MAX_REFINEMENTS {
+        let &(first, _) = candidates.first()?;
+        if candidates
+            .iter()
+            .all(|&(column_guess, _)| column_guess == first)
+        {
+            return Some(first);
+        }
+        let structure = setup(cipher, encryption_service, active);
+        candidates.retain(|&(column_guess, guess)| {
+            is_balanced(&partial_decryptions(&structure, column_guess), guess)
+        });
+    }
+    None
+}
+
+/// Square attack on 4 to 6 rounds: 4 and 5 rounds use a single active cell
+/// balanced after 3 rounds, 6 rounds an active diagonal balanced after 4.
+/// Peeling off 2 rounds guesses 5 cells at a time, which is quick with 4-bit
+/// cells but some 2^48 S-box lookups per column with 8-bit ones, and 6
+/// rounds need structures of 2^32 texts with those.
+pub fn crack_key_generic<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    num_rounds: usize,
+) -> Vec<u8> {
+    let (active, peeled_rounds) = match num_rounds {
+        4 => (vec![0], 1),
+        5 => (vec![0], 2),
+        6 => (diagonal(cipher), 2),
+        _ => panic!("the Square attack covers 4 to 6 rounds, not {num_rounds}"),
+    };
+    let last_round_key = crack_last_round_key(cipher, encryption_service, &active, peeled_rounds);
+    cipher.invert_key_expansion(&last_round_key, num_rounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+    use crate::rijndael::Rijndael;
+
+    #[test]
+    fn test_diagonal() {
+        assert_eq!(diagonal(&Rijndael::new(&[0; 16], 4, 4)), vec![0, 5, 10, 15]);
+    }
+
+    #[test]
+    fn test_crack_key_generic_aes() {
+        let key = *b"sixteen byte key";
+        let aes = unsafe { AES128::new(key, 4) };
+        let encryption_service =
+            |msg: &[u8]| unsafe { aes.encrypt(msg.try_into().unwrap()) }.to_vec();
+        let cipher = Rijndael::new(&key, 4, 4);
+        assert_eq!(crack_key_generic(&cipher, &encryption_service, 4), key);
+    }
+
+    #[test]
+    fn test_crack_column_five_rounds() {
+        // The full 2^40 guesses per column take too long for a test, so only
+        // 2^10 column guesses around the right one are tried.
+        let key = *b"sixteen byte key";
+        let rijndael = Rijndael::new(&key, 4, 5);
+        let encryption_service = |msg: &[u8]| rijndael.encrypt(msg);
+        let last_round_key = &rijndael.key_expansion(&key)[5];
+        let identity: Vec<u8> = (0..16).collect();
+        let sources = rijndael.inv_shift_rows(&identity);
+        let column_guess = (0..4).fold(0, |acc, row| {
+            acc | (last_round_key[sources[row] as usize] as usize) << (8 * row)
+        });
+        let guesses = column_guess & !0x3ff..(column_guess & !0x3ff) + 0x400;
+        assert_eq!(
+            crack_column(&rijndael, &encryption_service, &[0], 0, guesses),
+            Some(column_guess)
+        );
+    }
+}
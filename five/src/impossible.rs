@@ -0,0 +1,455 @@
+use crate::generic_attack::{setup, CellCipher};
+
+/// Where ShiftRows moves every cell, `moved[i]` being the destination of
+/// cell `i`.
+fn shift_rows_destinations<C: CellCipher>(cipher: &C) -> Vec<usize> {
+    let identity: Vec<u8> = (0..cipher.num_cells() as u8).collect();
+    cipher
+        .inv_shift_rows(&identity)
+        .into_iter()
+        .map(|cell| cell as usize)
+        .collect()
+}
+
+/// The cells which ShiftRows moves into column `col`, ordered by row.
+pub fn diagonal<C: CellCipher>(cipher: &C, col: usize) -> Vec<usize> {
+    let moved = shift_rows_destinations(cipher);
+    (0..cipher.num_rows())
+        .map(|row| {
+            let cell = cipher.num_rows() * col + row;
+            moved.iter().position(|&dest| dest == cell).unwrap()
+        })
+        .collect()
+}
+
+/// The cells which ShiftRows fills from column `col`, ordered by row. The
+/// output of a last round without MixColumns shows column `col` of its input
+/// in these cells.
+pub fn shifted_column<C: CellCipher>(cipher: &C, col: usize) -> Vec<usize> {
+    let moved = shift_rows_destinations(cipher);
+    (0..cipher.num_rows())
+        .map(|row| moved[cipher.num_rows() * col + row])
+        .collect()
+}
+
+/// Whether two outputs agree on the cells of some shifted column, which four
+/// rounds never produce from inputs differing in a single cell.
+///
+/// One active cell spreads to a column after one round and to every cell
+/// after two, while a zero column before the last round, which has no
+/// MixColumns, means a zero column after the MixColumns of the round before
+/// and thus zero cells in every column of its input. The two meet in the
+/// middle of the third round.
+pub fn is_impossible_output<C: CellCipher>(cipher: &C, c0: &[u8], c1: &[u8]) -> bool {
+    (0..cipher.num_cols()).any(|col| {
+        shifted_column(cipher, col)
+            .into_iter()
+            .all(|pos| c0[pos] == c1[pos])
+    })
+}
+
+/// All pairs of indices of `outputs` whose outputs agree on some shifted
+/// column, found by sorting on each shifted column in turn.
+fn impossible_pairs<C: CellCipher>(cipher: &C, outputs: &[Vec<u8>]) -> Vec<(usize, usize)> {
+    let mut pairs = vec![];
+    for col in 0..cipher.num_cols() {
+        let positions = shifted_column(cipher, col);
+        let values: Vec<u64> = outputs
+            .iter()
+            .map(|output| {
+                positions
+                    .iter()
+                    .fold(0, |acc, &pos| acc << 8 | output[pos] as u64)
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..outputs.len()).collect();
+        order.sort_unstable_by_key(|&i| values[i]);
+        for run in order.chunk_by(|&i, &j| values[i] == values[j]) {
+            for (a, &i) in run.iter().enumerate() {
+                pairs.extend(run[a + 1..].iter().map(|&j| (i, j)));
+            }
+        }
+    }
+    pairs
+}
+
+/// The solutions of the S-box differential equations and the differences
+/// which MixColumns turns into a single active cell, which together list the
+/// key guesses a pair eliminates.
+struct Eliminator {
+    rows: usize,
+    bits: usize,
+    /// `solutions[a][d]` are the x with S(x) ^ S(x ^ a) == d.
+    solutions: Vec<Vec<Vec<usize>>>,
+    single_cell_preimages: Vec<Vec<u8>>,
+}
+
+impl Eliminator {
+    fn new<C: CellCipher>(cipher: &C) -> Self {
+        let rows = cipher.num_rows();
+        let num_values = 1usize << cipher.cell_bits();
+        let mut sbox = vec![0; num_values];
+        for y in 0..num_values {
+            sbox[cipher.inv_sbox(y as u8) as usize] = y as u8;
+        }
+        let mut solutions = vec![vec![vec![]; num_values]; num_values];
+        for a in 0..num_values {
+            for x in 0..num_values {
+                solutions[a][(sbox[x] ^ sbox[x ^ a]) as usize].push(x);
+            }
+        }
+        let single_cell_preimages = (0..rows)
+            .flat_map(|row| (1..num_values).map(move |value| (row, value)))
+            .map(|(row, value)| {
+                let mut state = vec![0; cipher.num_cells()];
+                state[row] = value as u8;
+                cipher.inv_mix_columns(&state)[..rows].to_vec()
+            })
+            .collect();
+        Self {
+            rows,
+            bits: cipher.cell_bits() as usize,
+            solutions,
+            single_cell_preimages,
+        }
+    }
+
+    /// The number of guesses of the first-round key cells of a diagonal.
+    fn num_guesses(&self) -> usize {
+        1 << (self.bits * self.rows)
+    }
+
+    /// Appends to `eliminated` the guesses of the first-round key cells of a
+    /// diagonal under which the first round turns the plaintexts at structure
+    /// indices `i` and `j` into a pair differing in a single cell. They are
+    /// listed directly from the solutions of the S-box differential
+    /// equations, rather than tried.
+    fn eliminated_by(&self, i: usize, j: usize, eliminated: &mut Vec<usize>) {
+        let cell = |index: usize, row: usize| (index >> (self.bits * row)) % (1 << self.bits);
+        let (mut guesses, mut extended) = (vec![], vec![]);
+        for preimage in &self.single_cell_preimages {
+            // Every combination of solutions is a key guess to eliminate.
+            guesses.clear();
+            guesses.push(0);
+            for (row, &difference) in preimage.iter().enumerate() {
+                let (x, y) = (cell(i, row), cell(j, row));
+                extended.clear();
+                for &guess in &guesses {
+                    extended.extend(
+                        self.solutions[x ^ y][difference as usize]
+                            .iter()
+                            .map(|&z| guess | (z ^ x) << (self.bits * row)),
+                    );
+                }
+                std::mem::swap(&mut guesses, &mut extended);
+            }
+            eliminated.extend_from_slice(&guesses);
+        }
+    }
+
+    /// The guesses of the first-round key cells of diagonal `col` which
+    /// survive `outputs` of a structure over that diagonal, each guess packed
+    /// like the structure index of `setup`.
+    ///
+    /// A guess is wrong if it makes the first round turn some pair of the
+    /// structure into a pair differing in a single cell, while the outputs of
+    /// the pair agree on a shifted column: the remaining four rounds cannot
+    /// do that. The right guess is never eliminated, so the pairs are only
+    /// gone through until it is the last one.
+    fn surviving_keys<C: CellCipher>(&self, cipher: &C, outputs: &[Vec<u8>]) -> Vec<usize> {
+        let mut alive = vec![true; self.num_guesses()];
+        let mut num_alive = alive.len();
+        let mut eliminated = vec![];
+        for (i, j) in impossible_pairs(cipher, outputs) {
+            if num_alive <= 1 {
+                break;
+            }
+            eliminated.clear();
+            self.eliminated_by(i, j, &mut eliminated);
+            for &guess in &eliminated {
+                if alive[guess] {
+                    alive[guess] = false;
+                    num_alive -= 1;
+                }
+            }
+        }
+        (0..alive.len()).filter(|&guess| alive[guess]).collect()
+    }
+}
+
+/// The guesses of the first-round key cells of diagonal `col` which survive
+/// a structure over that diagonal, as in `Eliminator::surviving_keys`.
+fn surviving_diagonal_keys<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+    col: usize,
+) -> Vec<usize> {
+    let outputs = setup(cipher, encryption_service, &diagonal(cipher, col));
+    Eliminator::new(cipher).surviving_keys(cipher, &outputs)
+}
+
+/// Undoes a final MixColumns, which leaves the shifted columns intact.
+pub(crate) fn unmixed_service<'a, C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &'a C,
+    encryption_service: &'a E,
+) -> impl Fn(&[u8]) -> Vec<u8> + 'a {
+    move |msg| {
+        let enc = encryption_service(msg);
+        if cipher.final_mix_columns() {
+            cipher.inv_mix_columns(&enc)
+        } else {
+            enc
+        }
+    }
+}
+
+/// Impossible differential attack on 5 rounds: one round of key guessing in
+/// front of the 4-round impossible differential. Every structure over a
+/// diagonal eliminates the wrong guesses of its first-round key cells, and
+/// the first round key gives the key.
+///
+/// For AES every structure takes 2^32 chosen plaintexts, which is more than
+/// this implementation keeps in memory; the small-scale variants need 2^16.
+pub fn crack_key_impossible<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+) -> Vec<u8> {
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let bits = cipher.cell_bits() as usize;
+    let mut first_round_key = vec![0; cipher.num_cells()];
+    for col in 0..cipher.num_cols() {
+        let guesses = loop {
+            let guesses = surviving_diagonal_keys(cipher, &encryption_service, col);
+            if guesses.len() == 1 {
+                break guesses;
+            }
+        };
+        for (row, pos) in diagonal(cipher, col).into_iter().enumerate() {
+            first_round_key[pos] = ((guesses[0] >> (bits * row)) % (1 << bits)) as u8;
+        }
+    }
+    cipher.invert_key_expansion(&first_round_key, 0)
+}
+
+/// Impossible differential attack on 6 rounds: key guessing on one round at
+/// either end of the 4-round impossible differential. Each guess of the last
+/// round key peels off the last round together with the MixColumns before
+/// it, which leaves the shifted columns of the 5-round attack. The guess is
+/// wrong if no guess of the first-round key cells of a diagonal survives, and
+/// structures are encrypted until one guess is left.
+///
+/// Every shifted column draws on every column of the last round key, so all
+/// of it is guessed: 2^16 guesses on the 2x2 small-scale variant, and out of
+/// reach for anything larger, for which `None` is returned. It is also
+/// returned if every guess is eliminated. Each column of the peeled outputs only depends
+/// on the key cells ShiftRows moves into it, so the columns are tabulated
+/// once per structure for every guess of those cells, and the first-round
+/// guesses each pair of the structure eliminates are only listed once.
+pub fn crack_key_impossible_six_rounds<C: CellCipher, E: Fn(&[u8]) -> Vec<u8>>(
+    cipher: &C,
+    encryption_service: &E,
+) -> Option<Vec<u8>> {
+    let rows = cipher.num_rows();
+    let bits = cipher.cell_bits() as usize;
+    let num_cells = cipher.num_cells();
+    if bits * num_cells > 16 {
+        return None;
+    }
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let eliminator = Eliminator::new(cipher);
+    let identity: Vec<u8> = (0..num_cells as u8).collect();
+    let sources = cipher.inv_shift_rows(&identity);
+    let num_column_guesses = 1 << (bits * rows);
+    let shifted_columns: Vec<Vec<usize>> = (0..cipher.num_cols())
+        .map(|col| shifted_column(cipher, col))
+        .collect();
+    // A guess packs the equivalent key in the order InvShiftRows puts it in,
+    // `bits` bits per cell, i.e. the guesses of every column in turn.
+    let cell = |guess: usize, i: usize| ((guess >> (bits * i)) % (1 << bits)) as u8;
+
+    let mut guesses: Vec<usize> = (0..1 << (bits * num_cells)).collect();
+    while guesses.len() > 1 {
+        let outputs = setup(cipher, &encryption_service, &diagonal(cipher, 0));
+        // peeled[col][column_guess] holds column `col` of every peeled output.
+        let peeled: Vec<Vec<Vec<u8>>> = (0..cipher.num_cols())
+            .map(|col| {
+                (0..num_column_guesses)
+                    .map(|column_guess| {
+                        outputs
+                            .iter()
+                            .flat_map(|enc| {
+                                let mut state = vec![0; num_cells];
+                                for row in 0..rows {
+                                    let pos = sources[rows * col + row] as usize;
+                                    state[rows * col + row] =
+                                        cipher.inv_sbox(enc[pos] ^ cell(column_guess, row));
+                                }
+                                cipher.inv_mix_columns(&state)[rows * col..rows * (col + 1)]
+                                    .to_vec()
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        // The guesses of the first-round key cells each pair eliminates, as a
+        // bit set, which does not depend on the guess of the last round key.
+        let num_outputs = outputs.len();
+        let mut eliminated: Vec<Option<Vec<u64>>> = vec![None; num_outputs * num_outputs];
+        let num_words = eliminator.num_guesses().div_ceil(64);
+        let all_eliminated: Vec<u64> = (0..num_words)
+            .map(|word| {
+                let num_bits = (eliminator.num_guesses() - 64 * word).min(64);
+                u64::MAX >> (64 - num_bits)
+            })
+            .collect();
+        // Outputs with equal values on a shifted column are chained through
+        // `prev`, starting from `last`.
+        const NONE: usize = usize::MAX;
+        let (mut last, mut prev) = (vec![NONE; num_column_guesses], vec![NONE; num_outputs]);
+        let mut pairs = vec![];
+        guesses.retain(|&guess| {
+            let columns: Vec<&[u8]> = peeled
+                .iter()
+                .enumerate()
+                .map(|(col, peeled)| {
+                    peeled[(guess >> (bits * rows * col)) % num_column_guesses].as_slice()
+                })
+                .collect();
+            pairs.clear();
+            for positions in &shifted_columns {
+                last.fill(NONE);
+                for i in 0..num_outputs {
+                    let value = positions.iter().fold(0, |acc, &pos| {
+                        acc << bits | columns[pos / rows][rows * i + pos % rows] as usize
+                    });
+                    let mut j = last[value];
+                    prev[i] = j;
+                    last[value] = i;
+                    while j != NONE {
+                        pairs.push((j, i));
+                        j = prev[j];
+                    }
+                }
+            }
+            let mut dead = vec![0; num_words];
+            for &(i, j) in &pairs {
+                let mask = eliminated[num_outputs * i + j].get_or_insert_with(|| {
+                    let mut guesses = vec![];
+                    eliminator.eliminated_by(i, j, &mut guesses);
+                    let mut mask = vec![0; num_words];
+                    for guess in guesses {
+                        mask[guess / 64] |= 1 << (guess % 64);
+                    }
+                    mask
+                });
+                for (dead, mask) in dead.iter_mut().zip(mask.iter()) {
+                    *dead |= mask;
+                }
+                if dead == all_eliminated {
+                    return false;
+                }
+            }
+            true
+        });
+    }
+
+    let &[guess] = guesses.as_slice() else {
+        return None;
+    };
+    let mut equivalent_key = vec![0; num_cells];
+    for (i, &pos) in sources.iter().enumerate() {
+        equivalent_key[pos as usize] = cell(guess, i);
+    }
+    let last_round_key = if cipher.final_mix_columns() {
+        cipher.mix_columns(&equivalent_key)
+    } else {
+        equivalent_key
+    };
+    Some(cipher.invert_key_expansion(&last_round_key, 6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rijndael::Rijndael;
+    use crate::small_aes::SmallAES;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn test_diagonal_and_shifted_column() {
+        let aes = Rijndael::new(&[0; 16], 4, 4);
+        assert_eq!(diagonal(&aes, 1), vec![4, 9, 14, 3]);
+        assert_eq!(shifted_column(&aes, 1), vec![4, 1, 14, 11]);
+    }
+
+    #[test]
+    fn test_four_round_impossible_differential() {
+        let key: Vec<u8> = (0..16).map(|i| (5 * i + 9) & 0xf).collect();
+        // Structures of the 16 values of one cell give 120 pairs each.
+        let count_impossible = |num_rounds| {
+            let cipher = SmallAES::new(&key, 4, 4, num_rounds);
+            let mut count = 0;
+            for _ in 0..1 << 11 {
+                let mut msg: Vec<u8> = (0..16).map(|_| thread_rng().gen_range(0..16)).collect();
+                let pos = thread_rng().gen_range(0..16);
+                let outputs: Vec<Vec<u8>> = (0..16)
+                    .map(|value| {
+                        msg[pos] = value;
+                        cipher.encrypt(&msg)
+                    })
+                    .collect();
+                for (a, c0) in outputs.iter().enumerate() {
+                    count += outputs[a + 1..]
+                        .iter()
+                        .filter(|c1| is_impossible_output(&cipher, c0, c1))
+                        .count();
+                }
+            }
+            count
+        };
+        assert_eq!(count_impossible(4), 0);
+        assert!(count_impossible(5) > 0);
+    }
+
+    #[test]
+    fn test_surviving_diagonal_keys() {
+        let key: Vec<u8> = (0..16).map(|i| (7 * i + 4) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 5);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        let key_diagonal = diagonal(&cipher, 2)
+            .into_iter()
+            .enumerate()
+            .fold(0, |acc, (row, pos)| acc | (key[pos] as usize) << (4 * row));
+        assert_eq!(
+            surviving_diagonal_keys(&cipher, &encryption_service, 2),
+            vec![key_diagonal]
+        );
+    }
+
+    #[test]
+    fn test_crack_key_impossible() {
+        let key = [0xa, 0x1, 0x7, 0xe];
+        let cipher = SmallAES::new(&key, 2, 2, 5);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        assert_eq!(crack_key_impossible(&cipher, &encryption_service), key);
+    }
+
+    #[test]
+    fn test_crack_key_impossible_six_rounds() {
+        let key = [0x3, 0xc, 0x9, 0x4];
+        let cipher = SmallAES::new(&key, 2, 2, 6);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        assert_eq!(
+            crack_key_impossible_six_rounds(&cipher, &encryption_service),
+            Some(key.to_vec())
+        );
+        let key: Vec<u8> = (0..16).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 6);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        assert_eq!(
+            crack_key_impossible_six_rounds(&cipher, &encryption_service),
+            None
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::aes::BLOCK_SIZE;
+
+/// The integral property of a single byte over a set of chosen plaintexts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Property {
+    Constant,
+    /// Takes every value equally often. The mask records which active
+    /// plaintext bytes the byte depends on, as sums of uniform bytes are only
+    /// known to be uniform when they depend on disjoint inputs.
+    All(u16),
+    /// Sums to zero over the set.
+    Balanced,
+    Unknown,
+}
+
+use Property::{All, Balanced, Constant, Unknown};
+
+pub type IntegralState = [Property; BLOCK_SIZE];
+
+const MAX_ROUNDS: usize = 10;
+
+impl Property {
+    pub fn is_balanced(self) -> bool {
+        matches!(self, All(_) | Balanced)
+    }
+
+    fn add(self, other: Property) -> Property {
+        match (self, other) {
+            (Unknown, _) | (_, Unknown) => Unknown,
+            (Constant, p) | (p, Constant) => p,
+            (All(a), All(b)) if a & b == 0 => All(a | b),
+            _ => Balanced,
+        }
+    }
+}
+
+pub fn active_state(active: &[usize]) -> IntegralState {
+    let mut state = [Constant; BLOCK_SIZE];
+    for &pos in active {
+        state[pos] = All(1 << pos);
+    }
+    state
+}
+
+pub fn sub_bytes(state: IntegralState) -> IntegralState {
+    state.map(|p| match p {
+        Balanced => Unknown,
+        p => p,
+    })
+}
+
+pub fn shift_rows(state: IntegralState) -> IntegralState {
+    let mut res = state;
+    for col in 0..4 {
+        for row in 0..4 {
+            res[4 * col + row] = state[4 * ((col + row) & 3) + row];
+        }
+    }
+    res
+}
+
+/// Every output byte of a column is a combination of all four input bytes
+/// with non-zero coefficients, which do not affect the property.
+pub fn mix_columns(state: IntegralState) -> IntegralState {
+    let mut res = state;
+    for col in 0..4 {
+        let mixed = state[4 * col..4 * col + 4]
+            .iter()
+            .fold(Constant, |acc, &p| acc.add(p));
+        res[4 * col..4 * col + 4].fill(mixed);
+    }
+    res
+}
+
+/// A full round. Adding a constant key preserves every property, so the
+/// key addition is left out.
+pub fn round(state: IntegralState) -> IntegralState {
+    mix_columns(shift_rows(sub_bytes(state)))
+}
+
+/// The state after each of `num_rounds` full rounds, starting from a set of
+/// plaintexts where the bytes at `active` take all values.
+pub fn propagate(active: &[usize], num_rounds: usize) -> Vec<IntegralState> {
+    let mut state = active_state(active);
+    (0..num_rounds)
+        .map(|_| {
+            state = round(state);
+            state
+        })
+        .collect()
+}
+
+pub fn balanced_positions(state: IntegralState) -> Vec<usize> {
+    (0..BLOCK_SIZE)
+        .filter(|&i| state[i].is_balanced())
+        .collect()
+}
+
+/// The balanced byte positions after each round, stopping after the last
+/// round in which any byte is still balanced.
+pub fn balanced_after_rounds(active: &[usize]) -> Vec<Vec<usize>> {
+    propagate(active, MAX_ROUNDS)
+        .into_iter()
+        .map(balanced_positions)
+        .take_while(|positions| !positions.is_empty())
+        .collect()
+}
+
+/// Number of rounds covered by the distinguisher starting from `active`.
+pub fn distinguisher_length(active: &[usize]) -> usize {
+    balanced_after_rounds(active).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::{Step, AES128};
+
+    #[test]
+    fn test_shift_rows() {
+        let state: IntegralState = std::array::from_fn(|i| All(1 << i));
+        let res = shift_rows(state);
+        for (i, expected) in [0, 5, 10, 15, 4, 9, 14, 3].into_iter().enumerate() {
+            assert_eq!(res[i], All(1 << expected));
+        }
+    }
+
+    #[test]
+    fn test_single_active_byte() {
+        let states = propagate(&[0], 4);
+        assert!(states[0][..4].iter().all(|&p| p == All(1)));
+        assert!(states[0][4..].iter().all(|&p| p == Constant));
+        assert!(states[1].iter().all(|&p| p == All(1)));
+        assert!(states[2].iter().all(|&p| p == Balanced));
+        assert!(states[3].iter().all(|&p| p == Unknown));
+        assert_eq!(distinguisher_length(&[0]), 3);
+    }
+
+    #[test]
+    fn test_active_diagonal() {
+        // Disjoint active bytes mixed in one column stay uniform.
+        let states = propagate(&[0, 5, 10, 15], 3);
+        // On AES an active diagonal gives balanced bytes after 4 rounds: the
+        // first round maps it to a column taking all 2^32 values, which three
+        // more rounds keep balanced. The model only tracks bytes one at a time
+        // and loses that the column takes every value, so it finds one round
+        // less.
+        assert!(states[0][..4].iter().all(|&p| p == All(0x8421)));
+        assert_eq!(distinguisher_length(&[0, 5, 10, 15]), 3);
+    }
+
+    #[test]
+    fn test_prediction_matches_aes() {
+        unsafe {
+            let aes = AES128::new(*b"sixteen byte key", 4);
+            let active = [3, 6];
+            let predicted = &balanced_after_rounds(&active)[2];
+            let mut sum = [0; BLOCK_SIZE];
+            for i in 0..1 << 16 {
+                let mut msg = [0x42; BLOCK_SIZE];
+                msg[3] = i as u8;
+                msg[6] = (i >> 8) as u8;
+                let state = aes.encrypt_traced(msg).state(3, Step::AddRoundKey).unwrap();
+                for (acc, b) in sum.iter_mut().zip(state) {
+                    *acc ^= b;
+                }
+            }
+            assert_eq!(predicted.len(), BLOCK_SIZE);
+            for pos in predicted {
+                assert_eq!(sum[*pos], 0);
+            }
+        }
+    }
+}
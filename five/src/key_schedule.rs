@@ -0,0 +1,482 @@
+use std::rc::Rc;
+
+use crate::aes::{Block, BLOCK_SIZE, RCON};
+use crate::rijndael::{gf256_inv, gf256_mul, inv_sbox, sbox, Rijndael, INV_MIX_COLUMNS};
+
+/// A relation between the bytes of a `PartialKeySchedule`, as indices into
+/// its bytes.
+#[derive(Clone, Copy, Debug)]
+enum Relation {
+    /// `a + b + S(c) + constant = 0` with the S-box, else `a + b + c = 0`.
+    Schedule {
+        a: usize,
+        b: usize,
+        c: usize,
+        sbox: bool,
+        constant: u8,
+    },
+    /// A column of a round key and the same column of InvMixColumns of it.
+    /// As MixColumns is MDS, any 4 of those 8 bytes determine the others.
+    Column { key: [usize; 4], mixed: [usize; 4] },
+}
+
+impl Relation {
+    fn bytes(&self) -> Vec<usize> {
+        match *self {
+            Relation::Schedule { a, b, c, .. } => vec![a, b, c],
+            Relation::Column { key, mixed } => [key, mixed].concat(),
+        }
+    }
+
+    /// The most unknown bytes the relation can have and still determine
+    /// them.
+    fn max_unknown(&self) -> usize {
+        match self {
+            Relation::Schedule { .. } => 1,
+            Relation::Column { .. } => 4,
+        }
+    }
+}
+
+/// What stays the same while guessing bytes.
+#[derive(Debug)]
+struct Structure {
+    sbox: [u8; 256],
+    inv_sbox: [u8; 256],
+    relations: Vec<Relation>,
+    /// The relations every byte is in.
+    relations_of: Vec<Vec<usize>>,
+}
+
+/// What is known about the round keys of AES-128 and about InvMixColumns of
+/// them, the round keys of the equivalent cipher which the attacks peeling
+/// off a last round with MixColumns recover. `propagate` fills in whatever
+/// the key schedule determines from that, and `candidates` enumerates the
+/// cipher keys consistent with it.
+#[derive(Clone, Debug)]
+pub struct PartialKeySchedule {
+    num_rounds: usize,
+    structure: Rc<Structure>,
+    /// The bytes of the round keys of rounds 0 to `num_rounds`, then those of
+    /// InvMixColumns of them.
+    bytes: Vec<Option<u8>>,
+    /// The bytes learnt but not propagated yet.
+    pending: Vec<usize>,
+    consistent: bool,
+}
+
+/// Solves the linear equations `row . x = value` over the field of AES for
+/// the 4 unknowns, or returns `None` if they are contradictory. They must
+/// determine the unknowns.
+fn solve(mut equations: Vec<([u8; 4], u8)>) -> Option<[u8; 4]> {
+    for col in 0..4 {
+        let pivot = (col..equations.len()).find(|&i| equations[i].0[col] != 0)?;
+        equations.swap(col, pivot);
+        let inv = gf256_inv(equations[col].0[col]);
+        let (row, value) = equations[col];
+        equations[col] = (row.map(|x| gf256_mul(x, inv)), gf256_mul(value, inv));
+        let (row, value) = equations[col];
+        for (i, equation) in equations.iter_mut().enumerate() {
+            let factor = equation.0[col];
+            if i != col && factor != 0 {
+                for (x, &p) in equation.0.iter_mut().zip(&row) {
+                    *x ^= gf256_mul(factor, p);
+                }
+                equation.1 ^= gf256_mul(factor, value);
+            }
+        }
+    }
+    // The equations left over are now all zero on the left.
+    equations[4..]
+        .iter()
+        .all(|&(_, value)| value == 0)
+        .then(|| std::array::from_fn(|j| equations[j].1))
+}
+
+impl PartialKeySchedule {
+    /// Nothing known yet about the round keys of `num_rounds` rounds.
+    pub fn new(num_rounds: usize) -> Self {
+        let (sbox, inv_sbox) = (sbox(), inv_sbox());
+        let key = |round: usize, pos: usize| BLOCK_SIZE * round + pos;
+        let mixed = |round: usize, pos: usize| BLOCK_SIZE * (num_rounds + 1 + round) + pos;
+        let mut relations = vec![];
+        assert!(num_rounds <= RCON.len());
+        for (round, &rcon) in RCON.iter().enumerate().take(num_rounds) {
+            for pos in 0..BLOCK_SIZE {
+                relations.push(if pos < 4 {
+                    Relation::Schedule {
+                        a: key(round + 1, pos),
+                        b: key(round, pos),
+                        c: key(round, 12 + (pos + 1) % 4),
+                        sbox: true,
+                        constant: if pos == 0 { rcon } else { 0 },
+                    }
+                } else {
+                    Relation::Schedule {
+                        a: key(round + 1, pos),
+                        b: key(round, pos),
+                        c: key(round + 1, pos - 4),
+                        sbox: false,
+                        constant: 0,
+                    }
+                });
+            }
+        }
+        for round in 0..=num_rounds {
+            for col in 0..4 {
+                relations.push(Relation::Column {
+                    key: std::array::from_fn(|row| key(round, 4 * col + row)),
+                    mixed: std::array::from_fn(|row| mixed(round, 4 * col + row)),
+                });
+            }
+        }
+        let num_bytes = 2 * BLOCK_SIZE * (num_rounds + 1);
+        let mut relations_of = vec![vec![]; num_bytes];
+        for (i, relation) in relations.iter().enumerate() {
+            for index in relation.bytes() {
+                relations_of[index].push(i);
+            }
+        }
+        Self {
+            num_rounds,
+            structure: Rc::new(Structure {
+                sbox,
+                inv_sbox,
+                relations,
+                relations_of,
+            }),
+            bytes: vec![None; num_bytes],
+            pending: vec![],
+            consistent: true,
+        }
+    }
+
+    fn learn(&mut self, index: usize, value: u8) -> bool {
+        match self.bytes[index] {
+            Some(known) => {
+                self.consistent &= known == value;
+                false
+            }
+            None => {
+                self.bytes[index] = Some(value);
+                self.pending.push(index);
+                true
+            }
+        }
+    }
+
+    /// Learns byte `pos` of the round key of round `round`.
+    pub fn set_round_key_byte(&mut self, round: usize, pos: usize, value: u8) {
+        self.learn(BLOCK_SIZE * round + pos, value);
+    }
+
+    /// Learns byte `pos` of InvMixColumns of the round key of round `round`.
+    pub fn set_mixed_key_byte(&mut self, round: usize, pos: usize, value: u8) {
+        self.learn(BLOCK_SIZE * (self.num_rounds + 1 + round) + pos, value);
+    }
+
+    pub fn round_key(&self, round: usize) -> [Option<u8>; BLOCK_SIZE] {
+        self.bytes[BLOCK_SIZE * round..][..BLOCK_SIZE]
+            .try_into()
+            .unwrap()
+    }
+
+    pub fn mixed_key(&self, round: usize) -> [Option<u8>; BLOCK_SIZE] {
+        self.bytes[BLOCK_SIZE * (self.num_rounds + 1 + round)..][..BLOCK_SIZE]
+            .try_into()
+            .unwrap()
+    }
+
+    /// Whether no two facts learnt or propagated contradict each other.
+    pub fn is_consistent(&self) -> bool {
+        self.consistent
+    }
+
+    /// The cipher key if all of it is known.
+    pub fn key(&self) -> Option<Block> {
+        self.round_key(0)
+            .into_iter()
+            .collect::<Option<Vec<u8>>>()?
+            .try_into()
+            .ok()
+    }
+
+    /// The bytes of the cipher key still unknown.
+    pub fn num_unknown(&self) -> usize {
+        self.round_key(0).iter().filter(|x| x.is_none()).count()
+    }
+
+    /// Applies a relation, and returns whether it taught anything.
+    fn apply(&mut self, relation: Relation) -> bool {
+        let structure = Rc::clone(&self.structure);
+        match relation {
+            Relation::Schedule {
+                a,
+                b,
+                c,
+                sbox,
+                constant,
+            } => {
+                let forward = |x: u8| {
+                    if sbox {
+                        structure.sbox[x as usize] ^ constant
+                    } else {
+                        x
+                    }
+                };
+                let backward = |y: u8| {
+                    if sbox {
+                        structure.inv_sbox[(y ^ constant) as usize]
+                    } else {
+                        y
+                    }
+                };
+                match (self.bytes[a], self.bytes[b], self.bytes[c]) {
+                    (Some(x), Some(y), Some(z)) => {
+                        self.consistent &= x ^ y == forward(z);
+                        false
+                    }
+                    (None, Some(y), Some(z)) => {
+                        let value = y ^ forward(z);
+                        self.learn(a, value)
+                    }
+                    (Some(x), None, Some(z)) => {
+                        let value = x ^ forward(z);
+                        self.learn(b, value)
+                    }
+                    (Some(x), Some(y), None) => {
+                        let value = backward(x ^ y);
+                        self.learn(c, value)
+                    }
+                    _ => false,
+                }
+            }
+            Relation::Column { key, mixed } => {
+                let column: Option<Vec<u8>> = key.iter().map(|&index| self.bytes[index]).collect();
+                if let Some(column) = column {
+                    let mixed_column = Rijndael::inv_mix_columns(&column);
+                    return (0..4).fold(false, |learnt, row| {
+                        self.learn(mixed[row], mixed_column[row]) | learnt
+                    });
+                }
+                let mut equations = vec![];
+                for (row, &index) in key.iter().enumerate() {
+                    if let Some(value) = self.bytes[index] {
+                        equations.push((std::array::from_fn(|j| (j == row) as u8), value));
+                    }
+                }
+                for (row, &index) in mixed.iter().enumerate() {
+                    if let Some(value) = self.bytes[index] {
+                        let coefficients =
+                            std::array::from_fn(|j| INV_MIX_COLUMNS[(4 + j - row) % 4]);
+                        equations.push((coefficients, value));
+                    }
+                }
+                if equations.len() < 4 {
+                    return false;
+                }
+                let Some(column) = solve(equations) else {
+                    self.consistent = false;
+                    return false;
+                };
+                let mut learnt = false;
+                let mixed_column = Rijndael::inv_mix_columns(&column);
+                for row in 0..4 {
+                    learnt |= self.learn(key[row], column[row]);
+                    learnt |= self.learn(mixed[row], mixed_column[row]);
+                }
+                learnt
+            }
+        }
+    }
+
+    /// Fills in every byte the known ones determine through single relations
+    /// of the key schedule, forwards and backwards, until nothing changes.
+    /// Returns whether everything known is consistent.
+    pub fn propagate(&mut self) -> bool {
+        let structure = Rc::clone(&self.structure);
+        while let Some(index) = self.pending.pop() {
+            if !self.consistent {
+                break;
+            }
+            for &i in &structure.relations_of[index] {
+                self.apply(structure.relations[i]);
+            }
+        }
+        self.consistent
+    }
+
+    /// The unknown byte whose guess determines the most others right away:
+    /// those in schedule relations with one other unknown byte and in
+    /// columns missing one byte of the 4 that determine them. Falls back to
+    /// the first unknown byte of the cipher key.
+    fn next_guess(&self) -> usize {
+        let mut scores = vec![0; self.bytes.len()];
+        for relation in &self.structure.relations {
+            let unknown: Vec<usize> = relation
+                .bytes()
+                .into_iter()
+                .filter(|&i| self.bytes[i].is_none())
+                .collect();
+            if unknown.len() == relation.max_unknown() + 1 {
+                for i in unknown {
+                    scores[i] += 1;
+                }
+            }
+        }
+        let first_unknown = (0..BLOCK_SIZE).find(|&i| self.bytes[i].is_none()).unwrap();
+        (0..self.bytes.len())
+            .filter(|&i| scores[i] > 0)
+            .max_by_key(|&i| (scores[i], std::cmp::Reverse(i)))
+            .unwrap_or(first_unknown)
+    }
+
+    /// Guesses unknown bytes and propagates until the cipher key is known,
+    /// passing every consistent one to `visit` until it returns true.
+    fn search<F: FnMut(Block) -> bool>(&self, visit: &mut F) -> bool {
+        let mut schedule = self.clone();
+        if !schedule.propagate() {
+            return false;
+        }
+        if let Some(key) = schedule.key() {
+            return visit(key);
+        }
+        let index = schedule.next_guess();
+        (0..=255).any(|value| {
+            let mut guess = schedule.clone();
+            guess.learn(index, value);
+            guess.search(visit)
+        })
+    }
+
+    /// Every cipher key consistent with what is known, which may be up to
+    /// `256^num_unknown()` of them.
+    pub fn candidates(&self) -> Vec<Block> {
+        let mut keys = vec![];
+        self.search(&mut |key| {
+            keys.push(key);
+            false
+        });
+        keys
+    }
+
+    /// The first cipher key consistent with what is known that passes
+    /// `is_key`, e.g. reproduces a known plaintext-ciphertext pair.
+    pub fn find_key<F: Fn(Block) -> bool>(&self, is_key: F) -> Option<Block> {
+        let mut found = None;
+        self.search(&mut |key| {
+            found = Some(key).filter(|&key| is_key(key));
+            found.is_some()
+        });
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+
+    const KEY: Block = *b"sixteen byte key";
+
+    fn round_keys() -> Vec<Block> {
+        unsafe {
+            AES128::key_expansion(AES128::block_to_state(KEY))
+                .into_iter()
+                .map(|round_key| AES128::state_to_block(round_key))
+                .collect()
+        }
+    }
+
+    fn mixed_key(round: usize) -> Block {
+        unsafe {
+            AES128::state_to_block(AES128::inv_mix_columns(AES128::block_to_state(
+                round_keys()[round],
+            )))
+        }
+    }
+
+    #[test]
+    fn test_forwards_and_backwards() {
+        let round_keys = round_keys();
+        for known in [0, 4, 10] {
+            let mut schedule = PartialKeySchedule::new(10);
+            for (pos, &value) in round_keys[known].iter().enumerate() {
+                schedule.set_round_key_byte(known, pos, value);
+            }
+            assert!(schedule.propagate());
+            for (round, round_key) in round_keys.iter().enumerate() {
+                assert_eq!(schedule.round_key(round), round_key.map(Some));
+                assert_eq!(schedule.mixed_key(round), mixed_key(round).map(Some));
+            }
+            assert_eq!(schedule.candidates(), [KEY]);
+        }
+    }
+
+    #[test]
+    fn test_mixed_key() {
+        // A column of the round key and bytes of InvMixColumns of it as
+        // recovered by the attacks peeling off a round with MixColumns: any 4
+        // bytes of a column on either side give the rest.
+        let (round_keys, mixed) = (round_keys(), mixed_key(4));
+        let mut schedule = PartialKeySchedule::new(10);
+        for (pos, &value) in round_keys[4][..4].iter().enumerate() {
+            schedule.set_round_key_byte(4, pos, value);
+        }
+        for (pos, &value) in mixed.iter().enumerate().skip(4) {
+            if pos % 4 != 3 {
+                schedule.set_mixed_key_byte(4, pos, value);
+            }
+        }
+        assert!(schedule.propagate());
+        assert!(schedule.round_key(4)[..4].iter().all(Option::is_some));
+        assert_eq!(schedule.num_unknown(), 16);
+        // Three bytes of each of the other columns leave 2^24 keys; a byte
+        // of the next round key leaves 2^16, and one more of the same column
+        // 2^8, which `find_key` tells apart with a known pair.
+        schedule.set_round_key_byte(5, 7, round_keys[5][7]);
+        schedule.set_round_key_byte(3, 7, round_keys[3][7]);
+        let candidates = schedule.candidates();
+        assert_eq!(candidates.len(), 256);
+        assert!(candidates.contains(&KEY));
+        let aes = unsafe { AES128::new(KEY, 4) };
+        let msg = [0; 16];
+        let enc = unsafe { aes.encrypt(msg) };
+        let found = schedule.find_key(|key| unsafe { AES128::new(key, 4).encrypt(msg) } == enc);
+        assert_eq!(found, Some(KEY));
+    }
+
+    #[test]
+    fn test_missing_bytes() {
+        // All of the last round key but two bytes, one of which follows from
+        // a byte of the round key before.
+        let round_keys = round_keys();
+        let mut schedule = PartialKeySchedule::new(10);
+        for pos in (0..16).filter(|&pos| pos != 5 && pos != 10) {
+            schedule.set_round_key_byte(10, pos, round_keys[10][pos]);
+        }
+        schedule.set_round_key_byte(9, 9, round_keys[9][9]);
+        assert!(schedule.propagate());
+        assert_eq!(schedule.round_key(10)[5], Some(round_keys[10][5]));
+        assert_eq!(schedule.round_key(10)[10], None);
+        let candidates = schedule.candidates();
+        assert_eq!(candidates.len(), 256);
+        assert!(candidates.contains(&KEY));
+    }
+
+    #[test]
+    fn test_contradiction() {
+        let round_keys = round_keys();
+        let mut schedule = PartialKeySchedule::new(10);
+        for (pos, &value) in round_keys[10].iter().enumerate() {
+            schedule.set_round_key_byte(10, pos, value);
+        }
+        schedule.set_round_key_byte(0, 0, KEY[0] ^ 1);
+        assert!(!schedule.propagate());
+        assert!(schedule.candidates().is_empty());
+        let mut schedule = PartialKeySchedule::new(10);
+        schedule.set_mixed_key_byte(2, 0, 1);
+        schedule.set_mixed_key_byte(2, 0, 2);
+        assert!(!schedule.is_consistent());
+    }
+}
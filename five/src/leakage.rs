@@ -0,0 +1,258 @@
+use std::f64::consts::TAU;
+use std::io::{self, Read, Write};
+
+use rand::{thread_rng, Rng};
+
+use crate::aes::{Block, Step, AES128, BLOCK_SIZE};
+use crate::attack::gen_random_block;
+
+/// Starts every file written by `TraceSet::write_to`.
+const MAGIC: &[u8; 4] = b"TRC1";
+
+/// What a device leaks while it writes the output of an S-box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeakageModel {
+    /// The Hamming weight of the output, as when a precharged bus carries it.
+    HammingWeight,
+    /// The number of bits flipping when the output overwrites the input in
+    /// the same register.
+    HammingDistance,
+}
+
+impl LeakageModel {
+    /// The noiseless leakage of writing `output` over `input`.
+    pub fn leak(self, input: u8, output: u8) -> u32 {
+        match self {
+            LeakageModel::HammingWeight => output.count_ones(),
+            LeakageModel::HammingDistance => (input ^ output).count_ones(),
+        }
+    }
+}
+
+/// A sample of Gaussian noise, by the Box-Muller transform.
+fn gaussian<R: Rng>(rng: &mut R, standard_deviation: f64) -> f64 {
+    let (u, v): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+    standard_deviation * (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+}
+
+/// Simulates the power consumption of an AES implementation: one sample per
+/// S-box of every round, in the order `AES128::encrypt` computes them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeakageSimulator {
+    pub model: LeakageModel,
+    /// The standard deviation of the Gaussian noise added to every sample.
+    pub noise: f64,
+}
+
+impl LeakageSimulator {
+    pub fn new(model: LeakageModel, noise: f64) -> Self {
+        Self { model, noise }
+    }
+
+    /// Encrypts `msg` and returns the ciphertext with its trace, the samples
+    /// of byte `i` of the SubBytes of round `r` at `16 * (r - 1) + i`.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn encrypt(&self, aes: &AES128, msg: Block) -> (Block, Vec<f32>) {
+        let trace = aes.encrypt_traced(msg);
+        let mut rng = thread_rng();
+        let mut samples = vec![];
+        let mut input = trace.state(0, Step::AddRoundKey).unwrap();
+        for round in 1.. {
+            let Some(output) = trace.state(round, Step::SubBytes) else {
+                break;
+            };
+            samples.extend(input.iter().zip(output).map(|(&input, output)| {
+                let leakage = self.model.leak(input, output) as f64;
+                (leakage + gaussian(&mut rng, self.noise)) as f32
+            }));
+            input = trace.state(round, Step::AddRoundKey).unwrap();
+        }
+        let enc = trace.entries.last().unwrap().state;
+        (enc, samples)
+    }
+}
+
+/// Traces of random plaintexts with their ciphertexts, as collected from a
+/// device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceSet {
+    pub plaintexts: Vec<Block>,
+    pub ciphertexts: Vec<Block>,
+    pub traces: Vec<Vec<f32>>,
+    /// The number of samples per trace read from a file, which is all that
+    /// keeps it when the file has no traces.
+    num_samples: usize,
+}
+
+impl TraceSet {
+    /// Records `num_traces` encryptions of random plaintexts under `aes`.
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn collect(aes: &AES128, simulator: &LeakageSimulator, num_traces: usize) -> Self {
+        let mut trace_set = Self::default();
+        for _ in 0..num_traces {
+            let msg = gen_random_block();
+            let (enc, trace) = simulator.encrypt(aes, msg);
+            trace_set.num_samples = trace.len();
+            trace_set.plaintexts.push(msg);
+            trace_set.ciphertexts.push(enc);
+            trace_set.traces.push(trace);
+        }
+        trace_set
+    }
+
+    pub fn len(&self) -> usize {
+        self.traces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.traces.is_empty()
+    }
+
+    /// The number of samples in every trace.
+    pub fn num_samples(&self) -> usize {
+        self.traces.first().map_or(self.num_samples, Vec::len)
+    }
+
+    /// Writes the traces in a simple binary format for offline analysis: the
+    /// magic `TRC1`, the number of traces and of samples per trace as
+    /// little-endian `u32`s, then for every trace its plaintext, its
+    /// ciphertext and its samples as little-endian `f32`s.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.num_samples() as u32).to_le_bytes())?;
+        for ((msg, enc), trace) in self
+            .plaintexts
+            .iter()
+            .zip(&self.ciphertexts)
+            .zip(&self.traces)
+        {
+            writer.write_all(msg)?;
+            writer.write_all(enc)?;
+            for sample in trace {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads traces written by `write_to`. The header is not trusted: memory
+    /// only grows with the samples actually read.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a trace set",
+            ));
+        }
+        let mut read_u32 = || -> io::Result<usize> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes) as usize)
+        };
+        let (num_traces, num_samples) = (read_u32()?, read_u32()?);
+
+        let mut trace_set = Self {
+            num_samples,
+            ..Self::default()
+        };
+        for _ in 0..num_traces {
+            let (mut msg, mut enc) = ([0; BLOCK_SIZE], [0; BLOCK_SIZE]);
+            reader.read_exact(&mut msg)?;
+            reader.read_exact(&mut enc)?;
+            let mut bytes = vec![];
+            reader
+                .by_ref()
+                .take(4 * num_samples as u64)
+                .read_to_end(&mut bytes)?;
+            if bytes.len() != 4 * num_samples {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated trace",
+                ));
+            }
+            let trace = bytes
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+                .collect();
+            trace_set.plaintexts.push(msg);
+            trace_set.ciphertexts.push(enc);
+            trace_set.traces.push(trace);
+        }
+        Ok(trace_set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noiseless_hamming_weight() {
+        unsafe {
+            let aes = AES128::new(gen_random_block(), 10);
+            let simulator = LeakageSimulator::new(LeakageModel::HammingWeight, 0.0);
+            let msg = gen_random_block();
+            let (enc, samples) = simulator.encrypt(&aes, msg);
+            assert_eq!(enc, aes.encrypt(msg));
+            assert_eq!(samples.len(), 160);
+            let trace = aes.encrypt_traced(msg);
+            let output = trace.state(3, Step::SubBytes).unwrap();
+            for i in 0..BLOCK_SIZE {
+                assert_eq!(samples[32 + i], output[i].count_ones() as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise() {
+        let samples: Vec<f64> = (0..10000)
+            .map(|_| gaussian(&mut thread_rng(), 2.0))
+            .collect();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let variance =
+            samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        assert!(mean.abs() < 0.1);
+        assert!((variance - 4.0).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        unsafe {
+            let aes = AES128::new(gen_random_block(), 2);
+            let simulator = LeakageSimulator::new(LeakageModel::HammingDistance, 1.0);
+            let trace_set = TraceSet::collect(&aes, &simulator, 5);
+            let mut bytes = vec![];
+            trace_set.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), 12 + 5 * (32 + 4 * 32));
+            assert_eq!(
+                TraceSet::read_from(&mut bytes.as_slice()).unwrap(),
+                trace_set
+            );
+            assert!(TraceSet::read_from(&mut &bytes[..bytes.len() - 1]).is_err());
+            bytes[0] = b'X';
+            assert!(TraceSet::read_from(&mut bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_untrusted_header() {
+        let empty = TraceSet {
+            num_samples: 160,
+            ..TraceSet::default()
+        };
+        let mut bytes = vec![];
+        empty.write_to(&mut bytes).unwrap();
+        let read = TraceSet::read_from(&mut bytes.as_slice()).unwrap();
+        assert!(read.is_empty());
+        assert_eq!(read.num_samples(), 160);
+        // A header claiming huge traces fails on the missing samples.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(u32::MAX.to_le_bytes());
+        bytes.extend([0; 2 * BLOCK_SIZE + 8]);
+        assert!(TraceSet::read_from(&mut bytes.as_slice()).is_err());
+    }
+}
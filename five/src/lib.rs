@@ -0,0 +1,37 @@
+//! Every `unsafe fn` in this crate is unsafe for the same reason: it relies on
+//! the AES-NI and AVX2 instruction sets being available on the running CPU.
+#![allow(clippy::missing_safety_doc)]
+
+pub mod aes;
+pub mod aes_like;
+pub mod algebraic;
+pub mod attack;
+pub mod bitsliced;
+pub mod cache;
+pub mod collision;
+pub mod cpa;
+pub mod cube;
+pub mod dfa;
+pub mod division;
+pub mod dudect;
+pub mod generic_attack;
+pub mod impossible;
+pub mod integral;
+pub mod key_schedule;
+pub mod leakage;
+pub mod mac;
+pub mod mitm;
+pub mod mixture;
+pub mod modes;
+pub mod rijndael;
+pub mod sat;
+pub mod sbox_analysis;
+pub mod small_aes;
+pub mod square;
+pub mod trail;
+pub mod truncated;
+pub mod ttable;
+pub mod yoyo;
+
+#[cfg(test)]
+pub(crate) mod test_util;
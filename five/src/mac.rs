@@ -0,0 +1,170 @@
+use crate::aes::{Block, AES128, BLOCK_SIZE};
+use crate::attack::{crack_key, crack_key_four_rounds};
+
+fn xor_into(acc: &mut Block, chunk: &[u8]) {
+    for (x, y) in acc.iter_mut().zip(chunk) {
+        *x ^= y;
+    }
+}
+
+/// Multiplication by x in GF(2^128), used to derive the CMAC subkeys.
+fn double(block: Block) -> Block {
+    let x = u128::from_be_bytes(block);
+    let res = (x << 1) ^ if x >> 127 == 1 { 0x87 } else { 0 };
+    res.to_be_bytes()
+}
+
+/// Plain CBC-MAC with a zero IV. Only messages consisting of whole blocks are
+/// accepted, which is exactly what makes a single-block tag an encryption.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn cbc_mac(aes: &AES128, msg: &[u8]) -> Option<Block> {
+    if msg.is_empty() || !msg.len().is_multiple_of(BLOCK_SIZE) {
+        return None;
+    }
+    let mut tag = [0; BLOCK_SIZE];
+    for chunk in msg.chunks(BLOCK_SIZE) {
+        xor_into(&mut tag, chunk);
+        tag = aes.encrypt(tag);
+    }
+    Some(tag)
+}
+
+pub struct Cmac<'a> {
+    aes: &'a AES128,
+    k1: Block,
+    k2: Block,
+}
+
+impl<'a> Cmac<'a> {
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn new(aes: &'a AES128) -> Self {
+        let k1 = double(aes.encrypt([0; BLOCK_SIZE]));
+        Self {
+            aes,
+            k1,
+            k2: double(k1),
+        }
+    }
+
+    #[target_feature(enable = "avx2,aes")]
+    pub unsafe fn tag(&self, msg: &[u8]) -> Block {
+        let num_blocks = msg.len().div_ceil(BLOCK_SIZE).max(1);
+        let (head, last) = msg.split_at((num_blocks - 1) * BLOCK_SIZE);
+
+        let mut tag = [0; BLOCK_SIZE];
+        for chunk in head.chunks(BLOCK_SIZE) {
+            xor_into(&mut tag, chunk);
+            tag = self.aes.encrypt(tag);
+        }
+
+        xor_into(&mut tag, last);
+        if last.len() == BLOCK_SIZE {
+            xor_into(&mut tag, &self.k1);
+        } else {
+            tag[last.len()] ^= 0x80;
+            xor_into(&mut tag, &self.k2);
+        }
+        self.aes.encrypt(tag)
+    }
+}
+
+/// The tag of a single-block message is the encryption of that block, masked
+/// by a constant for CMAC. A constant mask leaves a delta set a delta set, so
+/// the Square attack goes through unchanged.
+fn single_block_tags<M: Fn(&[u8]) -> Block>(mac_service: &M) -> impl Fn(Block) -> Block + '_ {
+    move |msg: Block| mac_service(&msg)
+}
+
+/// Recovers the key of a 4-round AES behind a MAC service which only reveals
+/// tags of chosen messages.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_mac_key_four_rounds<M: Fn(&[u8]) -> Block>(mac_service: &M) -> Block {
+    crack_key_four_rounds(&single_block_tags(mac_service))
+}
+
+/// Same as `crack_mac_key_four_rounds` but for 5 rounds, using `crack_key`.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_mac_key<M: Fn(&[u8]) -> Block>(mac_service: &M) -> Block {
+    crack_key(&single_block_tags(mac_service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modes::cbc_encrypt;
+    use crate::test_util::decode_hex;
+
+    fn nist_aes() -> AES128 {
+        unsafe {
+            AES128::new(
+                decode_hex("2b7e151628aed2a6abf7158809cf4f3c")
+                    .try_into()
+                    .unwrap(),
+                10,
+            )
+        }
+    }
+
+    #[test]
+    fn test_cmac() {
+        unsafe {
+            let aes = nist_aes();
+            let cmac = Cmac::new(&aes);
+            let msg = decode_hex(
+                "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+                 30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+            );
+            for (len, expected) in [
+                (0, "bb1d6929e95937287fa37d129b756746"),
+                (16, "070a16b46b4d4144f79bdd9dd04a287c"),
+                (40, "dfa66747de9ae63030ca32611497c827"),
+                (64, "51f0bebf7e3b9d92fc49741779363cfe"),
+            ] {
+                assert_eq!(cmac.tag(&msg[..len]).to_vec(), decode_hex(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn test_cbc_mac() {
+        unsafe {
+            let aes = nist_aes();
+            let msg =
+                decode_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+            // With a zero IV the tag is the last block of the CBC encryption.
+            let enc_msg = cbc_encrypt(&aes, [0; BLOCK_SIZE], &msg);
+            assert_eq!(cbc_mac(&aes, &msg).unwrap(), enc_msg[16..32]);
+            assert_eq!(cbc_mac(&aes, &msg[..20]), None);
+        }
+    }
+
+    #[test]
+    fn test_crack_cmac_key_four_rounds() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let aes = AES128::new(key, 4);
+            let cmac = Cmac::new(&aes);
+            assert_eq!(crack_mac_key_four_rounds(&|msg: &[u8]| cmac.tag(msg)), key);
+        }
+    }
+
+    #[test]
+    fn test_crack_cbc_mac_key_four_rounds() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let aes = AES128::new(key, 4);
+            assert_eq!(
+                crack_mac_key_four_rounds(&|msg: &[u8]| cbc_mac(&aes, msg).unwrap()),
+                key
+            );
+        }
+    }
+}
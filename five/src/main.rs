@@ -1,27 +1,286 @@
-use aes::{AES128, BLOCK_SIZE};
-use attack::crack_key;
+use std::cell::Cell;
+use std::time::Instant;
+
+use five::aes::{Backend, Block, AES128, BLOCK_SIZE};
+use five::algebraic::{Description, System};
+use five::attack::crack_key;
+use five::cube::{estimate_degree, CubeCipher, ReducedAES, ReducedSmallAES};
+use five::dfa::{crack_key_dfa, randomize_byte};
+use five::dudect::measure_leakage;
+use five::mitm::complexity;
+use five::mixture::{crack_key_mixture, mixture_statistics, multiple_of_eight_pairs};
+use five::rijndael::Rijndael;
+use five::sat::Solver;
+use five::sbox_analysis::{aes_sbox, bct, ddt, lat, parse_sbox, properties, write_anf_to};
+use five::small_aes::SmallAES;
+use five::trail::{Kind, TrailModel};
+use five::truncated::crack_key_truncated;
+use five::ttable::TTableAES;
+use five::yoyo::crack_key_yoyo;
 use rand::{thread_rng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
-mod aes;
-mod attack;
-
 fn generate_secure_key() -> [u8; BLOCK_SIZE] {
     let mut key = [0; BLOCK_SIZE];
     ChaCha20Rng::from_rng(thread_rng()).unwrap().fill(&mut key);
     key
 }
 
+/// Prints how many pairs satisfy the mixture and multiple-of-8 properties
+/// on either side of the number of rounds they cover.
+unsafe fn print_statistics(secret_key: [u8; BLOCK_SIZE]) {
+    for num_rounds in [4, 5] {
+        let aes = AES128::new(secret_key, num_rounds);
+        println!("{num_rounds} rounds: {:?}", mixture_statistics(&aes, 17));
+    }
+    for num_rounds in [5, 6] {
+        let aes = AES128::new(secret_key, num_rounds);
+        let start = Instant::now();
+        let pairs = multiple_of_eight_pairs(&aes, 0, 0);
+        println!(
+            "{num_rounds} rounds: {pairs} pairs agree on an anti-diagonal, {} mod 8, {:.1?}",
+            pairs % 8,
+            start.elapsed()
+        );
+    }
+}
+
+/// Prints the dudect t-values of `AES128` on the bitsliced backend and of the
+/// T-table software AES.
+unsafe fn print_timing_leakage(secret_key: [u8; BLOCK_SIZE]) {
+    let fixed = generate_secure_key();
+    let bitsliced = AES128::with_backend(secret_key, 10, Backend::Bitsliced);
+    let report = measure_leakage(|msg| bitsliced.encrypt(msg), fixed, 1_000_000);
+    println!("bitsliced: {report:?}, leaking: {}", report.is_leaking());
+    let ttable = TTableAES::new(secret_key, 10);
+    let report = measure_leakage(|msg| ttable.encrypt(msg), fixed, 1_000_000);
+    println!("T-table: {report:?}, leaking: {}", report.is_leaking());
+}
+
+/// Prints lower bounds on the degree of 1 to 3 rounds of AES and of the
+/// small-scale variant from cube sums over up to 20 bits.
+unsafe fn print_degree_estimates() {
+    unsafe fn print<C: CubeCipher>(name: &str, cipher: &C) {
+        let start = Instant::now();
+        let degree = estimate_degree(cipher, 20, 4);
+        println!("{name}: degree at least {degree}, {:.1?}", start.elapsed());
+    }
+    for num_rounds in 1..=3 {
+        print(
+            &format!("AES, {num_rounds} rounds"),
+            &ReducedAES { num_rounds },
+        );
+        print(
+            &format!("small AES, {num_rounds} rounds"),
+            &ReducedSmallAES { num_rounds },
+        );
+    }
+}
+
+/// Two random known pairs of the 2x2 small-scale variant on `num_rounds`,
+/// packed into bits like `algebraic` wants them, and the key.
+fn small_aes_pairs(num_rounds: usize) -> (u128, Vec<(u128, u128)>) {
+    let mut rng = thread_rng();
+    let key = rng.gen::<u16>() as u128;
+    let cipher = SmallAES::new(&SmallAES::unpack(key, 4), 2, 2, num_rounds);
+    let pairs = (0..2)
+        .map(|_| {
+            let msg = rng.gen::<u16>() as u128;
+            (
+                msg,
+                SmallAES::pack(&cipher.encrypt(&SmallAES::unpack(msg, 4))),
+            )
+        })
+        .collect();
+    (key, pairs)
+}
+
+/// Solves the equations of two known pairs of the 2x2 small-scale variant on
+/// 1 to 3 rounds, or with `cnf` or `anf` writes those of 3 rounds to stdout.
+fn run_algebraic(mode: &str) {
+    if mode != "sat" {
+        let (_, pairs) = small_aes_pairs(3);
+        let system = System::new(&Description::small_aes(2, 2, 3), &pairs);
+        let mut stdout = std::io::stdout().lock();
+        if mode == "cnf" {
+            system.to_cnf().write_to(&mut stdout).unwrap();
+        } else {
+            system.write_anf_to(&mut stdout).unwrap();
+        }
+        return;
+    }
+    for num_rounds in 1..=3 {
+        let (key, pairs) = small_aes_pairs(num_rounds);
+        let system = System::new(&Description::small_aes(2, 2, num_rounds), &pairs);
+        let cnf = system.to_cnf();
+        let start = Instant::now();
+        let mut solver = Solver::new(&cnf);
+        let recovered = solver.solve().map(|model| system.key(&model));
+        println!(
+            "{num_rounds} rounds: {} variables, {} clauses, {} conflicts, {:.1?}, key {:04x}, found {:04x?}",
+            cnf.num_vars,
+            cnf.clauses.len(),
+            solver.num_conflicts(),
+            start.elapsed(),
+            key,
+            recovered
+        );
+    }
+}
+
+/// Prints the fewest active S-boxes over 1 to 6 rounds of AES, the bounds
+/// on characteristics and linear trails they give, and the best of those
+/// over up to 4 rounds. From 4 rounds on no trail beats the codebook, while
+/// the Square attack goes through 5.
+fn print_trail_bounds(aes: &Rijndael) {
+    let model = TrailModel::new(aes);
+    for num_rounds in 1..=6 {
+        let active = model.min_active_sboxes(Kind::Differential, num_rounds);
+        let differential = model.weight_bound(Kind::Differential, num_rounds);
+        let linear = model.weight_bound(Kind::Linear, num_rounds);
+        println!(
+            "{num_rounds} rounds: {active} active S-boxes, probability at most 2^-{differential}, correlation at most 2^-{linear}"
+        );
+        if num_rounds <= 4 {
+            for kind in [Kind::Differential, Kind::Linear] {
+                let start = Instant::now();
+                let trail = model.best_trail(kind, num_rounds);
+                println!(
+                    "  best {kind:?} trail: 2^-{:.2}, {} active S-boxes, {:.1?}",
+                    trail.weight,
+                    trail.num_active(),
+                    start.elapsed()
+                );
+            }
+        }
+    }
+}
+
+/// Reports a bad invocation of the `sbox` mode and exits with status 2.
+fn sbox_usage(problem: &str) -> ! {
+    eprintln!("{problem}");
+    eprintln!("usage: five sbox [ddt|lat|bct|anf] [file]");
+    std::process::exit(2)
+}
+
+/// Prints the properties of the AES S-box, or of the S-box written in hex in
+/// the file named after `sbox`. With `ddt`, `lat`, `bct` or `anf` in between
+/// prints that table of it as CSV, or its algebraic normal form, instead.
+fn analyze_sbox(args: &[String]) {
+    const TABLES: [&str; 4] = ["ddt", "lat", "bct", "anf"];
+    let (table, path) = match args {
+        [table, path] if TABLES.contains(&table.as_str()) => (Some(table.as_str()), Some(path)),
+        [table, _] => sbox_usage(&format!("unknown table {table}")),
+        [arg] if TABLES.contains(&arg.as_str()) => (Some(arg.as_str()), None),
+        [path] => (None, Some(path)),
+        [] => (None, None),
+        _ => sbox_usage("too many arguments"),
+    };
+    let sbox = match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .unwrap_or_else(|err| sbox_usage(&format!("cannot read {path}: {err}")));
+            parse_sbox(&text).unwrap_or_else(|| {
+                sbox_usage(&format!("{path} is not an S-box of 2^n entries of n bits"))
+            })
+        }
+        None => unsafe { aes_sbox() }.to_vec(),
+    };
+    let mut stdout = std::io::stdout().lock();
+    match table {
+        Some("ddt") => ddt(&sbox).write_csv_to(&mut stdout).unwrap(),
+        Some("lat") => lat(&sbox).write_csv_to(&mut stdout).unwrap(),
+        Some("bct") => bct(&sbox)
+            .unwrap_or_else(|| sbox_usage("the BCT needs a permutation"))
+            .write_csv_to(&mut stdout)
+            .unwrap(),
+        Some("anf") => write_anf_to(&sbox, &mut stdout).unwrap(),
+        _ => println!("{:?}", properties(&sbox)),
+    }
+}
+
+/// Runs the Square attack, or the yoyo or mixture attack when called with
+/// `yoyo` or `mixture`, and reports the number of oracle queries and the time
+/// taken. `truncated` runs the truncated differential attack on 4 rounds
+/// instead, `dfa` the fault attack on 10 rounds, `statistics` prints the
+/// mixture and multiple-of-8 statistics, `mitm` the complexity of the
+/// meet-in-the-middle attack on 7-round AES, `dudect` the timing leakage of
+/// the software implementations, `cube` the degrees of reduced rounds and
+/// `sat`, `cnf` and `anf` the algebraic attack on small-scale AES, `trails`
+/// the bounds on differential and linear trails. `sbox` analyzes an S-box.
 fn main() {
     unsafe {
         let secret_key = generate_secure_key();
-        let aes = AES128::new(secret_key, 5);
+        let mode = std::env::args().nth(1);
+        if mode.as_deref() == Some("statistics") {
+            print_statistics(secret_key);
+            return;
+        }
+        if mode.as_deref() == Some("dudect") {
+            print_timing_leakage(secret_key);
+            return;
+        }
+        if mode.as_deref() == Some("sbox") {
+            analyze_sbox(&std::env::args().skip(2).collect::<Vec<_>>());
+            return;
+        }
+        if let Some(mode @ ("sat" | "cnf" | "anf")) = mode.as_deref() {
+            run_algebraic(mode);
+            return;
+        }
+        if mode.as_deref() == Some("cube") {
+            print_degree_estimates();
+            return;
+        }
+        if mode.as_deref() == Some("trails") {
+            print_trail_bounds(&Rijndael::new(&secret_key, 4, 10));
+            return;
+        }
+        if mode.as_deref() == Some("mitm") {
+            println!("{:?}", complexity(&Rijndael::new(&secret_key, 4, 7)));
+            return;
+        }
+        let num_rounds = match mode.as_deref() {
+            Some("truncated") => 4,
+            Some("dfa") => 10,
+            _ => 5,
+        };
+        let aes = AES128::new(secret_key, num_rounds);
 
         println!("{:?}", secret_key);
 
-        let recovered_key = crack_key(&aes);
+        let (encryptions, decryptions) = (Cell::new(0u64), Cell::new(0u64));
+        let encryption_service = |msg: Block| {
+            encryptions.set(encryptions.get() + 1);
+            aes.encrypt(msg)
+        };
+        // Faulty encryptions count as encryptions.
+        let faulty_service = |msg: Block| {
+            encryptions.set(encryptions.get() + 1);
+            let pos = thread_rng().gen_range(0..BLOCK_SIZE);
+            aes.encrypt_with_fault(msg, num_rounds - 1, randomize_byte(pos))
+        };
+        let decryption_service = |enc_msg: Block| {
+            decryptions.set(decryptions.get() + 1);
+            aes.decrypt(enc_msg)
+        };
+
+        let start = Instant::now();
+        let recovered_key = match mode.as_deref() {
+            Some("yoyo") => crack_key_yoyo(&encryption_service, &decryption_service),
+            Some("mixture") => crack_key_mixture(&encryption_service, [None; BLOCK_SIZE]),
+            Some("truncated") => crack_key_truncated(&encryption_service),
+            Some("dfa") => crack_key_dfa(&encryption_service, &faulty_service, num_rounds),
+            _ => crack_key(&encryption_service),
+        };
 
         println!("{:?}", recovered_key);
+        println!(
+            "{} encryptions, {} decryptions, {:.1?}",
+            encryptions.get(),
+            decryptions.get(),
+            start.elapsed()
+        );
 
         if recovered_key == secret_key {
             println!("Key successfully recovered!");
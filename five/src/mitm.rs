@@ -0,0 +1,358 @@
+use std::collections::HashSet;
+
+use crate::generic_attack::{
+    add_round_key, completions, peel_last_round, setup_with_base, CellCipher,
+};
+use crate::impossible::{diagonal, shifted_column, unmixed_service};
+
+/// The forward S-box, from the inverse one.
+fn sbox<C: CellCipher>(cipher: &C) -> Vec<u8> {
+    let mut sbox = vec![0; 1 << cipher.cell_bits()];
+    for y in 0..sbox.len() {
+        sbox[cipher.inv_sbox(y as u8) as usize] = y as u8;
+    }
+    sbox
+}
+
+/// ShiftRows, from the destinations InvShiftRows gives away.
+fn shift_rows<C: CellCipher>(cipher: &C, state: &[u8]) -> Vec<u8> {
+    let identity: Vec<u8> = (0..cipher.num_cells() as u8).collect();
+    let mut shifted = vec![0; cipher.num_cells()];
+    for (pos, dest) in cipher.inv_shift_rows(&identity).into_iter().enumerate() {
+        shifted[dest as usize] = state[pos];
+    }
+    shifted
+}
+
+/// A round without its key addition, applied to `state` with the S-boxes of
+/// all cells outside `active` left out. What they would add is constant over
+/// a delta set and goes into the parameters.
+fn partial_round<C: CellCipher>(
+    cipher: &C,
+    sbox: &[u8],
+    state: &[u8],
+    active: impl Iterator<Item = usize>,
+) -> Vec<u8> {
+    let mut substituted = vec![0; cipher.num_cells()];
+    for pos in active {
+        substituted[pos] = sbox[state[pos] as usize];
+    }
+    cipher.mix_columns(&shift_rows(cipher, &substituted))
+}
+
+/// How many cells fix the 4-round delta sequence: the key cell `κ` added to
+/// the delta set, the first column `a` after one round, the whole state `b`
+/// after two rounds and the diagonal `d` after three, in this order. That
+/// is 25 bytes on AES, as found by Demirci and Selçuk.
+pub fn num_parameters<C: CellCipher>(cipher: &C) -> usize {
+    1 + 2 * cipher.num_rows() + cipher.num_cells()
+}
+
+/// The first cell after four rounds, just before the last key addition, for
+/// the value `v` of the delta set.
+fn distinguished_cell<C: CellCipher>(cipher: &C, sbox: &[u8], params: &[u8], v: u8) -> u8 {
+    let (rows, num_cells) = (cipher.num_rows(), cipher.num_cells());
+    let (a, rest) = params[1..].split_at(rows);
+    let (b, d) = rest.split_at(num_cells);
+
+    let mut state = vec![0; num_cells];
+    state[0] = v ^ params[0];
+    let mut state = partial_round(cipher, sbox, &state, 0..1);
+    for (cell, a_cell) in state.iter_mut().zip(a) {
+        *cell ^= a_cell;
+    }
+    let state: Vec<u8> = partial_round(cipher, sbox, &state, 0..rows)
+        .iter()
+        .zip(b)
+        .map(|(cell, b_cell)| cell ^ b_cell)
+        .collect();
+    let mut state = partial_round(cipher, sbox, &state, 0..num_cells);
+    let diagonal = diagonal(cipher, 0);
+    for (&pos, d_cell) in diagonal.iter().zip(d) {
+        state[pos] ^= d_cell;
+    }
+    partial_round(cipher, sbox, &state, diagonal.into_iter())[0]
+}
+
+/// The differences between the first cell after four rounds for every value
+/// of a delta set and for its value 0, given the parameters.
+///
+/// A delta set in one cell fills the first column after one round with
+/// affine functions of a single S-box output, and every cell after two
+/// rounds with such a function plus a constant. Everything else the cells
+/// after four rounds depend on is one diagonal after three rounds, so the
+/// whole ordered sequence is fixed by `num_parameters` cells rather than by
+/// the key and the constants of the delta set.
+pub fn delta_sequence<C: CellCipher>(cipher: &C, params: &[u8]) -> Vec<u8> {
+    let sbox = sbox(cipher);
+    let first = distinguished_cell(cipher, &sbox, params, 0);
+    (1..1 << cipher.cell_bits())
+        .map(|v| distinguished_cell(cipher, &sbox, params, v as u8) ^ first)
+        .collect()
+}
+
+/// The precomputation table: the delta sequences of every value of the
+/// parameters not given in `planted`.
+pub fn precompute_table<C: CellCipher>(cipher: &C, planted: &[Option<u8>]) -> HashSet<Vec<u8>> {
+    let bits = cipher.cell_bits() as usize;
+    let free: Vec<usize> = (0..planted.len())
+        .filter(|&i| planted[i].is_none())
+        .collect();
+    let mut params: Vec<u8> = planted.iter().map(|param| param.unwrap_or(0)).collect();
+    (0..1usize << (bits * free.len()))
+        .map(|values| {
+            for (j, &i) in free.iter().enumerate() {
+                params[i] = ((values >> (bits * j)) % (1 << bits)) as u8;
+            }
+            delta_sequence(cipher, &params)
+        })
+        .collect()
+}
+
+/// The true parameters of the delta sets used by `crack_key_mitm_seven_rounds`
+/// with the constant cells `base`, computed from the round keys. Planting some
+/// of them shrinks the precomputation table to something that fits in memory.
+pub fn parameters<C: CellCipher>(cipher: &C, round_keys: &[Vec<u8>], base: &[u8]) -> Vec<u8> {
+    let (rows, num_cells) = (cipher.num_rows(), cipher.num_cells());
+    let sbox = sbox(cipher);
+    let diagonal = diagonal(cipher, 0);
+
+    // The plaintext of the value 0, which makes the first column zero after
+    // the first round.
+    let mut msg = base.to_vec();
+    for &pos in &diagonal {
+        msg[pos] = cipher.inv_sbox(0) ^ round_keys[0][pos];
+    }
+    let states: Vec<Vec<u8>> = round_keys[1..5]
+        .iter()
+        .scan(add_round_key(&msg, &round_keys[0]), |state, round_key| {
+            let round = partial_round(cipher, &sbox, state, 0..num_cells);
+            *state = add_round_key(&round, round_key);
+            Some(state.clone())
+        })
+        .collect();
+
+    let mut params = vec![round_keys[1][0]];
+    let column = partial_round(cipher, &sbox, &states[0], 0..1);
+    params.extend((0..rows).map(|row| states[1][row] ^ column[row]));
+    let state = partial_round(cipher, &sbox, &states[1], 0..rows);
+    params.extend((0..num_cells).map(|pos| states[2][pos] ^ state[pos]));
+    let state = partial_round(cipher, &sbox, &states[2], 0..num_cells);
+    params.extend(diagonal.iter().map(|&pos| states[3][pos] ^ state[pos]));
+    params
+}
+
+/// Demirci-Selçuk meet-in-the-middle attack on 7 rounds: one round of
+/// first-round key guessing in front of the 4-round distinguisher and two
+/// peeled rounds behind it.
+///
+/// Under a guess of the first-round key cells of the first diagonal, the
+/// structure over that diagonal contains a delta set in the first cell after
+/// one round, whose sequence after five rounds is looked up in `table`.
+/// Reaching the first cell after five rounds takes the shifted column of the
+/// last round key which ShiftRows fills from the first column and one cell
+/// of the equivalent key of the round before, added after InvMixColumns.
+/// Their cells not given in `planted_last` and `planted_mixed` are guessed,
+/// as are the first-round key cells not in `planted_key`; the other cells of
+/// the last round key cannot be and must be given. The first guess whose
+/// sequence is found gives the key.
+///
+/// This runs over `CellCipher` rather than on `AES128` with the delta sets of
+/// `attack::setup`: the delta set has to sit after the first round, so it is
+/// picked out of the structure under each guess instead of being encrypted
+/// directly, and on AES that structure is 2^32 chosen plaintexts next to a
+/// table of 2^200 sequences. Only the small-scale variants bring it within
+/// reach, and `AES128` cannot describe them; AES itself goes in as a
+/// `Rijndael` with 4 columns, as for `complexity`.
+pub fn crack_key_mitm_seven_rounds<C, E>(
+    cipher: &C,
+    encryption_service: &E,
+    base: &[u8],
+    table: &HashSet<Vec<u8>>,
+    planted_key: &[Option<u8>],
+    planted_last: &[Option<u8>],
+    planted_mixed: &[Option<u8>],
+) -> Option<Vec<u8>>
+where
+    C: CellCipher,
+    E: Fn(&[u8]) -> Vec<u8>,
+{
+    let (rows, bits) = (cipher.num_rows(), cipher.cell_bits() as usize);
+    let encryption_service = unmixed_service(cipher, encryption_service);
+    let diagonal = diagonal(cipher, 0);
+    let structure = setup_with_base(cipher, &encryption_service, &diagonal, base);
+    // The delta set is the first column (v, 0, ..., 0) after the first round.
+    let columns: Vec<Vec<u8>> = (0..1 << bits)
+        .map(|v| {
+            let mut state = vec![0; cipher.num_cells()];
+            state[0] = v as u8;
+            cipher.inv_mix_columns(&state)[..rows].to_vec()
+        })
+        .collect();
+    let shifted = shifted_column(cipher, 0);
+    assert!(
+        (0..cipher.num_cells()).all(|pos| shifted.contains(&pos) || planted_last[pos].is_some()),
+        "the last round key outside the first shifted column must be planted"
+    );
+    // Only the cell of the mixed key over the first cell matters.
+    let needed = shifted[0];
+    let planted_mixed: Vec<Option<u8>> = (0..cipher.num_cells())
+        .map(|pos| match pos == needed {
+            true => planted_mixed[pos],
+            false => Some(0),
+        })
+        .collect();
+
+    let mask = (1 << bits) - 1;
+    completions(cipher, planted_last).find_map(|last_round_key| {
+        let equivalent_key = if cipher.final_mix_columns() {
+            cipher.inv_mix_columns(&last_round_key)
+        } else {
+            last_round_key.clone()
+        };
+        let peeled = peel_last_round(cipher, &structure, &equivalent_key, 0);
+        completions(cipher, &planted_mixed).find_map(|mixed_key| {
+            (0..1usize << (bits * rows))
+                .filter(|&guess| {
+                    diagonal.iter().enumerate().all(|(row, &pos)| {
+                        planted_key[pos]
+                            .is_none_or(|cell| (guess >> (bits * row)) & mask == cell as usize)
+                    })
+                })
+                .any(|guess| {
+                    let cells: Vec<u8> = columns
+                        .iter()
+                        .map(|column| {
+                            let index = (0..rows).fold(0, |index, row| {
+                                let cell = cipher.inv_sbox(column[row]) as usize;
+                                index | (cell ^ ((guess >> (bits * row)) & mask)) << (bits * row)
+                            });
+                            cipher.inv_sbox(peeled[index][0] ^ mixed_key[needed])
+                        })
+                        .collect();
+                    let sequence: Vec<u8> = cells[1..].iter().map(|cell| cell ^ cells[0]).collect();
+                    table.contains(&sequence)
+                })
+                .then(|| cipher.invert_key_expansion(&last_round_key, 7))
+        })
+    })
+}
+
+/// What the attack costs on a full-size cipher, all as log2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MitmComplexity {
+    /// Delta sequences in the precomputation table.
+    pub table_entries: f64,
+    /// Bits of memory for the table.
+    pub memory_bits: f64,
+    /// Chosen plaintexts, one structure over a diagonal.
+    pub data: f64,
+    /// Key guesses in the online phase: a diagonal of the first round key, a
+    /// shifted column of the last one and a cell of the equivalent key of
+    /// the round before, which is all that is needed to reach the
+    /// distinguished cell.
+    pub online_guesses: f64,
+}
+
+/// The complexity of the 7-round attack on `cipher` without planted cells.
+/// For AES the table alone takes 2^200 entries, more than exhausting a
+/// 128-bit key, which is why Demirci and Selçuk aim at AES-192 and AES-256
+/// and later work (Dunkelman, Keller and Shamir) cuts the parameters down
+/// with multisets and differential enumeration.
+pub fn complexity<C: CellCipher>(cipher: &C) -> MitmComplexity {
+    let bits = cipher.cell_bits() as f64;
+    let rows = cipher.num_rows() as f64;
+    let table_entries = bits * num_parameters(cipher) as f64;
+    let sequence_bits = (((1u32 << cipher.cell_bits()) - 1) * cipher.cell_bits()) as f64;
+    MitmComplexity {
+        table_entries,
+        memory_bits: table_entries + sequence_bits.log2(),
+        data: bits * rows,
+        online_guesses: bits * (2.0 * rows + 1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rijndael::Rijndael;
+    use crate::small_aes::SmallAES;
+    use rand::{thread_rng, Rng};
+
+    fn random_cells(n: usize) -> Vec<u8> {
+        (0..n).map(|_| thread_rng().gen_range(0..16)).collect()
+    }
+
+    #[test]
+    fn test_delta_sequence_matches_encryption() {
+        let key = random_cells(16);
+        let base = random_cells(16);
+        let cipher = SmallAES::new(&key, 4, 4, 5);
+        let round_keys = cipher.key_expansion(&key);
+        // Five full rounds, the last key addition undone.
+        let cells: Vec<u8> = (0..16)
+            .map(|v| {
+                let mut column = vec![0; 16];
+                column[0] = v;
+                let column = cipher.inv_mix_columns(&column);
+                let mut msg = base.clone();
+                for (row, pos) in diagonal(&cipher, 0).into_iter().enumerate() {
+                    msg[pos] = cipher.inv_sbox(column[row]) ^ key[pos];
+                }
+                let mut state = SmallAES::add_round_key(&msg, &round_keys[0]);
+                for round_key in &round_keys[1..5] {
+                    state = cipher.mix_columns(&cipher.shift_rows(&cipher.sub_bytes(&state)));
+                    state = SmallAES::add_round_key(&state, round_key);
+                }
+                cipher.mix_columns(&cipher.shift_rows(&cipher.sub_bytes(&state)))[0]
+            })
+            .collect();
+        let sequence: Vec<u8> = cells[1..].iter().map(|cell| cell ^ cells[0]).collect();
+        let params = parameters(&cipher, &round_keys, &base);
+        assert_eq!(params.len(), num_parameters(&cipher));
+        assert_eq!(delta_sequence(&cipher, &params), sequence);
+    }
+
+    #[test]
+    fn test_crack_key_mitm_seven_rounds() {
+        let key = random_cells(16);
+        let base = random_cells(16);
+        let cipher = SmallAES::new(&key, 4, 4, 7);
+        let round_keys = cipher.key_expansion(&key);
+        // Three free parameters, a table of 2^12 sequences.
+        let planted: Vec<Option<u8>> = parameters(&cipher, &round_keys, &base)
+            .into_iter()
+            .enumerate()
+            .map(|(i, param)| (i > 2).then_some(param))
+            .collect();
+        let table = precompute_table(&cipher, &planted);
+        // Two cells of the first round key and one of each of the last two
+        // round keys, in the cells reaching the distinguished one, are guessed.
+        let plant_all_but = |round_key: &[u8], unknown: &[usize]| -> Vec<Option<u8>> {
+            (0..16)
+                .map(|pos| (!unknown.contains(&pos)).then_some(round_key[pos]))
+                .collect()
+        };
+        assert_eq!(
+            crack_key_mitm_seven_rounds(
+                &cipher,
+                &|msg: &[u8]| cipher.encrypt(msg),
+                &base,
+                &table,
+                &plant_all_but(&key, &[0, 5]),
+                &plant_all_but(&round_keys[7], &[13]),
+                &plant_all_but(&cipher.inv_mix_columns(&round_keys[6]), &[0]),
+            ),
+            Some(key)
+        );
+    }
+
+    #[test]
+    fn test_complexity() {
+        let aes = Rijndael::new(&[0; 16], 4, 7);
+        let complexity = complexity(&aes);
+        assert_eq!(complexity.table_entries, 200.0);
+        assert_eq!(complexity.data, 32.0);
+        assert_eq!(complexity.online_guesses, 72.0);
+    }
+}
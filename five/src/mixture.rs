@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::{thread_rng, Rng};
+
+use crate::aes::{Block, BLOCK_SIZE};
+use crate::attack::{gen_random_block, EncryptionService};
+use crate::rijndael::{gf256_mul, inv_sbox, sbox, INV_MIX_COLUMNS, MIX_COLUMNS};
+use crate::yoyo::{anti_diagonal, diagonal};
+
+/// Texts encrypted to find ciphertext pairs agreeing on an anti-diagonal.
+/// Such a pair occurs with probability about 2^-30, so 2^17 texts give about
+/// 8 of them.
+const LOG_NUM_TEXTS: u32 = 17;
+/// Bucket counters allocated at once when counting equal values, a byte
+/// each, so 2 GiB.
+const MAX_BUCKETS: u64 = 1 << 31;
+
+/// How many ciphertext pairs agree on an anti-diagonal, and how many of the
+/// mixtures of those pairs agree on the same anti-diagonal.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MixtureStatistics {
+    pub pairs: u64,
+    pub colliding_pairs: u64,
+    pub mixtures: u64,
+    pub colliding_mixtures: u64,
+}
+
+/// Swaps the bytes of the first column in `swap` between the two texts.
+/// Every byte of a column lies in a different diagonal, so this swaps whole
+/// diagonals of the difference, like the plaintext side of the yoyo game.
+pub fn mixture((mut p0, mut p1): (Block, Block), swap: u8) -> (Block, Block) {
+    for row in (0..4).filter(|row| (swap >> row) & 1 == 1) {
+        std::mem::swap(&mut p0[row], &mut p1[row]);
+    }
+    (p0, p1)
+}
+
+fn anti_diagonal_value(block: Block, anti_diag: usize) -> u32 {
+    anti_diagonal(anti_diag)
+        .iter()
+        .fold(0, |acc, &pos| acc << 8 | block[pos] as u32)
+}
+
+/// All pairs `(i, j, anti_diag)` of ciphertexts agreeing on anti-diagonal
+/// `anti_diag`.
+fn colliding_pairs(ciphertexts: &[Block]) -> Vec<(usize, usize, usize)> {
+    let mut pairs = vec![];
+    for anti_diag in 0..4 {
+        let mut order: Vec<usize> = (0..ciphertexts.len()).collect();
+        order.sort_unstable_by_key(|&i| anti_diagonal_value(ciphertexts[i], anti_diag));
+        for run in order.chunk_by(|&i, &j| {
+            anti_diagonal_value(ciphertexts[i], anti_diag)
+                == anti_diagonal_value(ciphertexts[j], anti_diag)
+        }) {
+            for (a, &i) in run.iter().enumerate() {
+                pairs.extend(run[a + 1..].iter().map(|&j| (i, j, anti_diag)));
+            }
+        }
+    }
+    pairs
+}
+
+/// `2^log_num_texts` distinct random plaintexts which agree outside of the
+/// byte positions `active`.
+fn gen_structure(active: [usize; 4], log_num_texts: u32) -> Vec<Block> {
+    let base = gen_random_block();
+    let mut values = HashSet::new();
+    while values.len() < 1 << log_num_texts {
+        values.insert(thread_rng().gen::<u32>());
+    }
+    values
+        .into_iter()
+        .map(|value| {
+            let mut msg = base;
+            for (pos, byte) in active.into_iter().zip(value.to_be_bytes()) {
+                msg[pos] = byte;
+            }
+            msg
+        })
+        .collect()
+}
+
+/// Encrypts `2^log_num_texts` plaintexts differing in the first column, at
+/// most 2^32, and for every pair of them agreeing on an anti-diagonal of the
+/// ciphertext, checks whether its 7 nontrivial mixtures agree on the same
+/// anti-diagonal.
+///
+/// Grassi's mixture differential: four rounds of AES (without the last
+/// MixColumns) are two super-box layers, and mixing whole diagonals keeps the
+/// difference between the layers, so on 4 rounds every mixture of a
+/// colliding pair collides. For more rounds or a random permutation this
+/// happens with probability about 2^-32.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn mixture_statistics<E: EncryptionService>(
+    encryption_service: &E,
+    log_num_texts: u32,
+) -> MixtureStatistics {
+    assert!(log_num_texts <= 32, "the structure only varies 4 bytes");
+    let plaintexts = gen_structure([0, 1, 2, 3], log_num_texts);
+    let ciphertexts: Vec<Block> = plaintexts
+        .iter()
+        .map(|&msg| encryption_service.encrypt(msg))
+        .collect();
+
+    let mut statistics = MixtureStatistics {
+        pairs: plaintexts.len() as u64 * (plaintexts.len() as u64 - 1) / 2,
+        ..Default::default()
+    };
+    for (i, j, anti_diag) in colliding_pairs(&ciphertexts) {
+        statistics.colliding_pairs += 1;
+        for swap in 1..8 {
+            let (p0, p1) = mixture((plaintexts[i], plaintexts[j]), swap);
+            let (c0, c1) = (
+                encryption_service.encrypt(p0),
+                encryption_service.encrypt(p1),
+            );
+            statistics.mixtures += 1;
+            if anti_diagonal_value(c0, anti_diag) == anti_diagonal_value(c1, anti_diag) {
+                statistics.colliding_mixtures += 1;
+            }
+        }
+    }
+    statistics
+}
+
+/// The 4-round mixture distinguisher, repeated until some pair collides.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn is_four_round_aes<E: EncryptionService>(encryption_service: &E) -> bool {
+    loop {
+        let statistics = mixture_statistics(encryption_service, LOG_NUM_TEXTS);
+        if statistics.colliding_pairs > 0 {
+            return statistics.colliding_mixtures == statistics.mixtures;
+        }
+    }
+}
+
+/// Counts the pairs among `num_texts` texts whose `value_bits`-bit values
+/// are equal. Wide values take several passes, each counting one range of
+/// values and calling `value` for every text again, so that the counters
+/// fit in memory. The counters are bytes, and the few values shared by more
+/// than 255 texts are counted on in a map.
+pub fn count_equal_pairs<F: FnMut(u64) -> u32>(
+    num_texts: u64,
+    value_bits: u32,
+    mut value: F,
+) -> u64 {
+    let num_buckets = (1u64 << value_bits).min(MAX_BUCKETS);
+    let mut pairs = 0;
+    for pass in 0..(1u64 << value_bits) / num_buckets {
+        let mut counts = vec![0u8; num_buckets as usize];
+        let mut saturated: HashMap<usize, u64> = HashMap::new();
+        for i in 0..num_texts {
+            let value = value(i) as u64;
+            if value / num_buckets == pass {
+                let bucket = (value % num_buckets) as usize;
+                match counts[bucket].checked_add(1) {
+                    Some(count) => counts[bucket] = count,
+                    None => *saturated.entry(bucket).or_insert(u8::MAX as u64) += 1,
+                }
+            }
+        }
+        let pairs_among = |n: u64| n * n.saturating_sub(1) / 2;
+        pairs += counts.iter().map(|&n| pairs_among(n as u64)).sum::<u64>();
+        pairs += saturated
+            .into_values()
+            .map(|n| pairs_among(n) - pairs_among(u8::MAX as u64))
+            .sum::<u64>();
+    }
+    pairs
+}
+
+/// The number of pairs in a random coset of diagonal `diag`, all 2^32
+/// plaintexts, whose ciphertexts agree on anti-diagonal `anti_diag`.
+///
+/// By the multiple-of-8 property of Grassi, Rechberger and Rønjom this is a
+/// multiple of 8 for 5 rounds of AES, while for a random permutation it is
+/// one with probability 1/8. Counting it takes 2^32 counters, so with
+/// `MAX_BUCKETS` of them in memory it takes two passes over the coset, 2^33
+/// encryptions.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn multiple_of_eight_pairs<E: EncryptionService>(
+    encryption_service: &E,
+    diag: usize,
+    anti_diag: usize,
+) -> u64 {
+    let base = gen_random_block();
+    count_equal_pairs(1 << 32, 32, |i| {
+        let mut msg = base;
+        for (pos, byte) in diagonal(diag).into_iter().zip((i as u32).to_be_bytes()) {
+            msg[pos] = byte;
+        }
+        anti_diagonal_value(encryption_service.encrypt(msg), anti_diag)
+    })
+}
+
+struct Tables {
+    sbox: [u8; 256],
+    inv_sbox: [u8; 256],
+    /// Multiplication by 0, 1, 2 and 3.
+    mul: [[u8; 256]; 4],
+    mix_columns: [[usize; 4]; 4],
+}
+
+impl Tables {
+    fn new() -> Self {
+        Self {
+            sbox: sbox(),
+            inv_sbox: inv_sbox(),
+            mul: std::array::from_fn(|a| std::array::from_fn(|b| gf256_mul(a as u8, b as u8))),
+            mix_columns: std::array::from_fn(|row| {
+                std::array::from_fn(|i| MIX_COLUMNS[(4 + i - row) % 4] as usize)
+            }),
+        }
+    }
+}
+
+/// The mixture of `pair` after the first round, when diagonal `diag` of the
+/// key is `key_diagonal`: the two plaintexts of the diagonal coset whose
+/// first-round outputs are those of `pair` with the bytes in `swap` swapped.
+/// The other round keys only add the same constant to both texts.
+///
+/// None if the first-round outputs agree in some byte, since then some
+/// mixtures are the pair itself and collide under any key.
+fn first_round_mixture(
+    tables: &Tables,
+    (p0, p1): (Block, Block),
+    diag: usize,
+    key_diagonal: [u8; 4],
+    swap: u8,
+) -> Option<(Block, Block)> {
+    let positions = diagonal(diag);
+    let s0: [u8; 4] =
+        std::array::from_fn(|i| tables.sbox[(p0[positions[i]] ^ key_diagonal[i]) as usize]);
+    let s1: [u8; 4] =
+        std::array::from_fn(|i| tables.sbox[(p1[positions[i]] ^ key_diagonal[i]) as usize]);
+    let output_difference: [u8; 4] = std::array::from_fn(|row| {
+        (0..4).fold(0, |acc, i| {
+            acc ^ tables.mul[tables.mix_columns[row][i]][(s0[i] ^ s1[i]) as usize]
+        })
+    });
+    if output_difference.contains(&0) {
+        return None;
+    }
+    // Swapping a byte of MixColumns(s0) and MixColumns(s1) adds the same
+    // column to both, InvMixColumns of the byte difference.
+    let mut difference = [0; 4];
+    for row in (0..4).filter(|row| (swap >> row) & 1 == 1) {
+        for (i, difference) in difference.iter_mut().enumerate() {
+            *difference ^= gf256_mul(INV_MIX_COLUMNS[(4 + row - i) % 4], output_difference[row]);
+        }
+    }
+    let (mut q0, mut q1) = (p0, p1);
+    for (i, &pos) in positions.iter().enumerate() {
+        q0[pos] = tables.inv_sbox[(s0[i] ^ difference[i]) as usize] ^ key_diagonal[i];
+        q1[pos] = tables.inv_sbox[(s1[i] ^ difference[i]) as usize] ^ key_diagonal[i];
+    }
+    Some((q0, q1))
+}
+
+/// Recovers diagonal `diag` of the key of 5-round AES. A pair from a coset
+/// of the diagonal whose ciphertexts agree on an anti-diagonal is found by
+/// the birthday paradox; the last four rounds are the 4-round mixture
+/// distinguisher, so under the right key guess every first-round mixture of
+/// the pair agrees on that anti-diagonal as well. Each guess costs at most 14
+/// encryptions, and almost always two. Bytes of the diagonal given in
+/// `planted` are not guessed.
+///
+/// With probability about 2^-6 the first-round outputs of the pair agree in
+/// a byte, no guess is accepted and the next pair is tried.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_diagonal<E: EncryptionService>(
+    encryption_service: &E,
+    diag: usize,
+    planted: [Option<u8>; 4],
+) -> [u8; 4] {
+    let tables = Tables::new();
+    let free: Vec<usize> = (0..4).filter(|&i| planted[i].is_none()).collect();
+    let guesses = || {
+        (0..1u64 << (8 * free.len())).map(|value| {
+            let mut key_diagonal = planted.map(|byte| byte.unwrap_or(0));
+            for (j, &i) in free.iter().enumerate() {
+                key_diagonal[i] = (value >> (8 * j)) as u8;
+            }
+            key_diagonal
+        })
+    };
+    loop {
+        let plaintexts = gen_structure(diagonal(diag), LOG_NUM_TEXTS);
+        let ciphertexts: Vec<Block> = plaintexts
+            .iter()
+            .map(|&msg| encryption_service.encrypt(msg))
+            .collect();
+        for (i, j, anti_diag) in colliding_pairs(&ciphertexts) {
+            let pair = (plaintexts[i], plaintexts[j]);
+            let collides = |key_diagonal: [u8; 4], swap: u8| {
+                first_round_mixture(&tables, pair, diag, key_diagonal, swap).is_some_and(
+                    |(p0, p1)| {
+                        anti_diagonal_value(encryption_service.encrypt(p0), anti_diag)
+                            == anti_diagonal_value(encryption_service.encrypt(p1), anti_diag)
+                    },
+                )
+            };
+            if let Some(key_diagonal) =
+                guesses().find(|&key_diagonal| (1..8).all(|swap| collides(key_diagonal, swap)))
+            {
+                return key_diagonal;
+            }
+        }
+    }
+}
+
+/// The mixture differential key recovery on 5-round AES, one diagonal of the
+/// key at a time. With 2^17 chosen plaintexts and up to 2^33 adaptively
+/// chosen ones per diagonal it trades data for memory against Grassi's
+/// original attack, which encrypts each coset once and looks the mixtures up.
+/// Without planted key bytes that is about 2^35 encryptions in all, several
+/// minutes even with AES-NI; every byte in `planted` divides the guesses of
+/// its diagonal by 256.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_key_mixture<E: EncryptionService>(
+    encryption_service: &E,
+    planted: [Option<u8>; BLOCK_SIZE],
+) -> Block {
+    let mut key = [0; BLOCK_SIZE];
+    for diag in 0..4 {
+        let positions = diagonal(diag);
+        let key_diagonal =
+            crack_diagonal(encryption_service, diag, positions.map(|pos| planted[pos]));
+        for (pos, byte) in positions.into_iter().zip(key_diagonal) {
+            key[pos] = byte;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+    use crate::small_aes::SmallAES;
+
+    const KEY: Block = *b"sixteen byte key";
+
+    #[test]
+    fn test_mixture() {
+        let (p0, p1) = ([0; BLOCK_SIZE], [1; BLOCK_SIZE]);
+        let (q0, q1) = mixture((p0, p1), 0b0101);
+        assert_eq!(q0[..5], [1, 0, 1, 0, 0]);
+        assert_eq!(q1[..5], [0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_four_round_distinguisher() {
+        unsafe {
+            let aes = AES128::new(KEY, 4);
+            assert!(is_four_round_aes(&aes));
+            let aes = AES128::new(KEY, 5);
+            assert!(!is_four_round_aes(&aes));
+        }
+    }
+
+    #[test]
+    fn test_mixture_statistics_single_text() {
+        unsafe {
+            let aes = AES128::new(KEY, 4);
+            assert_eq!(mixture_statistics(&aes, 0), MixtureStatistics::default());
+        }
+    }
+
+    #[test]
+    fn test_crack_key_mixture() {
+        unsafe {
+            let aes = AES128::new(KEY, 5);
+            // One byte of every diagonal is guessed, 2^8 guesses each.
+            let planted = std::array::from_fn(|pos| (pos >= 4).then_some(KEY[pos]));
+            assert_eq!(crack_key_mixture(&aes, planted), KEY);
+        }
+    }
+
+    #[test]
+    fn test_first_round_mixture() {
+        unsafe {
+            let aes = AES128::new(KEY, 5);
+            let tables = Tables::new();
+            let plaintexts = gen_structure(diagonal(1), LOG_NUM_TEXTS);
+            let ciphertexts: Vec<Block> = plaintexts.iter().map(|&msg| aes.encrypt(msg)).collect();
+            let key_diagonal = diagonal(1).map(|pos| KEY[pos]);
+            let mut wrong_key_diagonal = key_diagonal;
+            wrong_key_diagonal[2] ^= 1;
+            // Skip pairs whose first-round outputs agree in a byte under
+            // either key.
+            let (mixtures, anti_diag) = colliding_pairs(&ciphertexts)
+                .into_iter()
+                .find_map(|(i, j, anti_diag)| {
+                    let pair = (plaintexts[i], plaintexts[j]);
+                    let mixture =
+                        |key_diagonal| first_round_mixture(&tables, pair, 1, key_diagonal, 0b0110);
+                    Some((
+                        [mixture(key_diagonal)?, mixture(wrong_key_diagonal)?],
+                        anti_diag,
+                    ))
+                })
+                .unwrap();
+            let collides = mixtures.map(|(p0, p1)| {
+                anti_diagonal_value(aes.encrypt(p0), anti_diag)
+                    == anti_diagonal_value(aes.encrypt(p1), anti_diag)
+            });
+            assert_eq!(collides, [true, false]);
+        }
+    }
+
+    #[test]
+    fn test_multiple_of_eight_small_scale() {
+        // The property holds for small-scale AES, where a diagonal coset has
+        // only 2^16 texts.
+        let key: Vec<u8> = (0..16).map(|i| (7 * i + 3) % 16).collect();
+        let base: Vec<u8> = (0..16).map(|i| (5 * i + 1) % 16).collect();
+        let counts = |num_rounds| {
+            let cipher = SmallAES::new(&key, 4, 4, num_rounds);
+            let ciphertexts: Vec<Vec<u8>> = (0..1 << 16)
+                .map(|i: usize| {
+                    let mut msg = base.clone();
+                    for (row, pos) in diagonal(0).into_iter().enumerate() {
+                        msg[pos] = (i >> (4 * row)) as u8 & 15;
+                    }
+                    cipher.encrypt(&msg)
+                })
+                .collect();
+            (0..4)
+                .map(|anti_diag| {
+                    count_equal_pairs(1 << 16, 16, |i| {
+                        anti_diagonal(anti_diag).iter().fold(0, |acc, &pos| {
+                            acc << 4 | ciphertexts[i as usize][pos] as u32
+                        })
+                    })
+                })
+                .collect::<Vec<u64>>()
+        };
+        assert!(counts(5).iter().all(|count| count % 8 == 0));
+        assert!(counts(6).iter().any(|count| count % 8 != 0));
+    }
+
+    #[test]
+    fn test_count_equal_pairs() {
+        assert_eq!(count_equal_pairs(256, 8, |_| 0), 256 * 255 / 2);
+        assert_eq!(count_equal_pairs(600, 8, |i| i as u32 % 2), 300 * 299);
+        assert_eq!(count_equal_pairs(256, 8, |i| i as u32), 0);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::aes::{Block, AES128, BLOCK_SIZE};
+
+pub const GCM_NONCE_SIZE: usize = 12;
+
+pub fn pad(msg: &[u8]) -> Vec<u8> {
+    let padding = BLOCK_SIZE - msg.len() % BLOCK_SIZE;
+    let mut padded = msg.to_vec();
+    padded.resize(msg.len() + padding, padding as u8);
+    padded
+}
+
+pub fn unpad(padded: &[u8]) -> Option<Vec<u8>> {
+    if padded.is_empty() || !padded.len().is_multiple_of(BLOCK_SIZE) {
+        return None;
+    }
+    let padding = *padded.last().unwrap() as usize;
+    if padding == 0 || padding > BLOCK_SIZE {
+        return None;
+    }
+    let (msg, tail) = padded.split_at(padded.len() - padding);
+    if tail.iter().any(|&b| b as usize != padding) {
+        return None;
+    }
+    Some(msg.to_vec())
+}
+
+fn xor_blocks(a: Block, b: Block) -> Block {
+    let mut res = a;
+    for (x, y) in res.iter_mut().zip(b) {
+        *x ^= y;
+    }
+    res
+}
+
+fn to_block(chunk: &[u8]) -> Block {
+    chunk.try_into().unwrap()
+}
+
+fn increment(counter: Block) -> Block {
+    u128::from_be_bytes(counter).wrapping_add(1).to_be_bytes()
+}
+
+/// Increments only the last 32 bits of the counter block, as GCM does.
+fn increment_32(counter: Block) -> Block {
+    let mut res = counter;
+    let low = u32::from_be_bytes(counter[12..].try_into().unwrap()).wrapping_add(1);
+    res[12..].copy_from_slice(&low.to_be_bytes());
+    res
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn ecb_encrypt(aes: &AES128, msg: &[u8]) -> Vec<u8> {
+    pad(msg)
+        .chunks(BLOCK_SIZE)
+        .flat_map(|chunk| aes.encrypt(to_block(chunk)))
+        .collect()
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn ecb_decrypt(aes: &AES128, enc_msg: &[u8]) -> Option<Vec<u8>> {
+    if !enc_msg.len().is_multiple_of(BLOCK_SIZE) {
+        return None;
+    }
+    let padded: Vec<_> = enc_msg
+        .chunks(BLOCK_SIZE)
+        .flat_map(|chunk| aes.decrypt(to_block(chunk)))
+        .collect();
+    unpad(&padded)
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn cbc_encrypt(aes: &AES128, iv: Block, msg: &[u8]) -> Vec<u8> {
+    let mut prev = iv;
+    let mut enc_msg = vec![];
+    for chunk in pad(msg).chunks(BLOCK_SIZE) {
+        prev = aes.encrypt(xor_blocks(to_block(chunk), prev));
+        enc_msg.extend(prev);
+    }
+    enc_msg
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn cbc_decrypt(aes: &AES128, iv: Block, enc_msg: &[u8]) -> Option<Vec<u8>> {
+    if !enc_msg.len().is_multiple_of(BLOCK_SIZE) {
+        return None;
+    }
+    let mut prev = iv;
+    let mut padded = vec![];
+    for chunk in enc_msg.chunks(BLOCK_SIZE) {
+        let block = to_block(chunk);
+        padded.extend(xor_blocks(aes.decrypt(block), prev));
+        prev = block;
+    }
+    unpad(&padded)
+}
+
+/// XORs `data` with the keystream starting at the given counter block. The
+/// whole block is treated as a big-endian counter, so CTR encryption and
+/// decryption are the same operation.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn ctr(aes: &AES128, initial_counter: Block, data: &[u8]) -> Vec<u8> {
+    apply_keystream(aes, initial_counter, data, increment)
+}
+
+#[target_feature(enable = "avx2,aes")]
+unsafe fn apply_keystream(
+    aes: &AES128,
+    initial_counter: Block,
+    data: &[u8],
+    next_counter: fn(Block) -> Block,
+) -> Vec<u8> {
+    let mut counter = initial_counter;
+    let mut res = vec![];
+    for chunk in data.chunks(BLOCK_SIZE) {
+        let keystream = aes.encrypt(counter);
+        res.extend(chunk.iter().zip(keystream).map(|(x, k)| x ^ k));
+        counter = next_counter(counter);
+    }
+    res
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn cfb_encrypt(aes: &AES128, iv: Block, msg: &[u8]) -> Vec<u8> {
+    let mut prev = iv;
+    let mut enc_msg = vec![];
+    for chunk in msg.chunks(BLOCK_SIZE) {
+        let keystream = aes.encrypt(prev);
+        let enc_chunk: Vec<_> = chunk.iter().zip(keystream).map(|(x, k)| x ^ k).collect();
+        if enc_chunk.len() == BLOCK_SIZE {
+            prev = to_block(&enc_chunk);
+        }
+        enc_msg.extend(enc_chunk);
+    }
+    enc_msg
+}
+
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn cfb_decrypt(aes: &AES128, iv: Block, enc_msg: &[u8]) -> Vec<u8> {
+    let mut prev = iv;
+    let mut msg = vec![];
+    for chunk in enc_msg.chunks(BLOCK_SIZE) {
+        let keystream = aes.encrypt(prev);
+        msg.extend(chunk.iter().zip(keystream).map(|(x, k)| x ^ k));
+        if chunk.len() == BLOCK_SIZE {
+            prev = to_block(chunk);
+        }
+    }
+    msg
+}
+
+/// OFB encryption and decryption are the same operation.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn ofb(aes: &AES128, iv: Block, data: &[u8]) -> Vec<u8> {
+    let mut keystream = iv;
+    let mut res = vec![];
+    for chunk in data.chunks(BLOCK_SIZE) {
+        keystream = aes.encrypt(keystream);
+        res.extend(chunk.iter().zip(keystream).map(|(x, k)| x ^ k));
+    }
+    res
+}
+
+/// Multiplication in GF(2^128) with the bit ordering used by GHASH.
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        if (x >> i) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
+
+fn ghash(h: Block, aad: &[u8], enc_msg: &[u8]) -> Block {
+    let h = u128::from_be_bytes(h);
+    let mut y = 0;
+    for data in [aad, enc_msg] {
+        for chunk in data.chunks(BLOCK_SIZE) {
+            let mut block = [0; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            y = gf128_mul(y ^ u128::from_be_bytes(block), h);
+        }
+    }
+    let lengths = ((aad.len() as u128 * 8) << 64) | (enc_msg.len() as u128 * 8);
+    gf128_mul(y ^ lengths, h).to_be_bytes()
+}
+
+fn gcm_initial_counter(nonce: [u8; GCM_NONCE_SIZE]) -> Block {
+    let mut counter = [0; BLOCK_SIZE];
+    counter[..GCM_NONCE_SIZE].copy_from_slice(&nonce);
+    counter[BLOCK_SIZE - 1] = 1;
+    counter
+}
+
+#[target_feature(enable = "avx2,aes")]
+unsafe fn gcm_tag(aes: &AES128, initial_counter: Block, aad: &[u8], enc_msg: &[u8]) -> Block {
+    let h = aes.encrypt([0; BLOCK_SIZE]);
+    xor_blocks(aes.encrypt(initial_counter), ghash(h, aad, enc_msg))
+}
+
+/// Returns the ciphertext together with the authentication tag.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn gcm_encrypt(
+    aes: &AES128,
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+    msg: &[u8],
+) -> (Vec<u8>, Block) {
+    let initial_counter = gcm_initial_counter(nonce);
+    let enc_msg = apply_keystream(aes, increment_32(initial_counter), msg, increment_32);
+    let tag = gcm_tag(aes, initial_counter, aad, &enc_msg);
+    (enc_msg, tag)
+}
+
+/// Returns `None` if the tag does not authenticate the ciphertext and `aad`.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn gcm_decrypt(
+    aes: &AES128,
+    nonce: [u8; GCM_NONCE_SIZE],
+    aad: &[u8],
+    enc_msg: &[u8],
+    tag: Block,
+) -> Option<Vec<u8>> {
+    let initial_counter = gcm_initial_counter(nonce);
+    if gcm_tag(aes, initial_counter, aad, enc_msg) != tag {
+        return None;
+    }
+    Some(apply_keystream(
+        aes,
+        increment_32(initial_counter),
+        enc_msg,
+        increment_32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attack::crack_key_four_rounds;
+    use crate::test_util::decode_hex;
+
+    fn nist_aes() -> AES128 {
+        unsafe {
+            AES128::new(
+                to_block(&decode_hex("2b7e151628aed2a6abf7158809cf4f3c")),
+                10,
+            )
+        }
+    }
+
+    const NIST_MSG: &str = "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51";
+
+    #[test]
+    fn test_pad_unpad() {
+        for len in 0..=2 * BLOCK_SIZE {
+            let msg = vec![0xaa; len];
+            let padded = pad(&msg);
+            assert_eq!(padded.len() % BLOCK_SIZE, 0);
+            assert!(padded.len() > len);
+            assert_eq!(unpad(&padded), Some(msg));
+        }
+        assert_eq!(unpad(&[0; BLOCK_SIZE]), None);
+        assert_eq!(unpad(&[3; BLOCK_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn test_ecb() {
+        unsafe {
+            let aes = nist_aes();
+            let msg = decode_hex(NIST_MSG);
+            let enc_msg = ecb_encrypt(&aes, &msg);
+            assert_eq!(
+                enc_msg[..2 * BLOCK_SIZE],
+                decode_hex("3ad77bb40d7a3660a89ecaf32466ef97f5d3d58503b9699de785895a96fdbaaf")
+            );
+            assert_eq!(ecb_decrypt(&aes, &enc_msg), Some(msg));
+        }
+    }
+
+    #[test]
+    fn test_cbc() {
+        unsafe {
+            let aes = nist_aes();
+            let iv = to_block(&decode_hex("000102030405060708090a0b0c0d0e0f"));
+            let msg = decode_hex(NIST_MSG);
+            let enc_msg = cbc_encrypt(&aes, iv, &msg);
+            assert_eq!(
+                enc_msg[..2 * BLOCK_SIZE],
+                decode_hex("7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2")
+            );
+            assert_eq!(cbc_decrypt(&aes, iv, &enc_msg), Some(msg));
+        }
+    }
+
+    #[test]
+    fn test_ctr() {
+        unsafe {
+            let aes = nist_aes();
+            let counter = to_block(&decode_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+            let msg = decode_hex(NIST_MSG);
+            let enc_msg = ctr(&aes, counter, &msg);
+            assert_eq!(
+                enc_msg,
+                decode_hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff")
+            );
+            assert_eq!(ctr(&aes, counter, &enc_msg[..20]), msg[..20]);
+        }
+    }
+
+    #[test]
+    fn test_cfb() {
+        unsafe {
+            let aes = nist_aes();
+            let iv = to_block(&decode_hex("000102030405060708090a0b0c0d0e0f"));
+            let msg = decode_hex(NIST_MSG);
+            let enc_msg = cfb_encrypt(&aes, iv, &msg);
+            assert_eq!(
+                enc_msg,
+                decode_hex("3b3fd92eb72dad20333449f8e83cfb4ac8a64537a0b3a93fcde3cdad9f1ce58b")
+            );
+            assert_eq!(cfb_decrypt(&aes, iv, &enc_msg[..20]), msg[..20]);
+        }
+    }
+
+    #[test]
+    fn test_ofb() {
+        unsafe {
+            let aes = nist_aes();
+            let iv = to_block(&decode_hex("000102030405060708090a0b0c0d0e0f"));
+            let msg = decode_hex(NIST_MSG);
+            let enc_msg = ofb(&aes, iv, &msg);
+            assert_eq!(
+                enc_msg,
+                decode_hex("3b3fd92eb72dad20333449f8e83cfb4a7789508d16918f03f53c52dac54ed825")
+            );
+            assert_eq!(ofb(&aes, iv, &enc_msg[..20]), msg[..20]);
+        }
+    }
+
+    #[test]
+    fn test_gcm() {
+        unsafe {
+            let aes = AES128::new(
+                to_block(&decode_hex("feffe9928665731c6d6a8f9467308308")),
+                10,
+            );
+            let nonce = decode_hex("cafebabefacedbaddecaf888").try_into().unwrap();
+            let aad = decode_hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+            let msg = decode_hex(
+                "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                 1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+            );
+            let (enc_msg, tag) = gcm_encrypt(&aes, nonce, &aad, &msg);
+            assert_eq!(
+                enc_msg,
+                decode_hex(
+                    "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+                     21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091"
+                )
+            );
+            assert_eq!(tag.to_vec(), decode_hex("5bc94fbc3221a5db94fae95ae7121a47"));
+            assert_eq!(gcm_decrypt(&aes, nonce, &aad, &enc_msg, tag), Some(msg));
+            assert_eq!(gcm_decrypt(&aes, nonce, &[], &enc_msg, tag), None);
+        }
+    }
+
+    #[test]
+    fn test_crack_key_through_ctr() {
+        unsafe {
+            let key = "sixteen byte key"
+                .bytes()
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let aes = AES128::new(key, 4);
+            // Encrypting zeros under a chosen nonce leaks the keystream block,
+            // i.e. the encryption of the nonce itself.
+            let keystream = |nonce: Block| to_block(&ctr(&aes, nonce, &[0; BLOCK_SIZE]));
+            assert_eq!(crack_key_four_rounds(&keystream), key);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use crate::generic_attack::CellCipher;
+
+/// x^8 + x^4 + x^3 + x + 1
+const MODULUS: u16 = 0x11b;
+/// The first row of the MixColumns matrix of AES, row `r` being it rotated
+/// right by `r`.
+pub const MIX_COLUMNS: [u8; 4] = [2, 3, 1, 1];
+pub const INV_MIX_COLUMNS: [u8; 4] = [0xe, 0xb, 0xd, 0x9];
+
+/// Multiplication in the field of AES.
+pub fn gf256_mul(a: u8, b: u8) -> u8 {
+    let (mut a, mut b, mut res) = (a as u16, b, 0);
+    while b != 0 {
+        if b & 1 == 1 {
+            res ^= a;
+        }
+        a <<= 1;
+        if a & 0x100 != 0 {
+            a ^= MODULUS;
+        }
+        b >>= 1;
+    }
+    res as u8
+}
+
+/// The multiplicative inverse of `a` in the field of `order` elements
+/// multiplied by `mul`, with 0 mapped to 0. The helpers below take the field
+/// this way so that the variants with other fields share them.
+pub fn field_inv<F: Fn(u8, u8) -> u8>(a: u8, order: usize, mul: F) -> u8 {
+    (1..order)
+        .map(|b| b as u8)
+        .find(|&b| mul(a, b) == 1)
+        .unwrap_or(0)
+}
+
+/// The multiplicative inverse in the field of AES, with 0 mapped to 0.
+pub fn gf256_inv(a: u8) -> u8 {
+    field_inv(a, 256, gf256_mul)
+}
+
+/// The round constants of the key schedule, the first `count` powers of x in
+/// the field multiplied by `mul`.
+pub fn round_constants<F: Fn(u8, u8) -> u8>(count: usize, mul: F) -> Vec<u8> {
+    (0..count)
+        .scan(1, |rcon, _| {
+            let cur = *rcon;
+            *rcon = mul(cur, 2);
+            Some(cur)
+        })
+        .collect()
+}
+
+/// Inverts a square matrix over the field of `order` elements multiplied by
+/// `mul`. Returns `None` if it is singular.
+pub fn invert_matrix<F: Fn(u8, u8) -> u8>(
+    matrix: &[Vec<u8>],
+    order: usize,
+    mul: F,
+) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut rows: Vec<Vec<u8>> = matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut row = row.clone();
+            row.extend((0..n).map(|j| (i == j) as u8));
+            row
+        })
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&i| rows[i][col] != 0)?;
+        rows.swap(col, pivot);
+        let inv = field_inv(rows[col][col], order, &mul);
+        rows[col] = rows[col].iter().map(|&x| mul(x, inv)).collect();
+        for i in 0..n {
+            if i != col && rows[i][col] != 0 {
+                let factor = rows[i][col];
+                let pivot_row = rows[col].clone();
+                for (x, p) in rows[i].iter_mut().zip(pivot_row) {
+                    *x ^= mul(factor, p);
+                }
+            }
+        }
+    }
+    Some(rows.into_iter().map(|row| row[n..].to_vec()).collect())
+}
+
+/// The S-box of AES: inversion in GF(2^8) followed by an affine map.
+pub fn sbox() -> [u8; 256] {
+    std::array::from_fn(|x| {
+        let inv = gf256_inv(x as u8);
+        (1..5).fold(0x63 ^ inv, |acc, i| acc ^ inv.rotate_left(i))
+    })
+}
+
+/// The inverse of the S-box of AES.
+pub fn inv_sbox() -> [u8; 256] {
+    invert_sbox(&sbox())
+}
+
+/// The inverse of an S-box given as a table, which must be a permutation.
+pub fn invert_sbox<const N: usize>(sbox: &[u8; N]) -> [u8; N] {
+    let mut inv_sbox = [0; N];
+    for (x, &y) in sbox.iter().enumerate() {
+        inv_sbox[y as usize] = x as u8;
+    }
+    inv_sbox
+}
+
+/// Rijndael with a block of 4, 6 or 8 columns, i.e. 128, 192 or 256 bits, and
+/// a key of 4, 6 or 8 columns. AES is the special case of 4 block columns.
+/// The state is stored column by column like an AES block, and the offsets of
+/// ShiftRows depend on the block size.
+pub struct Rijndael {
+    num_cols: usize,
+    key_cols: usize,
+    num_rounds: usize,
+    round_keys: Vec<Vec<u8>>,
+    sbox: [u8; 256],
+    inv_sbox: [u8; 256],
+}
+
+impl Rijndael {
+    /// The full cipher has `max(num_cols, key.len() / 4) + 6` rounds.
+    pub fn new(key: &[u8], num_cols: usize, num_rounds: usize) -> Self {
+        assert!(matches!(num_cols, 4 | 6 | 8), "unsupported block size");
+        assert!(matches!(key.len(), 16 | 24 | 32), "unsupported key size");
+        let sbox = sbox();
+        let inv_sbox = invert_sbox(&sbox);
+        let mut cipher = Self {
+            num_cols,
+            key_cols: key.len() / 4,
+            num_rounds,
+            round_keys: vec![],
+            sbox,
+            inv_sbox,
+        };
+        cipher.round_keys = cipher.key_expansion(key);
+        cipher
+    }
+
+    pub fn block_size(&self) -> usize {
+        4 * self.num_cols
+    }
+
+    /// ShiftRows rotates row `i` by `shift_offsets()[i]` positions to the left.
+    pub fn shift_offsets(&self) -> [usize; 4] {
+        match self.num_cols {
+            8 => [0, 1, 3, 4],
+            _ => [0, 1, 2, 3],
+        }
+    }
+
+    /// The value XORed onto word `i - key_cols` to obtain word `i` of the
+    /// expanded key, given word `i - 1`.
+    fn schedule_core(&self, prev: [u8; 4], i: usize, rcons: &[u8]) -> [u8; 4] {
+        let sub_word = |word: [u8; 4]| word.map(|x| self.sbox[x as usize]);
+        if i.is_multiple_of(self.key_cols) {
+            let mut word = sub_word([prev[1], prev[2], prev[3], prev[0]]);
+            word[0] ^= rcons[i / self.key_cols - 1];
+            word
+        } else if self.key_cols > 6 && i % self.key_cols == 4 {
+            sub_word(prev)
+        } else {
+            prev
+        }
+    }
+
+    fn word(words: &[u8], i: usize) -> [u8; 4] {
+        words[4 * i..4 * i + 4].try_into().unwrap()
+    }
+
+    pub fn key_expansion(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let num_words = self.num_cols * (self.num_rounds + 1);
+        let rcons = round_constants(num_words / self.key_cols, gf256_mul);
+        let mut words = key.to_vec();
+        for i in self.key_cols..num_words.max(self.key_cols) {
+            let core = self.schedule_core(Self::word(&words, i - 1), i, &rcons);
+            let word = Self::word(&words, i - self.key_cols);
+            words.extend(word.iter().zip(core).map(|(x, y)| x ^ y));
+        }
+        words.truncate(4 * num_words);
+        words
+            .chunks(self.block_size())
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// Walks the key schedule backwards from the round key of round `round`,
+    /// which is possible whenever the key is no wider than the block.
+    pub fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8> {
+        assert!(self.key_cols <= self.num_cols);
+        let first = self.num_cols * round;
+        let rcons = round_constants((first + self.num_cols) / self.key_cols, gf256_mul);
+        let mut words = vec![0; 4 * first];
+        words.extend_from_slice(round_key);
+        for i in (self.key_cols..first + self.num_cols).rev() {
+            if i < first + self.key_cols {
+                let core = self.schedule_core(Self::word(&words, i - 1), i, &rcons);
+                for (j, y) in core.into_iter().enumerate() {
+                    words[4 * (i - self.key_cols) + j] = words[4 * i + j] ^ y;
+                }
+            }
+        }
+        words.truncate(4 * self.key_cols);
+        words
+    }
+
+    pub fn sub_bytes(&self, state: &[u8]) -> Vec<u8> {
+        state.iter().map(|&x| self.sbox[x as usize]).collect()
+    }
+
+    pub fn inv_sub_bytes(&self, state: &[u8]) -> Vec<u8> {
+        state.iter().map(|&x| self.inv_sbox[x as usize]).collect()
+    }
+
+    pub fn shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        let offsets = self.shift_offsets();
+        (0..self.block_size())
+            .map(|i| {
+                let (row, col) = (i % 4, i / 4);
+                state[row + 4 * ((col + offsets[row]) % self.num_cols)]
+            })
+            .collect()
+    }
+
+    pub fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        let offsets = self.shift_offsets();
+        (0..self.block_size())
+            .map(|i| {
+                let (row, col) = (i % 4, i / 4);
+                state[row + 4 * ((col + self.num_cols - offsets[row]) % self.num_cols)]
+            })
+            .collect()
+    }
+
+    fn multiply_columns(state: &[u8], coefficients: [u8; 4]) -> Vec<u8> {
+        state
+            .chunks(4)
+            .flat_map(|column| {
+                (0..4).map(move |row| {
+                    (0..4).fold(0, |acc, k| {
+                        acc ^ gf256_mul(column[k], coefficients[(4 + k - row) % 4])
+                    })
+                })
+            })
+            .collect()
+    }
+
+    pub fn mix_columns(state: &[u8]) -> Vec<u8> {
+        Self::multiply_columns(state, MIX_COLUMNS)
+    }
+
+    pub fn inv_mix_columns(state: &[u8]) -> Vec<u8> {
+        Self::multiply_columns(state, INV_MIX_COLUMNS)
+    }
+
+    pub fn add_round_key(state: &[u8], round_key: &[u8]) -> Vec<u8> {
+        state.iter().zip(round_key).map(|(x, k)| x ^ k).collect()
+    }
+
+    pub fn encrypt(&self, msg: &[u8]) -> Vec<u8> {
+        let mut state = Self::add_round_key(msg, &self.round_keys[0]);
+        for i in 1..=self.num_rounds {
+            state = self.shift_rows(&self.sub_bytes(&state));
+            if i != self.num_rounds {
+                state = Self::mix_columns(&state);
+            }
+            state = Self::add_round_key(&state, &self.round_keys[i]);
+        }
+        state
+    }
+
+    pub fn decrypt(&self, enc_msg: &[u8]) -> Vec<u8> {
+        let mut state = enc_msg.to_vec();
+        for i in (1..=self.num_rounds).rev() {
+            state = Self::add_round_key(&state, &self.round_keys[i]);
+            if i != self.num_rounds {
+                state = Self::inv_mix_columns(&state);
+            }
+            state = self.inv_sub_bytes(&self.inv_shift_rows(&state));
+        }
+        Self::add_round_key(&state, &self.round_keys[0])
+    }
+}
+
+/// The attack only sees the block size through ShiftRows: the delta set and
+/// the diagonal structure for 6 rounds follow from its offsets.
+impl CellCipher for Rijndael {
+    fn num_rows(&self) -> usize {
+        4
+    }
+
+    fn num_cols(&self) -> usize {
+        self.num_cols
+    }
+
+    fn cell_bits(&self) -> u32 {
+        8
+    }
+
+    fn inv_sbox(&self, cell: u8) -> u8 {
+        self.inv_sbox[cell as usize]
+    }
+
+    fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        Rijndael::inv_shift_rows(self, state)
+    }
+
+    fn mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        Rijndael::mix_columns(state)
+    }
+
+    fn inv_mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        Rijndael::inv_mix_columns(state)
+    }
+
+    fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8> {
+        Rijndael::invert_key_expansion(self, round_key, round)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::{AES128, BLOCK_SIZE};
+    use crate::generic_attack::{crack_key_generic, diagonal};
+    use crate::test_util::decode_hex;
+
+    #[test]
+    fn test_matches_aes() {
+        // FIPS-197, Appendix C.
+        let msg = decode_hex("00112233445566778899aabbccddeeff");
+        for (key_cols, num_rounds, expected) in [
+            (4, 10, "69c4e0d86a7b0430d8cdb78070b4c55a"),
+            (6, 12, "dda97ca4864cdfe06eaf70a0ec0d7191"),
+            (8, 14, "8ea2b7ca516745bfeafc49904b496089"),
+        ] {
+            let key: Vec<u8> = (0..4 * key_cols as u8).collect();
+            let cipher = Rijndael::new(&key, 4, num_rounds);
+            assert_eq!(cipher.encrypt(&msg), decode_hex(expected));
+            assert_eq!(cipher.decrypt(&decode_hex(expected)), msg);
+        }
+    }
+
+    #[test]
+    fn test_known_answer_wide_blocks() {
+        // The test vectors of Gladman's specification of Rijndael: the digits
+        // of pi as plaintext and of e as key, both cut to size.
+        let msg = decode_hex("3243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c8");
+        let key = decode_hex("2b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfe");
+        for (num_cols, key_cols, expected) in [
+            (6, 4, "b24d275489e82bb8f7375e0d5fcdb1f481757c538b65148a"),
+            (6, 6, "725ae43b5f3161de806a7c93e0bca93c967ec1ae1b71e1cf"),
+            (6, 8, "0ebacf199e3315c2e34b24fcc7c46ef4388aa475d66c194c"),
+            (
+                8,
+                4,
+                "7d15479076b69a46ffb3b3beae97ad8313f622f67fedb487de9f06b9ed9c8f19",
+            ),
+            (
+                8,
+                6,
+                "5d7101727bb25781bf6715b0e6955282b9610e23a43c2eb062699f0ebf5887b2",
+            ),
+            (
+                8,
+                8,
+                "a49406115dfb30a40418aafa4869b7c6a886ff31602a7dd19c889dc64f7e4e7a",
+            ),
+        ] {
+            let num_rounds = usize::max(num_cols, key_cols) + 6;
+            let cipher = Rijndael::new(&key[..4 * key_cols], num_cols, num_rounds);
+            let msg = &msg[..4 * num_cols];
+            assert_eq!(cipher.encrypt(msg), decode_hex(expected));
+            assert_eq!(cipher.decrypt(&decode_hex(expected)), msg);
+        }
+    }
+
+    #[test]
+    fn test_matches_aes_reduced_rounds() {
+        unsafe {
+            let key = *b"sixteen byte key";
+            let aes = AES128::new(key, 4);
+            let cipher = Rijndael::new(&key, 4, 4);
+            let msg = [0x42; BLOCK_SIZE];
+            assert_eq!(cipher.encrypt(&msg), aes.encrypt(msg));
+        }
+    }
+
+    #[test]
+    fn test_inv_sbox() {
+        let (sbox, inv_sbox) = (sbox(), inv_sbox());
+        assert_eq!((sbox[0], inv_sbox[0]), (0x63, 0x52));
+        assert!((0..=255).all(|x| inv_sbox[sbox[x] as usize] == x as u8));
+    }
+
+    #[test]
+    fn test_shift_rows() {
+        let cipher = Rijndael::new(&[0; 16], 8, 1);
+        let state: Vec<u8> = (0..32).collect();
+        assert_eq!(cipher.shift_rows(&state)[..8], [0, 5, 14, 19, 4, 9, 18, 23]);
+        assert_eq!(cipher.inv_shift_rows(&cipher.shift_rows(&state)), state);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        for num_cols in [6, 8] {
+            for key_len in [16, 24, 32] {
+                let key: Vec<u8> = (0..key_len as u8).collect();
+                let cipher = Rijndael::new(&key, num_cols, 14);
+                let msg: Vec<u8> = (0..4 * num_cols as u8).map(|i| 3 * i).collect();
+                assert_eq!(cipher.decrypt(&cipher.encrypt(&msg)), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invert_key_expansion() {
+        for num_cols in [4, 6, 8] {
+            for key_len in [16, 24, 32].into_iter().filter(|&len| len <= 4 * num_cols) {
+                let key: Vec<u8> = (0..key_len as u8).map(|i| 7 * i + 1).collect();
+                let cipher = Rijndael::new(&key, num_cols, 10);
+                for (round, round_key) in cipher.round_keys.iter().enumerate() {
+                    assert_eq!(cipher.invert_key_expansion(round_key, round), key);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_balanced_after_three_rounds() {
+        for num_cols in [6, 8] {
+            let cipher = Rijndael::new(b"sixteen byte key", num_cols, 3);
+            let mut sum = vec![0; cipher.block_size()];
+            for i in 0..=255 {
+                let mut msg = vec![0x42; cipher.block_size()];
+                msg[0] = i;
+                sum = Rijndael::add_round_key(&sum, &cipher.encrypt(&msg));
+            }
+            assert!(sum.iter().all(|&x| x == 0));
+        }
+    }
+
+    #[test]
+    fn test_column_stays_uniform_in_256_bit_blocks() {
+        // With offsets 0, 1, 3, 4 only one active byte reaches column 2 in the
+        // third round, so after MixColumns its bytes take every value rather
+        // than merely summing to zero. The last round has no MixColumns, which
+        // leaves that single byte visible.
+        let cipher = Rijndael::new(b"sixteen byte key", 8, 3);
+        let mut seen = vec![vec![false; 256]; 4];
+        for i in 0..=255 {
+            let mut msg = vec![0x42; cipher.block_size()];
+            msg[0] = i;
+            let enc_msg = cipher.encrypt(&msg);
+            for (row, seen) in seen.iter_mut().enumerate() {
+                seen[enc_msg[8 + row] as usize] = true;
+            }
+        }
+        let num_values: Vec<usize> = seen
+            .iter()
+            .map(|seen| seen.iter().filter(|&&x| x).count())
+            .collect();
+        assert_eq!(num_values, [1, 1, 256, 1]);
+    }
+
+    #[test]
+    fn test_diagonal() {
+        let key = [0; 16];
+        assert_eq!(diagonal(&Rijndael::new(&key, 6, 6)), vec![0, 5, 10, 15]);
+        assert_eq!(diagonal(&Rijndael::new(&key, 8, 6)), vec![0, 5, 14, 19]);
+    }
+
+    #[test]
+    fn test_crack_key_four_rounds() {
+        for (num_cols, key_len) in [(6, 24), (8, 32), (8, 16)] {
+            let key: Vec<u8> = (0..key_len as u8).map(|i| i.wrapping_mul(11) ^ 5).collect();
+            let cipher = Rijndael::new(&key, num_cols, 4);
+            let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+            assert_eq!(crack_key_generic(&cipher, &encryption_service, 4), key);
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// Conflicts before the first restart, scaled by the Luby sequence.
+const RESTART_INTERVAL: usize = 64;
+const ACTIVITY_DECAY: f64 = 0.95;
+
+/// A formula in conjunctive normal form with DIMACS literals: variable `v`
+/// counts from 1, `v` is the literal and `-v` its negation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cnf {
+    pub num_vars: usize,
+    pub clauses: Vec<Vec<i32>>,
+}
+
+impl Cnf {
+    /// Whether `model`, the value of variable `v` at index `v - 1`, satisfies
+    /// every clause.
+    pub fn is_satisfied_by(&self, model: &[bool]) -> bool {
+        self.clauses.iter().all(|clause| {
+            clause
+                .iter()
+                .any(|&lit| model[lit.unsigned_abs() as usize - 1] == (lit > 0))
+        })
+    }
+
+    /// Writes the formula in the DIMACS format read by SAT solvers.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "p cnf {} {}", self.num_vars, self.clauses.len())?;
+        for clause in &self.clauses {
+            for lit in clause {
+                write!(writer, "{lit} ")?;
+            }
+            writeln!(writer, "0")?;
+        }
+        Ok(())
+    }
+
+    /// Reads a formula in the DIMACS format, skipping comments.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut cnf = Self::default();
+        let mut clause = vec![];
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.starts_with('c') || line.trim().is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix("p cnf") {
+                let num_vars = header.split_whitespace().next();
+                cnf.num_vars = num_vars
+                    .and_then(|num_vars| num_vars.parse().ok())
+                    .ok_or_else(|| invalid("bad header"))?;
+                continue;
+            }
+            for token in line.split_whitespace() {
+                match token.parse::<i32>() {
+                    Ok(0) => cnf.clauses.push(std::mem::take(&mut clause)),
+                    Ok(lit) if lit.unsigned_abs() as usize <= cnf.num_vars => clause.push(lit),
+                    _ => return Err(invalid("bad literal")),
+                }
+            }
+        }
+        Ok(cnf)
+    }
+}
+
+/// A literal of the solver: twice the variable, plus one if negated.
+type Lit = usize;
+
+fn lit_from_dimacs(lit: i32) -> Lit {
+    2 * (lit.unsigned_abs() as usize - 1) + (lit < 0) as usize
+}
+
+fn var(lit: Lit) -> usize {
+    lit / 2
+}
+
+fn negate(lit: Lit) -> Lit {
+    lit ^ 1
+}
+
+/// The Luby sequence 1, 1, 2, 1, 1, 2, 4, 1, ... at index `i`, from 0.
+fn luby(mut i: usize) -> usize {
+    let mut size = 1;
+    let mut seq = 0;
+    while size < i + 1 {
+        seq += 1;
+        size = 2 * size + 1;
+    }
+    while size - 1 != i {
+        size = (size - 1) / 2;
+        seq -= 1;
+        i %= size;
+    }
+    1 << seq
+}
+
+/// A variable in the queue of branching candidates, ordered by activity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Candidate {
+    activity: f64,
+    var: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.activity.total_cmp(&other.activity)
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A small CDCL solver in the style of MiniSat: two watched literals per
+/// clause, first-UIP clause learning with non-chronological backjumping,
+/// VSIDS variable activities, phase saving and Luby restarts. At restarts
+/// the learnt clauses spanning the most decision levels are deleted once
+/// there are too many.
+pub struct Solver {
+    num_vars: usize,
+    clauses: Vec<Vec<Lit>>,
+    /// The clauses watching each literal, visited when it becomes false.
+    watches: Vec<Vec<usize>>,
+    /// The value of every variable, if assigned.
+    values: Vec<Option<bool>>,
+    levels: Vec<usize>,
+    /// The clause that implied every assigned variable, `None` for decisions.
+    reasons: Vec<Option<usize>>,
+    trail: Vec<Lit>,
+    /// The length of the trail at the start of every decision level.
+    trail_limits: Vec<usize>,
+    propagated: usize,
+    activities: Vec<f64>,
+    activity_increment: f64,
+    /// Every unassigned variable with its current activity, and outdated
+    /// entries skipped when they come up.
+    candidates: BinaryHeap<Candidate>,
+    phases: Vec<bool>,
+    /// The learnt clauses with the number of decision levels they spanned.
+    learnts: Vec<(usize, usize)>,
+    max_learnts: usize,
+    seen: Vec<bool>,
+    /// Set when a clause is empty or the units contradict each other.
+    unsat: bool,
+    num_conflicts: usize,
+}
+
+impl Solver {
+    pub fn new(cnf: &Cnf) -> Self {
+        let num_vars = cnf.num_vars;
+        let mut solver = Self {
+            num_vars,
+            clauses: vec![],
+            watches: vec![vec![]; 2 * num_vars],
+            values: vec![None; num_vars],
+            levels: vec![0; num_vars],
+            reasons: vec![None; num_vars],
+            trail: vec![],
+            trail_limits: vec![],
+            propagated: 0,
+            activities: vec![0.0; num_vars],
+            activity_increment: 1.0,
+            candidates: (0..num_vars)
+                .map(|var| Candidate { activity: 0.0, var })
+                .collect(),
+            phases: vec![false; num_vars],
+            learnts: vec![],
+            max_learnts: cnf.clauses.len() / 3 + 1000,
+            seen: vec![false; num_vars],
+            unsat: false,
+            num_conflicts: 0,
+        };
+        for clause in &cnf.clauses {
+            let mut clause: Vec<Lit> = clause.iter().map(|&lit| lit_from_dimacs(lit)).collect();
+            clause.sort_unstable();
+            clause.dedup();
+            if clause.windows(2).any(|pair| pair[1] == negate(pair[0])) {
+                continue;
+            }
+            match clause.len() {
+                0 => solver.unsat = true,
+                1 => match solver.value(clause[0]) {
+                    Some(false) => solver.unsat = true,
+                    Some(true) => (),
+                    None => solver.assign(clause[0], None),
+                },
+                _ => {
+                    solver.add_clause(clause);
+                }
+            }
+        }
+        solver
+    }
+
+    /// The number of conflicts met so far.
+    pub fn num_conflicts(&self) -> usize {
+        self.num_conflicts
+    }
+
+    fn value(&self, lit: Lit) -> Option<bool> {
+        self.values[var(lit)].map(|value| value != (lit & 1 == 1))
+    }
+
+    fn decision_level(&self) -> usize {
+        self.trail_limits.len()
+    }
+
+    fn add_clause(&mut self, clause: Vec<Lit>) -> usize {
+        let index = self.clauses.len();
+        self.watches[clause[0]].push(index);
+        self.watches[clause[1]].push(index);
+        self.clauses.push(clause);
+        index
+    }
+
+    fn assign(&mut self, lit: Lit, reason: Option<usize>) {
+        let v = var(lit);
+        self.values[v] = Some(lit & 1 == 0);
+        self.levels[v] = self.decision_level();
+        self.reasons[v] = reason;
+        self.trail.push(lit);
+    }
+
+    /// Unit propagation. Returns the clause in conflict, if any. The literal
+    /// a clause implies is kept first in it.
+    fn propagate(&mut self) -> Option<usize> {
+        while self.propagated < self.trail.len() {
+            let false_lit = negate(self.trail[self.propagated]);
+            self.propagated += 1;
+            let watching = std::mem::take(&mut self.watches[false_lit]);
+            let mut kept = Vec::with_capacity(watching.len());
+            let mut conflict = None;
+            for (i, &index) in watching.iter().enumerate() {
+                if conflict.is_some() {
+                    kept.extend_from_slice(&watching[i..]);
+                    break;
+                }
+                let clause = &mut self.clauses[index];
+                if clause.is_empty() {
+                    // Deleted.
+                    continue;
+                }
+                if clause[0] == false_lit {
+                    clause.swap(0, 1);
+                }
+                let first = clause[0];
+                if self.values[var(first)].is_some_and(|value| value != (first & 1 == 1)) {
+                    kept.push(index);
+                    continue;
+                }
+                let values = &self.values;
+                let replacement = (2..clause.len()).find(|&k| {
+                    values[var(clause[k])].is_none_or(|value| value != (clause[k] & 1 == 1))
+                });
+                if let Some(k) = replacement {
+                    clause.swap(1, k);
+                    self.watches[clause[1]].push(index);
+                    continue;
+                }
+                kept.push(index);
+                match self.value(first) {
+                    Some(false) => conflict = Some(index),
+                    _ => self.assign(first, Some(index)),
+                }
+            }
+            self.watches[false_lit] = kept;
+            if conflict.is_some() {
+                return conflict;
+            }
+        }
+        None
+    }
+
+    fn bump(&mut self, v: usize) {
+        self.activities[v] += self.activity_increment;
+        if self.activities[v] > 1e100 {
+            for activity in &mut self.activities {
+                *activity *= 1e-100;
+            }
+            self.activity_increment *= 1e-100;
+            self.candidates = (0..self.num_vars)
+                .filter(|&var| self.values[var].is_none())
+                .map(|var| Candidate {
+                    activity: self.activities[var],
+                    var,
+                })
+                .collect();
+        } else if self.values[v].is_none() {
+            self.candidates.push(Candidate {
+                activity: self.activities[v],
+                var: v,
+            });
+        }
+    }
+
+    /// The first-UIP clause learnt from a conflict, its asserting literal
+    /// first, and the level to jump back to. Literals implied by others of
+    /// the clause are left out.
+    fn analyze(&mut self, mut conflict: usize) -> (Vec<Lit>, usize) {
+        let mut learnt = vec![0];
+        let mut pending = 0;
+        let mut index = self.trail.len();
+        let mut implied = None;
+        loop {
+            let start = implied.is_some() as usize;
+            for k in start..self.clauses[conflict].len() {
+                let lit = self.clauses[conflict][k];
+                let v = var(lit);
+                if !self.seen[v] && self.levels[v] > 0 {
+                    self.seen[v] = true;
+                    self.bump(v);
+                    if self.levels[v] == self.decision_level() {
+                        pending += 1;
+                    } else {
+                        learnt.push(lit);
+                    }
+                }
+            }
+            loop {
+                index -= 1;
+                if self.seen[var(self.trail[index])] {
+                    break;
+                }
+            }
+            let lit = self.trail[index];
+            self.seen[var(lit)] = false;
+            pending -= 1;
+            implied = Some(lit);
+            if pending == 0 {
+                break;
+            }
+            conflict = self.reasons[var(lit)].unwrap();
+        }
+        learnt[0] = negate(implied.unwrap());
+
+        let marked = learnt[1..].to_vec();
+        let mut k = 1;
+        while k < learnt.len() {
+            let redundant = self.reasons[var(learnt[k])].is_some_and(|reason| {
+                self.clauses[reason][1..]
+                    .iter()
+                    .all(|&other| self.seen[var(other)] || self.levels[var(other)] == 0)
+            });
+            if redundant {
+                learnt.swap_remove(k);
+            } else {
+                k += 1;
+            }
+        }
+        for lit in marked {
+            self.seen[var(lit)] = false;
+        }
+
+        let mut level = 0;
+        if learnt.len() > 1 {
+            let deepest = (1..learnt.len())
+                .max_by_key(|&k| self.levels[var(learnt[k])])
+                .unwrap();
+            learnt.swap(1, deepest);
+            level = self.levels[var(learnt[1])];
+        }
+        (learnt, level)
+    }
+
+    /// Deletes the worse half of the learnt clauses by the number of
+    /// decision levels they spanned, keeping those spanning two or fewer.
+    /// Only called at decision level 0, when no learnt clause is a reason
+    /// that `analyze` may look at.
+    fn reduce_learnts(&mut self) {
+        self.learnts.sort_by_key(|&(_, lbd)| lbd);
+        let half = self.learnts.len() / 2;
+        for &(index, lbd) in &self.learnts[half..] {
+            if lbd > 2 {
+                self.clauses[index] = vec![];
+            }
+        }
+        let clauses = &self.clauses;
+        self.learnts
+            .retain(|&(index, _)| !clauses[index].is_empty());
+        self.max_learnts += self.max_learnts / 10;
+    }
+
+    fn backtrack(&mut self, level: usize) {
+        if self.decision_level() <= level {
+            return;
+        }
+        for &lit in &self.trail[self.trail_limits[level]..] {
+            let v = var(lit);
+            self.phases[v] = lit & 1 == 0;
+            self.values[v] = None;
+            self.candidates.push(Candidate {
+                activity: self.activities[v],
+                var: v,
+            });
+        }
+        self.trail.truncate(self.trail_limits[level]);
+        self.trail_limits.truncate(level);
+        self.propagated = self.trail.len();
+    }
+
+    /// The unassigned variable of the highest activity.
+    fn pick_branch_var(&mut self) -> Option<usize> {
+        while let Some(candidate) = self.candidates.pop() {
+            let v = candidate.var;
+            if self.values[v].is_none() && candidate.activity == self.activities[v] {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    /// A satisfying assignment, the value of variable `v` at index `v - 1`,
+    /// or `None` if the formula is unsatisfiable.
+    pub fn solve(&mut self) -> Option<Vec<bool>> {
+        if self.unsat {
+            return None;
+        }
+        let mut restarts = 0;
+        let mut conflicts_since_restart = 0;
+        loop {
+            if let Some(conflict) = self.propagate() {
+                self.num_conflicts += 1;
+                conflicts_since_restart += 1;
+                if self.decision_level() == 0 {
+                    self.unsat = true;
+                    return None;
+                }
+                let (learnt, level) = self.analyze(conflict);
+                self.backtrack(level);
+                if learnt.len() == 1 {
+                    self.assign(learnt[0], None);
+                } else {
+                    let asserting = learnt[0];
+                    let mut levels: Vec<usize> =
+                        learnt.iter().map(|&lit| self.levels[var(lit)]).collect();
+                    levels.sort_unstable();
+                    levels.dedup();
+                    let index = self.add_clause(learnt);
+                    self.learnts.push((index, levels.len()));
+                    self.assign(asserting, Some(index));
+                }
+                self.activity_increment /= ACTIVITY_DECAY;
+            } else if conflicts_since_restart >= RESTART_INTERVAL * luby(restarts) {
+                restarts += 1;
+                conflicts_since_restart = 0;
+                self.backtrack(0);
+                if self.learnts.len() > self.max_learnts {
+                    self.reduce_learnts();
+                }
+            } else {
+                let Some(v) = self.pick_branch_var() else {
+                    return Some(self.values.iter().map(|value| value.unwrap()).collect());
+                };
+                self.trail_limits.push(self.trail.len());
+                self.assign(2 * v + !self.phases[v] as usize, None);
+            }
+        }
+    }
+}
+
+/// Solves `cnf` with a fresh `Solver`.
+pub fn solve(cnf: &Cnf) -> Option<Vec<bool>> {
+    Solver::new(cnf).solve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    /// Pigeon `i` sits in hole `j` for variable `holes * i + j + 1`.
+    fn pigeonhole(pigeons: usize, holes: usize) -> Cnf {
+        let var = |i: usize, j: usize| (holes * i + j + 1) as i32;
+        let mut clauses: Vec<Vec<i32>> = (0..pigeons)
+            .map(|i| (0..holes).map(|j| var(i, j)).collect())
+            .collect();
+        for j in 0..holes {
+            for a in 0..pigeons {
+                for b in a + 1..pigeons {
+                    clauses.push(vec![-var(a, j), -var(b, j)]);
+                }
+            }
+        }
+        Cnf {
+            num_vars: pigeons * holes,
+            clauses,
+        }
+    }
+
+    #[test]
+    fn test_luby() {
+        let seq: Vec<usize> = (0..15).map(luby).collect();
+        assert_eq!(seq, [1, 1, 2, 1, 1, 2, 4, 1, 1, 2, 1, 1, 2, 4, 8]);
+    }
+
+    #[test]
+    fn test_pigeonhole() {
+        assert_eq!(solve(&pigeonhole(6, 5)), None);
+        let cnf = pigeonhole(5, 5);
+        assert!(cnf.is_satisfied_by(&solve(&cnf).unwrap()));
+    }
+
+    #[test]
+    fn test_random_3sat() {
+        // Around the threshold of 4.26 clauses per variable, where formulas
+        // are hardest and about half of them satisfiable. Few enough
+        // variables to check unsatisfiability by brute force.
+        let mut rng = thread_rng();
+        let num_vars = 16;
+        for _ in 0..20 {
+            let clauses = (0..68)
+                .map(|_| {
+                    (0..3)
+                        .map(|_| {
+                            let v = rng.gen_range(1..=num_vars as i32);
+                            if rng.gen() {
+                                v
+                            } else {
+                                -v
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            let cnf = Cnf { num_vars, clauses };
+            match solve(&cnf) {
+                Some(model) => assert!(cnf.is_satisfied_by(&model)),
+                None => assert!((0..1 << num_vars).all(|bits: usize| {
+                    let model: Vec<bool> = (0..num_vars).map(|v| bits >> v & 1 == 1).collect();
+                    !cnf.is_satisfied_by(&model)
+                })),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dimacs() {
+        let cnf = pigeonhole(3, 2);
+        let mut bytes = vec![];
+        cnf.write_to(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"p cnf 6 9\n1 2 0\n"));
+        let with_comment = [b"c pigeons\n".as_slice(), &bytes].concat();
+        assert_eq!(Cnf::read_from(&mut with_comment.as_slice()).unwrap(), cnf);
+        assert!(Cnf::read_from(&mut b"p cnf 2 1\n1 3 0\n".as_slice()).is_err());
+    }
+}
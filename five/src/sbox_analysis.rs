@@ -0,0 +1,316 @@
+use std::io::{self, Write};
+
+use crate::aes::{AES128, BLOCK_SIZE};
+
+/// The AES S-box, read off `AES128::sub_bytes`.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn aes_sbox() -> [u8; 256] {
+    let mut sbox = [0; 256];
+    for chunk in 0..16 {
+        let block: [u8; BLOCK_SIZE] = std::array::from_fn(|i| (16 * chunk + i) as u8);
+        let res = AES128::state_to_block(AES128::sub_bytes(AES128::block_to_state(block)));
+        sbox[16 * chunk..16 * chunk + 16].copy_from_slice(&res);
+    }
+    sbox
+}
+
+/// Parses an S-box written as its entries in hex, separated by whitespace or
+/// commas, with or without `0x`. Returns `None` unless there are `2^n`
+/// entries of `n` bits for some `n` up to 8.
+pub fn parse_sbox(text: &str) -> Option<Vec<u8>> {
+    let sbox: Vec<u8> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| u8::from_str_radix(entry.trim_start_matches("0x"), 16).ok())
+        .collect::<Option<_>>()?;
+    let size = sbox.len();
+    let valid = size.is_power_of_two() && size > 1 && size <= 256;
+    (valid && sbox.iter().all(|&y| (y as usize) < size)).then_some(sbox)
+}
+
+/// A square table over the inputs and outputs of an S-box, e.g. input and
+/// output differences for the DDT or masks for the LAT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub entries: Vec<Vec<i32>>,
+}
+
+impl Table {
+    pub fn get(&self, input: usize, output: usize) -> i32 {
+        self.entries[input][output]
+    }
+
+    /// The largest absolute value outside the first row and column, which
+    /// only hold the trivial entries.
+    pub fn max_nontrivial(&self) -> i32 {
+        self.entries[1..]
+            .iter()
+            .flat_map(|row| row[1..].iter().map(|entry| entry.abs()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Writes the table as CSV, the input in the first column and the output
+    /// in the header row, which plotting tools read as a heatmap.
+    pub fn write_csv_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "in\\out")?;
+        for output in 0..self.entries.len() {
+            write!(writer, ",{output}")?;
+        }
+        writeln!(writer)?;
+        for (input, row) in self.entries.iter().enumerate() {
+            write!(writer, "{input}")?;
+            for entry in row {
+                write!(writer, ",{entry}")?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+}
+
+fn inverse(sbox: &[u8]) -> Option<Vec<u8>> {
+    let mut inv = vec![None; sbox.len()];
+    for (x, &y) in sbox.iter().enumerate() {
+        inv[y as usize] = Some(x as u8);
+    }
+    inv.into_iter().collect()
+}
+
+/// The difference distribution table: entry `(a, b)` counts the inputs `x`
+/// with `S(x) + S(x + a) = b`.
+pub fn ddt(sbox: &[u8]) -> Table {
+    let size = sbox.len();
+    let mut entries = vec![vec![0; size]; size];
+    for (a, row) in entries.iter_mut().enumerate() {
+        for x in 0..size {
+            row[(sbox[x] ^ sbox[x ^ a]) as usize] += 1;
+        }
+    }
+    Table { entries }
+}
+
+/// The linear approximation table: entry `(a, b)` is the number of inputs
+/// `x` with `a.x = b.S(x)` minus half of them, i.e. the bias of the
+/// approximation times the size of the S-box.
+pub fn lat(sbox: &[u8]) -> Table {
+    let size = sbox.len();
+    let parity = |x: usize| x.count_ones() % 2;
+    let entries = (0..size)
+        .map(|a| {
+            (0..size)
+                .map(|b| {
+                    let agreeing = (0..size)
+                        .filter(|&x| parity(a & x) == parity(b & sbox[x] as usize))
+                        .count();
+                    agreeing as i32 - size as i32 / 2
+                })
+                .collect()
+        })
+        .collect();
+    Table { entries }
+}
+
+/// The boomerang connectivity table of Cid et al.: entry `(a, b)` counts the
+/// inputs `x` with `S^-1(S(x) + b) + S^-1(S(x + a) + b) = a`, the
+/// probability of a boomerang switching through the S-box times its size.
+/// Only defined for permutations.
+pub fn bct(sbox: &[u8]) -> Option<Table> {
+    let inv = inverse(sbox)?;
+    let size = sbox.len();
+    let entries = (0..size)
+        .map(|a| {
+            (0..size)
+                .map(|b| {
+                    (0..size)
+                        .filter(|&x| {
+                            let returned =
+                                inv[(sbox[x] as usize) ^ b] ^ inv[(sbox[x ^ a] as usize) ^ b];
+                            returned as usize == a
+                        })
+                        .count() as i32
+                })
+                .collect()
+        })
+        .collect();
+    Some(Table { entries })
+}
+
+/// The coefficients of the algebraic normal form of a Boolean function from
+/// its truth table, by the Möbius transform: entry `mask` is that of the
+/// product of the input bits in `mask`.
+pub fn anf(truth_table: &[bool]) -> Vec<bool> {
+    let mut coefficients = truth_table.to_vec();
+    let mut step = 1;
+    while step < coefficients.len() {
+        for x in 0..coefficients.len() {
+            if x & step != 0 {
+                coefficients[x] ^= coefficients[x ^ step];
+            }
+        }
+        step *= 2;
+    }
+    coefficients
+}
+
+/// The algebraic normal form of every output bit of the S-box.
+pub fn coordinate_anfs(sbox: &[u8]) -> Vec<Vec<bool>> {
+    (0..sbox.len().trailing_zeros())
+        .map(|j| anf(&sbox.iter().map(|&y| y >> j & 1 == 1).collect::<Vec<_>>()))
+        .collect()
+}
+
+/// The highest degree of a monomial in the algebraic normal form of an
+/// output bit.
+pub fn algebraic_degree(sbox: &[u8]) -> u32 {
+    coordinate_anfs(sbox)
+        .iter()
+        .flat_map(|coefficients| {
+            coefficients
+                .iter()
+                .enumerate()
+                .filter(|(_, &c)| c)
+                .map(|(mask, _)| mask.count_ones())
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Writes the algebraic normal form of every output bit, one per line, as
+/// `y<j> = ` a sum of products of the input bits `x<i>`.
+pub fn write_anf_to<W: Write>(sbox: &[u8], writer: &mut W) -> io::Result<()> {
+    let num_bits = sbox.len().trailing_zeros();
+    for (j, coefficients) in coordinate_anfs(sbox).iter().enumerate() {
+        let mut terms: Vec<String> = coefficients
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, &c)| c)
+            .map(|(mask, _)| {
+                let vars: Vec<String> = (0..num_bits)
+                    .filter(|&i| mask >> i & 1 == 1)
+                    .map(|i| format!("x{i}"))
+                    .collect();
+                vars.join("*")
+            })
+            .collect();
+        if coefficients[0] {
+            terms.push("1".to_string());
+        }
+        if terms.is_empty() {
+            terms.push("0".to_string());
+        }
+        writeln!(writer, "y{j} = {}", terms.join(" + "))?;
+    }
+    Ok(())
+}
+
+/// The figures of merit of an S-box against differential, linear and
+/// boomerang attacks and algebraic ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Properties {
+    /// The largest entry of the DDT for a non-zero input difference.
+    pub differential_uniformity: i32,
+    /// The distance to the closest affine function of the nearest
+    /// non-zero combination of output bits.
+    pub nonlinearity: i32,
+    /// The largest entry of the BCT for non-zero differences, or `None`
+    /// unless the S-box is a permutation.
+    pub boomerang_uniformity: Option<i32>,
+    pub algebraic_degree: u32,
+}
+
+pub fn properties(sbox: &[u8]) -> Properties {
+    Properties {
+        differential_uniformity: ddt(sbox).max_nontrivial(),
+        nonlinearity: sbox.len() as i32 / 2 - lat(sbox).max_nontrivial(),
+        boomerang_uniformity: bct(sbox).map(|table| table.max_nontrivial()),
+        algebraic_degree: algebraic_degree(sbox),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rijndael::sbox;
+    use crate::small_aes::SmallAES;
+
+    #[test]
+    fn test_aes_sbox() {
+        assert_eq!(unsafe { aes_sbox() }, sbox());
+    }
+
+    #[test]
+    fn test_aes_properties() {
+        let sbox = unsafe { aes_sbox() };
+        assert_eq!(
+            properties(&sbox),
+            Properties {
+                differential_uniformity: 4,
+                nonlinearity: 112,
+                boomerang_uniformity: Some(6),
+                algebraic_degree: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tables() {
+        let sbox = unsafe { aes_sbox() };
+        let (ddt, lat, bct) = (ddt(&sbox), lat(&sbox), bct(&sbox).unwrap());
+        assert_eq!(ddt.get(0, 0), 256);
+        assert_eq!(lat.get(0, 0), 128);
+        for a in 1..256 {
+            assert_eq!(ddt.entries[a].iter().sum::<i32>(), 256);
+            assert_eq!(ddt.get(a, 0), 0);
+            // Both differences in the same S-box make the boomerang return.
+            assert_eq!(bct.get(a, 0), 256);
+            assert_eq!(bct.get(0, a), 256);
+            for b in 1..256 {
+                assert!(bct.get(a, b) >= ddt.get(a, b));
+                assert_eq!(lat.get(a, b) % 2, 0);
+            }
+        }
+        // Parseval: the squares of a column add up to 2^(2n) / 4.
+        let squares: i32 = (0..256).map(|a| lat.get(a, 1).pow(2)).sum();
+        assert_eq!(squares, 256 * 256 / 4);
+    }
+
+    #[test]
+    fn test_anf() {
+        // x0 + x1 + x0*x1, i.e. OR.
+        assert_eq!(anf(&[false, true, true, true]), [false, true, true, true]);
+        assert_eq!(
+            anf(&[false, false, false, true]),
+            [false, false, false, true]
+        );
+        // The S-box of the small-scale variant is the inverse in GF(2^4)
+        // followed by an affine map, so of degree 3.
+        let small_sbox = SmallAES::new(&[0; 16], 4, 4, 1).sub_bytes(&(0..16).collect::<Vec<u8>>());
+        assert_eq!(algebraic_degree(&small_sbox), 3);
+        let mut text = vec![];
+        write_anf_to(&[0, 1, 3, 2], &mut text).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), "y0 = x0 + x1\ny1 = x1\n");
+    }
+
+    #[test]
+    fn test_parse_sbox() {
+        assert_eq!(parse_sbox("0x3, 0x0,1 2"), Some(vec![3, 0, 1, 2]));
+        assert_eq!(
+            parse_sbox("c 5 6 b 9 0 a d 3 e f 8 4 7 1 2").unwrap().len(),
+            16
+        );
+        assert_eq!(parse_sbox("0 1 2"), None);
+        assert_eq!(parse_sbox("0 4"), None);
+    }
+
+    #[test]
+    fn test_csv() {
+        let mut csv = vec![];
+        ddt(&[0, 1, 3, 2]).write_csv_to(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "in\\out,0,1,2,3\n0,4,0,0,0\n1,0,4,0,0\n2,0,0,0,4\n3,0,0,4,0\n"
+        );
+    }
+}
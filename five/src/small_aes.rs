@@ -0,0 +1,334 @@
+use crate::generic_attack::CellCipher;
+use crate::rijndael::{field_inv, invert_matrix, invert_sbox, MIX_COLUMNS};
+
+/// Columns of the GF(2)-linear part of the S-box affine transformation.
+const AFFINE_COLUMNS: [u8; 4] = [0xd, 0xb, 0x7, 0xe];
+const AFFINE_CONSTANT: u8 = 0x6;
+/// x^4 + x + 1
+const MODULUS: u8 = 0x13;
+
+/// Multiplication in GF(2^4).
+pub fn gf16_mul(a: u8, b: u8) -> u8 {
+    let mut res = 0;
+    for i in 0..4 {
+        if (b >> i) & 1 == 1 {
+            res ^= a << i;
+        }
+    }
+    for i in (4..8).rev() {
+        if (res >> i) & 1 == 1 {
+            res ^= MODULUS << (i - 4);
+        }
+    }
+    res
+}
+
+/// The small-scale variants SR*(n, r, c, 4) of AES by Cid, Murphy and
+/// Robshaw: a state of `rows` x `cols` cells of 4 bits, stored column by
+/// column like the bytes of an AES block. Like AES, the last round has no
+/// MixColumns.
+pub struct SmallAES {
+    rows: usize,
+    cols: usize,
+    num_rounds: usize,
+    round_keys: Vec<Vec<u8>>,
+    sbox: [u8; 16],
+    inv_sbox: [u8; 16],
+    mix_matrix: Vec<Vec<u8>>,
+    inv_mix_matrix: Vec<Vec<u8>>,
+}
+
+impl SmallAES {
+    /// `rows` must be 1, 2 or 4 and `key` must hold `rows * cols` cells.
+    pub fn new(key: &[u8], rows: usize, cols: usize, num_rounds: usize) -> Self {
+        assert_eq!(key.len(), rows * cols);
+        let sbox: [u8; 16] = std::array::from_fn(|x| {
+            let inv = field_inv(x as u8, 16, gf16_mul);
+            (0..4)
+                .filter(|i| (inv >> i) & 1 == 1)
+                .fold(AFFINE_CONSTANT, |acc, i| acc ^ AFFINE_COLUMNS[i])
+        });
+        let inv_sbox = invert_sbox(&sbox);
+        let mix_matrix = match rows {
+            1 => vec![vec![1]],
+            2 => vec![vec![3, 2], vec![2, 3]],
+            4 => (0..4)
+                .map(|i| (0..4).map(|j| MIX_COLUMNS[(4 + j - i) % 4]).collect())
+                .collect(),
+            _ => panic!("unsupported number of rows: {rows}"),
+        };
+        let mut cipher = Self {
+            rows,
+            cols,
+            num_rounds,
+            round_keys: vec![],
+            inv_mix_matrix: invert_matrix(&mix_matrix, 16, gf16_mul).unwrap(),
+            mix_matrix,
+            sbox,
+            inv_sbox,
+        };
+        cipher.round_keys = cipher.key_expansion(key);
+        cipher
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.rows * self.cols
+    }
+
+    pub fn key_expansion(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let mut round_keys = vec![key.to_vec()];
+        let mut rcon = 1;
+        for _ in 0..self.num_rounds {
+            let prev = round_keys.last().unwrap();
+            let last_col = &prev[self.rows * (self.cols - 1)..];
+            let mut next = prev.clone();
+            for row in 0..self.rows {
+                next[row] ^= self.sbox[last_col[(row + 1) % self.rows] as usize];
+            }
+            next[0] ^= rcon;
+            for i in self.rows..self.block_size() {
+                next[i] ^= next[i - self.rows];
+            }
+            round_keys.push(next);
+            rcon = gf16_mul(rcon, 2);
+        }
+        round_keys
+    }
+
+    /// Walks the key schedule backwards from the round key of round `round`.
+    pub fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8> {
+        let rcons: Vec<u8> = (0..round)
+            .scan(1, |rcon, _| {
+                let cur = *rcon;
+                *rcon = gf16_mul(cur, 2);
+                Some(cur)
+            })
+            .collect();
+        let mut key = round_key.to_vec();
+        for r in (0..round).rev() {
+            for i in (self.rows..self.block_size()).rev() {
+                key[i] ^= key[i - self.rows];
+            }
+            let last_col = key[self.rows * (self.cols - 1)..].to_vec();
+            for row in 0..self.rows {
+                key[row] ^= self.sbox[last_col[(row + 1) % self.rows] as usize];
+            }
+            key[0] ^= rcons[r];
+        }
+        key
+    }
+
+    pub fn sub_bytes(&self, state: &[u8]) -> Vec<u8> {
+        state.iter().map(|&x| self.sbox[x as usize]).collect()
+    }
+
+    pub fn inv_sub_bytes(&self, state: &[u8]) -> Vec<u8> {
+        state.iter().map(|&x| self.inv_sbox[x as usize]).collect()
+    }
+
+    /// Row `i` is rotated `i` positions to the left.
+    pub fn shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        (0..self.block_size())
+            .map(|i| {
+                let (row, col) = (i % self.rows, i / self.rows);
+                state[row + self.rows * ((col + row) % self.cols)]
+            })
+            .collect()
+    }
+
+    pub fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        (0..self.block_size())
+            .map(|i| {
+                let (row, col) = (i % self.rows, i / self.rows);
+                state[row + self.rows * ((col + self.cols - row % self.cols) % self.cols)]
+            })
+            .collect()
+    }
+
+    fn apply_matrix(&self, matrix: &[Vec<u8>], state: &[u8]) -> Vec<u8> {
+        state
+            .chunks(self.rows)
+            .flat_map(|column| {
+                matrix.iter().map(move |row| {
+                    row.iter()
+                        .zip(column)
+                        .fold(0, |acc, (&m, &x)| acc ^ gf16_mul(m, x))
+                })
+            })
+            .collect()
+    }
+
+    pub fn mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        self.apply_matrix(&self.mix_matrix, state)
+    }
+
+    pub fn inv_mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        self.apply_matrix(&self.inv_mix_matrix, state)
+    }
+
+    pub fn add_round_key(state: &[u8], round_key: &[u8]) -> Vec<u8> {
+        state.iter().zip(round_key).map(|(x, k)| x ^ k).collect()
+    }
+
+    pub fn inv_add_round_key(state: &[u8], round_key: &[u8]) -> Vec<u8> {
+        Self::add_round_key(state, round_key)
+    }
+
+    /// The `num_cells` cells packed into `bits`, cell `i` in bits `4 * i` to
+    /// `4 * i + 3`, the way the bit-level attacks pass blocks around.
+    pub fn unpack(bits: u128, num_cells: usize) -> Vec<u8> {
+        (0..num_cells)
+            .map(|i| (bits >> (4 * i)) as u8 & 0xf)
+            .collect()
+    }
+
+    /// The inverse of `unpack`.
+    pub fn pack(cells: &[u8]) -> u128 {
+        cells
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &cell)| bits | (cell as u128) << (4 * i))
+    }
+
+    pub fn encrypt(&self, msg: &[u8]) -> Vec<u8> {
+        let mut state = Self::add_round_key(msg, &self.round_keys[0]);
+        for i in 1..=self.num_rounds {
+            state = self.shift_rows(&self.sub_bytes(&state));
+            if i != self.num_rounds {
+                state = self.mix_columns(&state);
+            }
+            state = Self::add_round_key(&state, &self.round_keys[i]);
+        }
+        state
+    }
+
+    pub fn decrypt(&self, enc_msg: &[u8]) -> Vec<u8> {
+        let mut state = enc_msg.to_vec();
+        for i in (1..=self.num_rounds).rev() {
+            state = Self::inv_add_round_key(&state, &self.round_keys[i]);
+            if i != self.num_rounds {
+                state = self.inv_mix_columns(&state);
+            }
+            state = self.inv_sub_bytes(&self.inv_shift_rows(&state));
+        }
+        Self::inv_add_round_key(&state, &self.round_keys[0])
+    }
+}
+
+impl CellCipher for SmallAES {
+    fn num_rows(&self) -> usize {
+        self.rows
+    }
+
+    fn num_cols(&self) -> usize {
+        self.cols
+    }
+
+    fn cell_bits(&self) -> u32 {
+        4
+    }
+
+    fn inv_sbox(&self, cell: u8) -> u8 {
+        self.inv_sbox[cell as usize]
+    }
+
+    fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        SmallAES::inv_shift_rows(self, state)
+    }
+
+    fn mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        SmallAES::mix_columns(self, state)
+    }
+
+    fn inv_mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        SmallAES::inv_mix_columns(self, state)
+    }
+
+    fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8> {
+        SmallAES::invert_key_expansion(self, round_key, round)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic_attack::crack_key_generic;
+
+    #[test]
+    fn test_sbox() {
+        let cipher = SmallAES::new(&[0; 4], 2, 2, 1);
+        assert_eq!(
+            cipher.sbox,
+            [6, 11, 5, 4, 2, 14, 7, 10, 9, 13, 15, 12, 3, 1, 0, 8]
+        );
+    }
+
+    #[test]
+    fn test_mix_columns() {
+        for rows in [1, 2, 4] {
+            let cipher = SmallAES::new(&vec![0; rows * 4], rows, 4, 1);
+            let state: Vec<u8> = (0..rows as u8 * 4).collect();
+            assert_eq!(cipher.inv_mix_columns(&cipher.mix_columns(&state)), state);
+        }
+    }
+
+    #[test]
+    fn test_shift_rows() {
+        let cipher = SmallAES::new(&[0; 16], 4, 4, 1);
+        let state: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            cipher.shift_rows(&state),
+            vec![0, 5, 10, 15, 4, 9, 14, 3, 8, 13, 2, 7, 12, 1, 6, 11]
+        );
+        assert_eq!(cipher.inv_shift_rows(&cipher.shift_rows(&state)), state);
+    }
+
+    #[test]
+    fn test_pack() {
+        let cells: Vec<u8> = (0..16).map(|i| (7 * i + 2) & 0xf).collect();
+        assert_eq!(SmallAES::pack(&cells[..4]), 0x7092);
+        assert_eq!(SmallAES::unpack(SmallAES::pack(&cells), 16), cells);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        for (rows, cols) in [(1, 1), (2, 2), (4, 4), (2, 4)] {
+            let key: Vec<u8> = (0..rows * cols).map(|i| (7 * i + 3) as u8 & 0xf).collect();
+            let cipher = SmallAES::new(&key, rows, cols, 10);
+            let msg: Vec<u8> = (0..rows * cols).map(|i| i as u8 & 0xf).collect();
+            let enc_msg = cipher.encrypt(&msg);
+            assert_ne!(enc_msg, msg);
+            assert_eq!(cipher.decrypt(&enc_msg), msg);
+        }
+    }
+
+    #[test]
+    fn test_invert_key_expansion() {
+        let key: Vec<u8> = (0..16).map(|i| (5 * i + 1) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 10);
+        for (round, round_key) in cipher.round_keys.iter().enumerate() {
+            assert_eq!(cipher.invert_key_expansion(round_key, round), key);
+        }
+    }
+
+    #[test]
+    fn test_crack_key() {
+        let key = [0x3, 0xc, 0x9, 0x5];
+        for num_rounds in [4, 5, 6] {
+            let cipher = SmallAES::new(&key, 2, 2, num_rounds);
+            let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+            assert_eq!(
+                crack_key_generic(&cipher, &encryption_service, num_rounds),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_crack_key_four_by_four() {
+        let key: Vec<u8> = (0..16).map(|i| (11 * i + 2) & 0xf).collect();
+        let cipher = SmallAES::new(&key, 4, 4, 4);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg);
+        assert_eq!(crack_key_generic(&cipher, &encryption_service, 4), key);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::aes::{Block, BLOCK_SIZE};
+use crate::generic_attack::CellCipher;
+use crate::rijndael::{field_inv, invert_sbox, round_constants};
+
+/// x^8 + x^7 + x^6 + x^5 + x^4 + x^2 + 1
+const MODULUS: u16 = 0x1f5;
+/// Rows of the GF(2)-linear part of the affine map in γ, one per output bit.
+const AFFINE_ROWS: [u8; 8] = [0x01, 0x03, 0x05, 0x0f, 0x1f, 0x3d, 0x7b, 0xd6];
+const AFFINE_CONSTANT: u8 = 0xb1;
+/// Coefficients of c(x) = 2 + x + x^2 + 3x^3, by which θ multiplies every row.
+const THETA: [u8; 4] = [2, 1, 1, 3];
+/// Coefficients of c(x)^-1 mod x^4 + 1.
+const INV_THETA: [u8; 4] = [0xe, 0x9, 0xd, 0xb];
+
+/// Multiplication in GF(2^8) as defined for Square, which uses a different
+/// modulus than AES.
+pub fn gf256_mul(a: u8, b: u8) -> u8 {
+    let (mut a, mut b, mut res) = (a as u16, b, 0);
+    while b != 0 {
+        if b & 1 == 1 {
+            res ^= a;
+        }
+        a <<= 1;
+        if a & 0x100 != 0 {
+            a ^= MODULUS;
+        }
+        b >>= 1;
+    }
+    res as u8
+}
+
+/// The block cipher Square by Daemen, Knudsen and Rijmen, the predecessor of
+/// AES. The state is a 4x4 array of bytes stored row by row, and
+///
+/// Square[k] = ρ[k^R] ∘ ... ∘ ρ[k^1] ∘ σ[k^0] ∘ θ^-1, with ρ[k] = σ[k] ∘ π ∘ γ ∘ θ
+///
+/// Unlike AES every round is identical, the initial θ^-1 plays the role of
+/// the missing MixColumns in the last round of AES.
+pub struct Square {
+    round_keys: Vec<Block>,
+    sbox: [u8; 256],
+    inv_sbox: [u8; 256],
+}
+
+impl Square {
+    /// The original cipher has 8 rounds.
+    pub fn new(key: Block, num_rounds: usize) -> Self {
+        let sbox: [u8; 256] = std::array::from_fn(|x| {
+            let inv = field_inv(x as u8, 256, gf256_mul);
+            (0..8).fold(AFFINE_CONSTANT, |acc, bit| {
+                acc ^ (((AFFINE_ROWS[bit] & inv).count_ones() as u8 & 1) << bit)
+            })
+        });
+        Self {
+            round_keys: Self::key_expansion(key, num_rounds),
+            sbox,
+            inv_sbox: invert_sbox(&sbox),
+        }
+    }
+
+    /// Row 0 of k^t is row 0 of k^(t-1) plus the rotated last row of k^(t-1)
+    /// plus a round constant, every other row is the row above plus the same
+    /// row of k^(t-1).
+    pub fn key_expansion(key: Block, num_rounds: usize) -> Vec<Block> {
+        let mut round_keys = vec![key];
+        for constant in round_constants(num_rounds, gf256_mul) {
+            let prev = round_keys.last().unwrap();
+            let mut next = *prev;
+            for col in 0..4 {
+                next[col] ^= prev[12 + (col + 1) % 4];
+            }
+            next[0] ^= constant;
+            for i in 4..BLOCK_SIZE {
+                next[i] ^= next[i - 4];
+            }
+            round_keys.push(next);
+        }
+        round_keys
+    }
+
+    /// Walks the key schedule backwards from the round key of round `round`.
+    pub fn invert_key_expansion(round_key: Block, round: usize) -> Block {
+        let mut key = round_key;
+        for constant in round_constants(round, gf256_mul).into_iter().rev() {
+            for i in (4..BLOCK_SIZE).rev() {
+                key[i] ^= key[i - 4];
+            }
+            for col in 0..4 {
+                key[col] ^= key[12 + (col + 1) % 4];
+            }
+            key[0] ^= constant;
+        }
+        key
+    }
+
+    fn multiply_rows(state: Block, coefficients: [u8; 4]) -> Block {
+        std::array::from_fn(|i| {
+            let (row, col) = (i / 4, i % 4);
+            (0..4).fold(0, |acc, k| {
+                acc ^ gf256_mul(state[4 * row + k], coefficients[(4 + col - k) % 4])
+            })
+        })
+    }
+
+    pub fn theta(state: Block) -> Block {
+        Self::multiply_rows(state, THETA)
+    }
+
+    pub fn inv_theta(state: Block) -> Block {
+        Self::multiply_rows(state, INV_THETA)
+    }
+
+    pub fn gamma(&self, state: Block) -> Block {
+        state.map(|x| self.sbox[x as usize])
+    }
+
+    pub fn inv_gamma(&self, state: Block) -> Block {
+        state.map(|x| self.inv_sbox[x as usize])
+    }
+
+    /// Transposition of the state, which is its own inverse.
+    pub fn pi(state: Block) -> Block {
+        std::array::from_fn(|i| state[4 * (i % 4) + i / 4])
+    }
+
+    pub fn sigma(state: Block, round_key: Block) -> Block {
+        std::array::from_fn(|i| state[i] ^ round_key[i])
+    }
+
+    pub fn round(&self, state: Block, round_key: Block) -> Block {
+        Self::sigma(Self::pi(self.gamma(Self::theta(state))), round_key)
+    }
+
+    pub fn inv_round(&self, state: Block, round_key: Block) -> Block {
+        Self::inv_theta(self.inv_gamma(Self::pi(Self::sigma(state, round_key))))
+    }
+
+    pub fn encrypt(&self, msg: Block) -> Block {
+        let state = Self::sigma(Self::inv_theta(msg), self.round_keys[0]);
+        self.round_keys[1..]
+            .iter()
+            .fold(state, |state, &round_key| self.round(state, round_key))
+    }
+
+    pub fn decrypt(&self, enc_msg: Block) -> Block {
+        let state = self.round_keys[1..]
+            .iter()
+            .rev()
+            .fold(enc_msg, |state, &round_key| {
+                self.inv_round(state, round_key)
+            });
+        Self::theta(Self::sigma(state, self.round_keys[0]))
+    }
+}
+
+/// θ mixes the rows of the state, which are stored contiguously like the
+/// columns of AES, and π takes the place of ShiftRows. Since every round ends
+/// in γ, π and σ, the last round peels off exactly like the last round of AES.
+impl CellCipher for Square {
+    fn num_rows(&self) -> usize {
+        4
+    }
+
+    fn num_cols(&self) -> usize {
+        4
+    }
+
+    fn cell_bits(&self) -> u32 {
+        8
+    }
+
+    fn inv_sbox(&self, cell: u8) -> u8 {
+        self.inv_sbox[cell as usize]
+    }
+
+    fn inv_shift_rows(&self, state: &[u8]) -> Vec<u8> {
+        Self::pi(state.try_into().unwrap()).to_vec()
+    }
+
+    fn mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        Self::theta(state.try_into().unwrap()).to_vec()
+    }
+
+    fn inv_mix_columns(&self, state: &[u8]) -> Vec<u8> {
+        Self::inv_theta(state.try_into().unwrap()).to_vec()
+    }
+
+    fn invert_key_expansion(&self, round_key: &[u8], round: usize) -> Vec<u8> {
+        Self::invert_key_expansion(round_key.try_into().unwrap(), round).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic_attack::{crack_column, crack_key_generic, diagonal};
+
+    const KEY: Block = *b"sixteen byte key";
+
+    #[test]
+    fn test_sbox() {
+        // The first entries of the table published with the cipher.
+        let cipher = Square::new(KEY, 8);
+        assert_eq!(
+            cipher.sbox[..16],
+            [
+                0xb1, 0xce, 0xc3, 0x95, 0x5a, 0xad, 0xe7, 0x02, 0x4d, 0x44, 0xfb, 0x91, 0x0c, 0x87,
+                0xa1, 0x50
+            ]
+        );
+        assert!((0..=255).all(|x| cipher.inv_sbox[cipher.sbox[x] as usize] == x as u8));
+    }
+
+    #[test]
+    fn test_theta() {
+        let mut state = [0; BLOCK_SIZE];
+        state[0] = 1;
+        assert_eq!(Square::theta(state)[..4], THETA);
+        let state: Block = std::array::from_fn(|i| (37 * i + 5) as u8);
+        assert_eq!(Square::inv_theta(Square::theta(state)), state);
+    }
+
+    #[test]
+    fn test_pi() {
+        let state: Block = std::array::from_fn(|i| i as u8);
+        assert_eq!(
+            Square::pi(state),
+            [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15]
+        );
+        assert_eq!(Square::pi(Square::pi(state)), state);
+    }
+
+    #[test]
+    fn test_invert_key_expansion() {
+        for (round, round_key) in Square::key_expansion(KEY, 8).into_iter().enumerate() {
+            assert_eq!(Square::invert_key_expansion(round_key, round), KEY);
+        }
+    }
+
+    #[test]
+    fn test_known_answer() {
+        // The test vector of the reference implementation by Barreto.
+        let key: Block = std::array::from_fn(|i| i as u8);
+        let cipher = Square::new(key, 8);
+        let enc_msg = [
+            0x7c, 0x34, 0x91, 0xd9, 0x49, 0x94, 0xe7, 0x0f, 0x0e, 0xc2, 0xe7, 0xa5, 0xcc, 0xb5,
+            0xa1, 0x4f,
+        ];
+        assert_eq!(cipher.encrypt(key), enc_msg);
+        assert_eq!(cipher.decrypt(enc_msg), key);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = Square::new(KEY, 8);
+        let msg = *b"a message block!";
+        let enc_msg = cipher.encrypt(msg);
+        assert_ne!(enc_msg, msg);
+        assert_eq!(cipher.decrypt(enc_msg), msg);
+    }
+
+    #[test]
+    fn test_balanced_after_three_rounds() {
+        let cipher = Square::new(KEY, 3);
+        let sum = (0..=255).fold([0; BLOCK_SIZE], |acc, i| {
+            let mut msg = [0x42; BLOCK_SIZE];
+            msg[0] = i;
+            Square::sigma(acc, cipher.encrypt(msg))
+        });
+        assert_eq!(sum, [0; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_diagonal_is_a_column() {
+        assert_eq!(diagonal(&Square::new(KEY, 6)), vec![0, 4, 8, 12]);
+    }
+
+    #[test]
+    fn test_crack_key_four_rounds() {
+        let cipher = Square::new(KEY, 4);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg.try_into().unwrap()).to_vec();
+        assert_eq!(crack_key_generic(&cipher, &encryption_service, 4), KEY);
+    }
+
+    /// Scaled down from the 6-round attack, whose structures of 2^32 texts are
+    /// out of reach of a test: 5 rounds peel off 2 like 6 do, but start from a
+    /// single active cell, and only 2^10 guesses of the first column are tried.
+    #[test]
+    fn test_crack_column_five_rounds() {
+        let cipher = Square::new(KEY, 5);
+        let encryption_service = |msg: &[u8]| cipher.encrypt(msg.try_into().unwrap()).to_vec();
+        let last_round_key = Square::key_expansion(KEY, 5)[5];
+        let column_guess = (0..4).fold(0, |acc, row| {
+            acc | (last_round_key[4 * row] as usize) << (8 * row)
+        });
+        let guesses = column_guess & !0x3ff..(column_guess & !0x3ff) + 0x400;
+        assert_eq!(
+            crack_column(&cipher, &encryption_service, &[0], 0, guesses),
+            Some(column_guess)
+        );
+    }
+}
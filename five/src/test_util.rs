@@ -0,0 +1,7 @@
+/// Parses a hex string, as test vectors are given.
+pub(crate) fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .flat_map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect()
+}
@@ -0,0 +1,817 @@
+use crate::generic_attack::CellCipher;
+use crate::sbox_analysis::{ddt, lat};
+
+/// Leaves room for rounding when comparing sums of logarithms.
+const EPSILON: f64 = 1e-9;
+
+/// Whether a trail follows differences or linear masks through the cipher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Differential,
+    Linear,
+}
+
+/// The round function of an AES-like cipher as the trail search sees it, read
+/// off a `CellCipher`: the S-box, where ShiftRows moves every cell, and
+/// MixColumns as a binary matrix, which must be the same on every column.
+/// The state may have at most 16 cells.
+pub struct TrailModel {
+    num_rows: usize,
+    cell_bits: u32,
+    sbox: Vec<u8>,
+    /// ShiftRows moves cell `i` to `destinations[i]`.
+    destinations: Vec<usize>,
+    /// The images of the bits of one column under MixColumns, bit `j` of
+    /// row `i` being bit `cell_bits * i + j`.
+    mix_columns: Vec<u32>,
+}
+
+/// A characteristic or linear trail over some rounds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trail {
+    /// The inputs and outputs of the S-box layer of every round.
+    pub rounds: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The probability of the characteristic, or the absolute correlation of
+    /// the linear trail, is `2^-weight`.
+    pub weight: f64,
+    /// Whether the correlation is negative; never for a characteristic.
+    pub negative: bool,
+}
+
+impl Trail {
+    pub fn num_active(&self) -> usize {
+        self.rounds
+            .iter()
+            .map(|(input, _)| input.iter().filter(|&&x| x != 0).count())
+            .sum()
+    }
+}
+
+/// The XOR of the images of the bits set in `x`.
+fn apply(images: &[u32], x: u32) -> u32 {
+    images
+        .iter()
+        .enumerate()
+        .filter(|(k, _)| x >> k & 1 == 1)
+        .fold(0, |acc, (_, image)| acc ^ image)
+}
+
+fn rank(mut vectors: Vec<u32>) -> usize {
+    let mut rank = 0;
+    for bit in 0..32 {
+        let Some(pivot) = (rank..vectors.len()).find(|&i| vectors[i] >> bit & 1 == 1) else {
+            continue;
+        };
+        vectors.swap(rank, pivot);
+        for i in rank + 1..vectors.len() {
+            if vectors[i] >> bit & 1 == 1 {
+                vectors[i] ^= vectors[rank];
+            }
+        }
+        rank += 1;
+    }
+    rank
+}
+
+/// The images of the inverse of the invertible matrix given by `images`.
+fn invert(images: &[u32]) -> Vec<u32> {
+    let n = images.len();
+    let mut pairs: Vec<(u32, u32)> = images
+        .iter()
+        .enumerate()
+        .map(|(k, &y)| (y, 1 << k))
+        .collect();
+    for bit in 0..n {
+        let pivot = (bit..n)
+            .find(|&i| pairs[i].0 >> bit & 1 == 1)
+            .expect("MixColumns must be invertible");
+        pairs.swap(bit, pivot);
+        let (y, x) = pairs[bit];
+        for (i, pair) in pairs.iter_mut().enumerate() {
+            if i != bit && pair.0 >> bit & 1 == 1 {
+                *pair = (pair.0 ^ y, pair.1 ^ x);
+            }
+        }
+    }
+    pairs.into_iter().map(|(_, x)| x).collect()
+}
+
+fn transpose(images: &[u32]) -> Vec<u32> {
+    (0..images.len())
+        .map(|j| {
+            images
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (k, &image)| acc | (image >> j & 1) << k)
+        })
+        .collect()
+}
+
+/// How differences or masks go through the S-box and the linear layer.
+struct Propagation {
+    /// The outputs of every non-zero input as `(output, weight, negative)`,
+    /// best first.
+    outputs: Vec<Vec<(u8, f64, bool)>>,
+    /// The best input of every non-zero output.
+    best_inputs: Vec<(u8, f64, bool)>,
+    /// The smallest weight of any active S-box.
+    min_weight: f64,
+    /// The linear layer on one column from the outputs of the S-boxes to the
+    /// inputs of the next ones, after ShiftRows, and its inverse.
+    forward: Vec<u32>,
+    backward: Vec<u32>,
+    /// The active cells a column can have after the linear layer given those
+    /// it has before, fewest first.
+    transitions: Vec<Vec<usize>>,
+}
+
+struct Search<'a> {
+    model: &'a TrailModel,
+    propagation: Propagation,
+    /// Every non-zero pattern of active cells, fewest first.
+    patterns: Vec<u32>,
+    /// The fewest active S-boxes over `i` rounds, for as many as known.
+    min_active: Vec<usize>,
+    /// The fewest active S-boxes over `i + 1` rounds from every pattern on,
+    /// and whether it is exact or only a lower bound.
+    memo: Vec<Vec<(usize, bool)>>,
+    best: Option<Trail>,
+}
+
+impl TrailModel {
+    pub fn new<C: CellCipher>(cipher: &C) -> Self {
+        let (num_rows, num_cells, cell_bits) =
+            (cipher.num_rows(), cipher.num_cells(), cipher.cell_bits());
+        assert!(num_cells <= 16 && num_rows as u32 * cell_bits <= 32);
+        let mut sbox = vec![0; 1 << cell_bits];
+        for y in 0..sbox.len() {
+            sbox[cipher.inv_sbox(y as u8) as usize] = y as u8;
+        }
+        // The inverse of ShiftRows takes the cell at `destinations[i]` back
+        // to `i`.
+        let identity: Vec<u8> = (0..num_cells as u8).collect();
+        let destinations = cipher
+            .inv_shift_rows(&identity)
+            .into_iter()
+            .map(usize::from)
+            .collect();
+        let mix_columns = (0..num_rows as u32 * cell_bits)
+            .map(|k| {
+                let mut state = vec![0; num_cells];
+                state[(k / cell_bits) as usize] = 1 << (k % cell_bits);
+                cipher.mix_columns(&state)[..num_rows]
+                    .iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, &cell)| {
+                        acc | (cell as u32) << (cell_bits * i as u32)
+                    })
+            })
+            .collect();
+        Self {
+            num_rows,
+            cell_bits,
+            sbox,
+            destinations,
+            mix_columns,
+        }
+    }
+
+    fn num_cells(&self) -> usize {
+        self.destinations.len()
+    }
+
+    fn num_cols(&self) -> usize {
+        self.num_cells() / self.num_rows
+    }
+
+    fn cell_mask(&self) -> u32 {
+        (1 << self.cell_bits) - 1
+    }
+
+    /// The fewest S-boxes active in any characteristic or linear trail over
+    /// `num_rounds`.
+    pub fn min_active_sboxes(&self, kind: Kind, num_rounds: usize) -> usize {
+        self.search(kind).min_active_sboxes(num_rounds)
+    }
+
+    /// The weight every characteristic or linear trail over `num_rounds` has
+    /// at least, from its active S-boxes and the best entry of the DDT or LAT.
+    pub fn weight_bound(&self, kind: Kind, num_rounds: usize) -> f64 {
+        let mut search = self.search(kind);
+        search.min_active_sboxes(num_rounds) as f64 * search.propagation.min_weight
+    }
+
+    /// The characteristic of the highest probability or the linear trail of
+    /// the highest absolute correlation over `num_rounds`, by branch and bound
+    /// on the values of the differences or masks. This is only practical
+    /// while the bound from active S-boxes is tight, e.g. up to 4 rounds of
+    /// AES.
+    pub fn best_trail(&self, kind: Kind, num_rounds: usize) -> Trail {
+        let mut search = self.search(kind);
+        search.best_trail(num_rounds);
+        search.best.unwrap()
+    }
+
+    fn search(&self, kind: Kind) -> Search<'_> {
+        let mut patterns: Vec<u32> = (1..1 << self.num_cells()).collect();
+        patterns.sort_by_key(|pattern| pattern.count_ones());
+        Search {
+            model: self,
+            propagation: self.propagation(kind),
+            patterns,
+            min_active: vec![0],
+            memo: vec![],
+            best: None,
+        }
+    }
+
+    fn propagation(&self, kind: Kind) -> Propagation {
+        let (table, forward, backward) = match kind {
+            Kind::Differential => (
+                ddt(&self.sbox),
+                self.mix_columns.clone(),
+                invert(&self.mix_columns),
+            ),
+            // A mask `u` after the linear layer `A` equals the mask `A^T u`
+            // before it.
+            Kind::Linear => (
+                lat(&self.sbox),
+                transpose(&invert(&self.mix_columns)),
+                transpose(&self.mix_columns),
+            ),
+        };
+        let magnitude = match kind {
+            Kind::Differential => self.cell_bits as f64,
+            Kind::Linear => self.cell_bits as f64 - 1.0,
+        };
+        let weight = |entry: i32| (entry != 0).then(|| magnitude - (entry.abs() as f64).log2());
+        let size = self.sbox.len();
+        let outputs: Vec<Vec<(u8, f64, bool)>> = (0..size)
+            .map(|a| {
+                let mut outputs: Vec<_> = (1..size)
+                    .filter_map(|b| {
+                        let entry = table.get(a, b);
+                        weight(entry).map(|w| (b as u8, w, entry < 0))
+                    })
+                    .collect();
+                outputs.sort_by(|x, y| x.1.total_cmp(&y.1));
+                outputs
+            })
+            .collect();
+        let best_inputs = (0..size)
+            .map(|b| {
+                (1..size)
+                    .filter_map(|a| {
+                        let entry = table.get(a, b);
+                        weight(entry).map(|w| (a as u8, w, entry < 0))
+                    })
+                    .min_by(|x, y| x.1.total_cmp(&y.1))
+                    .unwrap_or((0, 0.0, false))
+            })
+            .collect();
+        let min_weight = outputs[1..]
+            .iter()
+            .filter_map(|outputs| outputs.first())
+            .map(|&(_, w, _)| w)
+            .fold(f64::INFINITY, f64::min);
+        let transitions = self.column_transitions(&forward);
+        Propagation {
+            outputs,
+            best_inputs,
+            min_weight,
+            forward,
+            backward,
+            transitions,
+        }
+    }
+
+    /// The active cells a column can have after the binary matrix given by
+    /// `images`, fewest first, for every set of active cells before it. The
+    /// solutions without other active cells form a space, and some have
+    /// exactly those cells active unless the space is the union of its
+    /// subspaces with one more inactive cell. As the matrix is linear over a
+    /// field of at least 8 elements, that only happens if one of them is the
+    /// whole space.
+    fn column_transitions(&self, images: &[u32]) -> Vec<Vec<usize>> {
+        let cell_bits = self.cell_bits as usize;
+        let dimension = |inputs: usize, outputs: usize| -> usize {
+            let inactive: u32 = (0..self.num_rows)
+                .filter(|i| outputs >> i & 1 == 0)
+                .fold(0, |acc, i| acc | self.cell_mask() << (cell_bits * i));
+            let vectors: Vec<u32> = (0..images.len())
+                .filter(|k| inputs >> (k / cell_bits) & 1 == 1)
+                .map(|k| images[k] & inactive)
+                .collect();
+            vectors.len() - rank(vectors)
+        };
+        let num_patterns = 1 << self.num_rows;
+        (0..num_patterns)
+            .map(|inputs: usize| {
+                let mut outputs: Vec<usize> = (0..num_patterns)
+                    .filter(|&outputs| {
+                        if inputs == 0 {
+                            return outputs == 0;
+                        }
+                        let full = dimension(inputs, outputs);
+                        let cells = (0..self.num_rows).map(|i| 1 << i);
+                        cells
+                            .clone()
+                            .filter(|c| inputs & c != 0)
+                            .all(|c| dimension(inputs ^ c, outputs) < full)
+                            && cells
+                                .filter(|c| outputs & c != 0)
+                                .all(|c| dimension(inputs, outputs ^ c) < full)
+                    })
+                    .collect();
+                outputs.sort_by_key(|outputs| outputs.count_ones());
+                outputs
+            })
+            .collect()
+    }
+
+    fn shift_rows<T: Copy + Default>(&self, state: &[T]) -> Vec<T> {
+        let mut shifted = vec![T::default(); state.len()];
+        for (i, &x) in state.iter().enumerate() {
+            shifted[self.destinations[i]] = x;
+        }
+        shifted
+    }
+
+    fn inv_shift_rows<T: Copy>(&self, state: &[T]) -> Vec<T> {
+        self.destinations.iter().map(|&i| state[i]).collect()
+    }
+
+    fn apply_to_columns(&self, images: &[u32], state: &[u8]) -> Vec<u8> {
+        let cell_bits = self.cell_bits as usize;
+        state
+            .chunks(self.num_rows)
+            .flat_map(|column| {
+                let x = column
+                    .iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, &cell)| acc | (cell as u32) << (cell_bits * i));
+                let y = apply(images, x);
+                (0..self.num_rows).map(move |i| (y >> (cell_bits * i) & self.cell_mask()) as u8)
+            })
+            .collect()
+    }
+
+    fn shift_pattern(&self, pattern: u32) -> u32 {
+        (0..self.num_cells())
+            .filter(|i| pattern >> i & 1 == 1)
+            .fold(0, |acc, i| acc | 1 << self.destinations[i])
+    }
+
+    /// The active cells of the column `col` of `pattern`.
+    fn column(&self, pattern: u32, col: usize) -> usize {
+        (pattern >> (self.num_rows * col)) as usize & ((1 << self.num_rows) - 1)
+    }
+}
+
+fn pattern(state: &[u8]) -> u32 {
+    state
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, &x)| acc | ((x != 0) as u32) << i)
+}
+
+impl Search<'_> {
+    fn lower_bound(&self, num_rounds: usize) -> usize {
+        self.min_active
+            .get(num_rounds)
+            .copied()
+            .unwrap_or(num_rounds)
+    }
+
+    fn min_active_sboxes(&mut self, num_rounds: usize) -> usize {
+        while self.min_active.len() <= num_rounds {
+            let rounds = self.min_active.len();
+            let mut best = self.model.num_cells() * rounds + 1;
+            for i in 0..self.patterns.len() {
+                let pattern = self.patterns[i];
+                if pattern.count_ones() as usize + self.lower_bound(rounds - 1) >= best {
+                    break;
+                }
+                best = best.min(self.min_active_from(pattern, rounds, best));
+            }
+            self.min_active.push(best);
+        }
+        self.min_active[num_rounds]
+    }
+
+    /// The fewest active S-boxes over `num_rounds` starting with the active
+    /// cells in `pattern` if fewer than `limit`, else at least `limit`.
+    fn min_active_from(&mut self, pattern: u32, num_rounds: usize, limit: usize) -> usize {
+        let own = pattern.count_ones() as usize;
+        if num_rounds == 1 || own >= limit {
+            return own;
+        }
+        if self.memo.len() < num_rounds {
+            self.memo.resize(num_rounds, vec![]);
+        }
+        if self.memo[num_rounds - 1].is_empty() {
+            self.memo[num_rounds - 1] = vec![(0, false); 1 << self.model.num_cells()];
+        }
+        let (count, exact) = self.memo[num_rounds - 1][pattern as usize];
+        if exact || count >= limit {
+            return count;
+        }
+        let shifted = self.model.shift_pattern(pattern);
+        let columns: Vec<usize> = (0..self.model.num_cols())
+            .map(|col| self.model.column(shifted, col))
+            .collect();
+        let mut best = limit;
+        self.min_over_successors(&columns, 0, 0, own, num_rounds, &mut best);
+        self.memo[num_rounds - 1][pattern as usize] = (best, best < limit);
+        best
+    }
+
+    fn min_over_successors(
+        &mut self,
+        columns: &[usize],
+        col: usize,
+        next: u32,
+        own: usize,
+        num_rounds: usize,
+        best: &mut usize,
+    ) {
+        if col == columns.len() {
+            let total = own + self.min_active_from(next, num_rounds - 1, *best - own);
+            *best = (*best).min(total);
+            return;
+        }
+        let remaining: usize = columns[col + 1..]
+            .iter()
+            .map(|&c| self.propagation.transitions[c][0].count_ones() as usize)
+            .sum();
+        for i in 0..self.propagation.transitions[columns[col]].len() {
+            let outputs = self.propagation.transitions[columns[col]][i];
+            let active = (next.count_ones() + outputs.count_ones()) as usize + remaining;
+            let bound =
+                (active + self.lower_bound(num_rounds - 2)).max(self.lower_bound(num_rounds - 1));
+            if own + bound >= *best {
+                break;
+            }
+            let next = next | (outputs as u32) << (self.model.num_rows * col);
+            self.min_over_successors(columns, col + 1, next, own, num_rounds, best);
+        }
+    }
+
+    fn best_weight(&self) -> f64 {
+        self.best
+            .as_ref()
+            .map_or(f64::INFINITY, |trail| trail.weight)
+    }
+
+    /// The most active S-boxes worth trying with `weight` spent.
+    fn active_limit(&self, weight: f64) -> usize {
+        let remaining = (self.best_weight() - weight) / self.propagation.min_weight;
+        if remaining.is_finite() {
+            remaining.ceil().max(0.0) as usize
+        } else {
+            usize::MAX / 2
+        }
+    }
+
+    fn best_trail(&mut self, num_rounds: usize) {
+        if num_rounds == 1 {
+            let (input, (output, weight, negative)) = (1..self.propagation.outputs.len())
+                .map(|a| (a as u8, self.propagation.outputs[a][0]))
+                .min_by(|x, y| x.1 .1.total_cmp(&y.1 .1))
+                .unwrap();
+            let mut state = (
+                vec![0; self.model.num_cells()],
+                vec![0; self.model.num_cells()],
+            );
+            (state.0[0], state.1[0]) = (input, output);
+            self.best = Some(Trail {
+                rounds: vec![state],
+                weight,
+                negative,
+            });
+            return;
+        }
+        // Start from the inputs of the second round, whose first round is
+        // then best with the best input for every output.
+        self.min_active_sboxes(num_rounds - 1);
+        // Fewest active cells first among equal bounds, as they have the
+        // fewest values to try.
+        let mut candidates: Vec<(usize, u32, usize, u32)> = self
+            .patterns
+            .iter()
+            .map(|&pattern| {
+                let first: usize = (0..self.model.num_cols())
+                    .map(|col| self.min_predecessor(self.model.column(pattern, col)))
+                    .sum();
+                let bound =
+                    first + pattern.count_ones() as usize + self.lower_bound(num_rounds - 2);
+                (bound, pattern.count_ones(), first, pattern)
+            })
+            .collect();
+        candidates.sort();
+        let min_weight = self.propagation.min_weight;
+        for (bound, _, first, pattern) in candidates {
+            if bound as f64 * min_weight >= self.best_weight() - EPSILON {
+                break;
+            }
+            let limit = self.active_limit(0.0).saturating_sub(first).max(1);
+            let rest = self.min_active_from(pattern, num_rounds - 1, limit);
+            if (first + rest) as f64 * min_weight >= self.best_weight() - EPSILON {
+                continue;
+            }
+            let cells: Vec<usize> = (0..self.model.num_cells())
+                .filter(|i| pattern >> i & 1 == 1)
+                .collect();
+            let mut state = vec![0; self.model.num_cells()];
+            self.enumerate_inputs(&cells, &mut state, rest, num_rounds);
+        }
+    }
+
+    /// The fewest active cells a column can have before the linear layer to
+    /// have the active cells `outputs` after it.
+    fn min_predecessor(&self, outputs: usize) -> usize {
+        (0..self.propagation.transitions.len())
+            .filter(|&inputs| self.propagation.transitions[inputs].contains(&outputs))
+            .map(|inputs| inputs.count_ones() as usize)
+            .min()
+            .unwrap()
+    }
+
+    /// Tries every value of the active `cells` of the inputs of the second
+    /// round, which need at least `rest` active S-boxes from there on.
+    fn enumerate_inputs(
+        &mut self,
+        cells: &[usize],
+        state: &mut Vec<u8>,
+        rest: usize,
+        num_rounds: usize,
+    ) {
+        let Some((&cell, cells)) = cells.split_first() else {
+            let output = self.model.inv_shift_rows(
+                &self
+                    .model
+                    .apply_to_columns(&self.propagation.backward, state),
+            );
+            let input: Vec<u8> = output
+                .iter()
+                .map(|&b| self.propagation.best_inputs[b as usize].0)
+                .collect();
+            let (weight, negative) = output
+                .iter()
+                .filter(|&&b| b != 0)
+                .map(|&b| self.propagation.best_inputs[b as usize])
+                .fold((0.0, false), |(w, n), (_, weight, negative)| {
+                    (w + weight, n ^ negative)
+                });
+            if weight + rest as f64 * self.propagation.min_weight < self.best_weight() - EPSILON {
+                let mut rounds = vec![(input, output)];
+                self.extend(&mut rounds, state.clone(), weight, negative, num_rounds - 1);
+            }
+            return;
+        };
+        for x in 1..=self.model.cell_mask() as u8 {
+            state[cell] = x;
+            self.enumerate_inputs(cells, state, rest, num_rounds);
+        }
+        state[cell] = 0;
+    }
+
+    /// Continues the trail in `rounds` with `input` into the next of
+    /// `rounds_left` rounds.
+    fn extend(
+        &mut self,
+        rounds: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        input: Vec<u8>,
+        weight: f64,
+        negative: bool,
+        rounds_left: usize,
+    ) {
+        let cells: Vec<usize> = (0..input.len()).filter(|&i| input[i] != 0).collect();
+        let mut output = vec![0; input.len()];
+        if rounds_left == 1 {
+            let (mut weight, mut negative) = (weight, negative);
+            for &i in &cells {
+                let (b, w, n) = self.propagation.outputs[input[i] as usize][0];
+                output[i] = b;
+                weight += w;
+                negative ^= n;
+            }
+            if weight < self.best_weight() - EPSILON {
+                let mut rounds = rounds.clone();
+                rounds.push((input, output));
+                self.best = Some(Trail {
+                    rounds,
+                    weight,
+                    negative,
+                });
+            }
+            return;
+        }
+        // The S-boxes active in later rounds, whatever their outputs.
+        let limit = self.active_limit(weight).max(cells.len() + 1);
+        let later = self.min_active_from(pattern(&input), rounds_left, limit) - cells.len();
+        rounds.push((input, vec![]));
+        self.choose_outputs(
+            rounds,
+            &cells,
+            &mut output,
+            (weight, negative),
+            rounds_left,
+            later,
+        );
+        rounds.pop();
+    }
+
+    /// Tries the outputs of the active `cells` left in the current round,
+    /// after which `later` S-boxes are active at least.
+    fn choose_outputs(
+        &mut self,
+        rounds: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        cells: &[usize],
+        output: &mut Vec<u8>,
+        (weight, negative): (f64, bool),
+        rounds_left: usize,
+        later: usize,
+    ) {
+        let min_weight = self.propagation.min_weight;
+        let Some((&cell, cells)) = cells.split_first() else {
+            let next = self
+                .model
+                .apply_to_columns(&self.propagation.forward, &self.model.shift_rows(output));
+            let limit = self.active_limit(weight);
+            let rest = self.min_active_from(pattern(&next), rounds_left - 1, limit.max(1));
+            if weight + rest as f64 * min_weight < self.best_weight() - EPSILON {
+                rounds.last_mut().unwrap().1 = output.clone();
+                self.extend(rounds, next, weight, negative, rounds_left - 1);
+            }
+            return;
+        };
+        let a = rounds.last().unwrap().0[cell] as usize;
+        let remaining = (cells.len() + later) as f64 * min_weight;
+        for i in 0..self.propagation.outputs[a].len() {
+            let (b, w, n) = self.propagation.outputs[a][i];
+            if weight + w + remaining >= self.best_weight() - EPSILON {
+                break;
+            }
+            output[cell] = b;
+            let score = (weight + w, negative ^ n);
+            self.choose_outputs(rounds, cells, output, score, rounds_left, later);
+        }
+        output[cell] = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::aes_like::{is_mds, AesLike, AesLikeParams};
+    use crate::rijndael::Rijndael;
+    use crate::small_aes::SmallAES;
+
+    fn aes_model() -> TrailModel {
+        TrailModel::new(&Rijndael::new(&[0; 16], 4, 10))
+    }
+
+    fn small_aes_model() -> TrailModel {
+        TrailModel::new(&SmallAES::new(&[0; 16], 4, 4, 10))
+    }
+
+    fn parity(x: &[u8], y: &[u8]) -> u32 {
+        x.iter()
+            .zip(y)
+            .map(|(a, b)| (a & b).count_ones())
+            .sum::<u32>()
+            % 2
+    }
+
+    /// Checks `trail` against the S-box tables and the inverse round
+    /// functions of `cipher`.
+    fn assert_valid<C: CellCipher>(cipher: &C, kind: Kind, trail: &Trail) {
+        let model = TrailModel::new(cipher);
+        let table = match kind {
+            Kind::Differential => ddt(&model.sbox),
+            Kind::Linear => lat(&model.sbox),
+        };
+        let size = model.sbox.len() as f64;
+        let mut weight = 0.0;
+        let mut negative = false;
+        for (input, output) in &trail.rounds {
+            for (&a, &b) in input.iter().zip(output) {
+                let entry = table.get(a as usize, b as usize);
+                assert_ne!(entry, 0);
+                assert_eq!(a == 0, b == 0);
+                if a != 0 {
+                    weight += match kind {
+                        Kind::Differential => (size / entry as f64).log2(),
+                        Kind::Linear => (size / 2.0 / entry.abs() as f64).log2(),
+                    };
+                    negative ^= entry < 0;
+                }
+            }
+        }
+        assert!((weight - trail.weight).abs() < EPSILON);
+        assert_eq!(negative, trail.negative);
+        for window in trail.rounds.windows(2) {
+            let (output, next) = (&window[0].1, &window[1].0);
+            match kind {
+                Kind::Differential => {
+                    assert_eq!(
+                        &cipher.inv_shift_rows(&cipher.inv_mix_columns(next)),
+                        output
+                    );
+                }
+                Kind::Linear => {
+                    for _ in 0..100 {
+                        let state: Vec<u8> = (0..cipher.num_cells())
+                            .map(|_| thread_rng().gen::<u8>() & model.cell_mask() as u8)
+                            .collect();
+                        let before = cipher.inv_shift_rows(&cipher.inv_mix_columns(&state));
+                        assert_eq!(parity(next, &state), parity(output, &before));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_column_transitions() {
+        // A column of an MDS matrix has at least 5 active cells on both
+        // sides, and all of those patterns happen.
+        let model = aes_model();
+        for kind in [Kind::Differential, Kind::Linear] {
+            let transitions = model.propagation(kind).transitions;
+            for (inputs, possible_outputs) in transitions.iter().enumerate().skip(1) {
+                for outputs in 0..16usize {
+                    let possible = possible_outputs.contains(&outputs);
+                    assert_eq!(possible, (inputs | outputs << 4).count_ones() >= 5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_active_sboxes() {
+        for model in [aes_model(), small_aes_model()] {
+            for kind in [Kind::Differential, Kind::Linear] {
+                let counts: Vec<usize> =
+                    (1..=4).map(|r| model.min_active_sboxes(kind, r)).collect();
+                assert_eq!(counts, [1, 5, 9, 25]);
+            }
+        }
+        assert_eq!(aes_model().weight_bound(Kind::Differential, 4), 150.0);
+        assert_eq!(aes_model().weight_bound(Kind::Linear, 4), 75.0);
+    }
+
+    #[test]
+    fn test_no_diffusion() {
+        // Without MixColumns every trail can stay in a single S-box.
+        let mut params = AesLikeParams::with_random_sbox(&mut thread_rng());
+        params.mix_matrix = [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]];
+        assert!(!is_mds(params.mix_matrix));
+        let model = TrailModel::new(&AesLike::new([0; 16], 10, params));
+        assert_eq!(model.min_active_sboxes(Kind::Differential, 6), 6);
+        assert_eq!(model.min_active_sboxes(Kind::Linear, 6), 6);
+    }
+
+    #[test]
+    fn test_best_trails_aes() {
+        let aes = Rijndael::new(&[0; 16], 4, 10);
+        let model = aes_model();
+        // Every active S-box reaches the best entry of its table.
+        for (kind, weights) in [
+            (Kind::Differential, [6.0, 30.0, 54.0, 150.0]),
+            (Kind::Linear, [3.0, 15.0, 27.0, 75.0]),
+        ] {
+            for (num_rounds, weight) in (1..=4).zip(weights) {
+                let trail = model.best_trail(kind, num_rounds);
+                assert_eq!(trail.rounds.len(), num_rounds);
+                assert_eq!(trail.weight, weight);
+                assert_eq!(
+                    trail.num_active(),
+                    model.min_active_sboxes(kind, num_rounds)
+                );
+                assert_valid(&aes, kind, &trail);
+            }
+        }
+    }
+
+    #[test]
+    fn test_best_trails_small_aes() {
+        let cipher = SmallAES::new(&[0; 16], 4, 4, 10);
+        let model = small_aes_model();
+        // The optimal weights for 1, 2 and 3 rounds.
+        for (kind, weights) in [
+            (Kind::Differential, [2.0, 10.0, 18.0]),
+            (Kind::Linear, [1.0, 5.0, 9.0]),
+        ] {
+            for (num_rounds, weight) in (1..=3).zip(weights) {
+                let trail = model.best_trail(kind, num_rounds);
+                assert_eq!(trail.weight, weight);
+                assert!(trail.weight >= model.weight_bound(kind, num_rounds));
+                assert_valid(&cipher, kind, &trail);
+            }
+        }
+    }
+}
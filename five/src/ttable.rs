@@ -0,0 +1,152 @@
+use crate::aes::{Block, BLOCK_SIZE};
+use crate::rijndael::{gf256_mul, sbox, Rijndael, MIX_COLUMNS};
+
+/// Entries of a table sharing a cache line: 64-byte lines of 4-byte entries.
+pub const LINE_ENTRIES: usize = 16;
+/// Lines of a table of 256 entries.
+pub const NUM_LINES: usize = 256 / LINE_ENTRIES;
+/// The table of the last round, `Te4` in OpenSSL: the S-box in every byte.
+pub const LAST_ROUND_TABLE: usize = 4;
+
+/// A lookup in one of the tables, as a cache sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheAccess {
+    pub round: usize,
+    pub table: usize,
+    pub line: usize,
+}
+
+/// The table lookups of one encryption, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheTrace {
+    pub accesses: Vec<CacheAccess>,
+}
+
+impl CacheTrace {
+    /// The lines of `table` the encryption touched, one bit each, which is
+    /// what a spy process learns by priming the cache before the encryption
+    /// and probing it after.
+    pub fn touched_lines(&self, table: usize) -> u16 {
+        self.accesses
+            .iter()
+            .filter(|access| access.table == table)
+            .fold(0, |lines, access| lines | 1 << access.line)
+    }
+}
+
+/// AES-128 the way software implementations did it before AES-NI: every
+/// round but the last is 16 lookups in four tables combining SubBytes,
+/// ShiftRows and MixColumns, and the last one uses a fifth table. Which
+/// entries are looked up depends on the key, and with them which cache
+/// lines are loaded, which is what the cache attacks of `cache` exploit and
+/// why `AES128` uses the AES-NI instructions instead.
+pub struct TTableAES {
+    round_keys: Vec<Block>,
+    num_rounds: usize,
+    tables: [[u32; 256]; 5],
+}
+
+impl TTableAES {
+    pub fn new(key: Block, num_rounds: usize) -> Self {
+        let round_keys = Rijndael::new(&key, 4, num_rounds)
+            .key_expansion(&key)
+            .into_iter()
+            .map(|round_key| round_key.try_into().unwrap())
+            .collect();
+        let sbox = sbox();
+        // Table `r` maps a byte in row `r` to its column after SubBytes and
+        // MixColumns, i.e. its S-box output times column `r` of the matrix.
+        let mut tables = [[0; 256]; 5];
+        for x in 0..256 {
+            for (r, table) in tables[..4].iter_mut().enumerate() {
+                let column: [u8; 4] =
+                    std::array::from_fn(|row| gf256_mul(MIX_COLUMNS[(4 + r - row) % 4], sbox[x]));
+                table[x] = u32::from_le_bytes(column);
+            }
+            tables[LAST_ROUND_TABLE][x] = u32::from_le_bytes([sbox[x]; 4]);
+        }
+        Self {
+            round_keys,
+            num_rounds,
+            tables,
+        }
+    }
+
+    pub fn encrypt(&self, msg: Block) -> Block {
+        self.encrypt_with_cache_trace(msg).0
+    }
+
+    /// Same as `encrypt`, but also records every table lookup.
+    pub fn encrypt_with_cache_trace(&self, msg: Block) -> (Block, CacheTrace) {
+        let mut trace = CacheTrace::default();
+        let mut lookup = |round: usize, table: usize, index: u8| {
+            trace.accesses.push(CacheAccess {
+                round,
+                table,
+                line: index as usize / LINE_ENTRIES,
+            });
+            self.tables[table][index as usize]
+        };
+
+        let mut state: Block = std::array::from_fn(|i| msg[i] ^ self.round_keys[0][i]);
+        for round in 1..=self.num_rounds {
+            let mut next = [0; BLOCK_SIZE];
+            for col in 0..4 {
+                // ShiftRows takes row `r` of this column from column `col + r`.
+                let column = (0..4).fold(0, |column, r| {
+                    let index = state[4 * ((col + r) % 4) + r];
+                    if round == self.num_rounds {
+                        column | (lookup(round, LAST_ROUND_TABLE, index) & 0xff << (8 * r))
+                    } else {
+                        column ^ lookup(round, r, index)
+                    }
+                });
+                next[4 * col..4 * col + 4].copy_from_slice(&column.to_le_bytes());
+            }
+            state = std::array::from_fn(|i| next[i] ^ self.round_keys[round][i]);
+        }
+        (state, trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+    use crate::attack::gen_random_block;
+
+    #[test]
+    fn test_matches_aes() {
+        for num_rounds in [4, 10] {
+            let key = gen_random_block();
+            let ttable = TTableAES::new(key, num_rounds);
+            let msg = gen_random_block();
+            unsafe {
+                assert_eq!(
+                    ttable.encrypt(msg),
+                    AES128::new(key, num_rounds).encrypt(msg)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cache_trace() {
+        let key = gen_random_block();
+        let ttable = TTableAES::new(key, 10);
+        let msg = gen_random_block();
+        let (_, trace) = ttable.encrypt_with_cache_trace(msg);
+        assert_eq!(trace.accesses.len(), 160);
+        // The first lookup in table 0 is byte 0 of the state after the initial
+        // key addition.
+        assert_eq!(trace.accesses[0].table, 0);
+        assert_eq!(
+            trace.accesses[0].line,
+            (msg[0] ^ key[0]) as usize / LINE_ENTRIES
+        );
+        assert_ne!(trace.touched_lines(0) & 1 << trace.accesses[0].line, 0);
+        assert!(trace.accesses[144..]
+            .iter()
+            .all(|access| access.round == 10 && access.table == LAST_ROUND_TABLE));
+    }
+}
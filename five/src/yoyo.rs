@@ -0,0 +1,328 @@
+use rand::{thread_rng, Rng};
+
+use crate::aes::{Block, BLOCK_SIZE};
+use crate::attack::{gen_random_block, DecryptionService, EncryptionService};
+use crate::rijndael::{gf256_mul, sbox, MIX_COLUMNS};
+
+/// Sets of ciphertext anti-diagonals swapped to make new pairs. Swapping the
+/// complement of a set gives the same pair in the other order, so no two of
+/// these are complements.
+const SWAPS: [u8; 5] = [0b0001, 0b0010, 0b0100, 0b1000, 0b0011];
+
+/// The byte positions of diagonal `diag`, ordered by row. ShiftRows moves
+/// them into column `diag`.
+pub fn diagonal(diag: usize) -> [usize; 4] {
+    std::array::from_fn(|row| 4 * ((diag + row) % 4) + row)
+}
+
+/// The byte positions which ShiftRows fills from column `diag`, ordered by
+/// row.
+pub fn anti_diagonal(diag: usize) -> [usize; 4] {
+    std::array::from_fn(|row| 4 * ((diag + 4 - row) % 4) + row)
+}
+
+/// Bit `i` is set iff `a` and `b` agree on diagonal `i`.
+pub fn zero_diagonals(a: Block, b: Block) -> u8 {
+    (0..4)
+        .filter(|&diag| diagonal(diag).iter().all(|&pos| a[pos] == b[pos]))
+        .fold(0, |acc, diag| acc | 1 << diag)
+}
+
+/// One move of the yoyo game: encrypt both plaintexts, swap the ciphertext
+/// anti-diagonals in `swap` between them and decrypt again.
+///
+/// Four rounds of AES (without the last MixColumns) are two layers of
+/// super-boxes acting on diagonals of the plaintext and anti-diagonals of the
+/// ciphertext, separated by a linear map. Swapping words at the output of a
+/// super-box layer swaps them at its input, and keeps the difference in
+/// between the layers, so the new plaintexts differ in exactly the same
+/// diagonals as the old ones.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn yoyo<E: EncryptionService, D: DecryptionService>(
+    encryption_service: &E,
+    decryption_service: &D,
+    pair: (Block, Block),
+    swap: u8,
+) -> (Block, Block) {
+    let (c0, c1) = (
+        encryption_service.encrypt(pair.0),
+        encryption_service.encrypt(pair.1),
+    );
+    swap_and_decrypt(decryption_service, (c0, c1), swap)
+}
+
+#[target_feature(enable = "avx2,aes")]
+unsafe fn swap_and_decrypt<D: DecryptionService>(
+    decryption_service: &D,
+    (mut c0, mut c1): (Block, Block),
+    swap: u8,
+) -> (Block, Block) {
+    for diag in (0..4).filter(|diag| (swap >> diag) & 1 == 1) {
+        for pos in anti_diagonal(diag) {
+            std::mem::swap(&mut c0[pos], &mut c1[pos]);
+        }
+    }
+    (
+        decryption_service.decrypt(c0),
+        decryption_service.decrypt(c1),
+    )
+}
+
+/// A random pair of plaintexts differing in every byte of diagonal `diag`
+/// and nowhere else.
+fn gen_pair(diag: usize) -> (Block, Block) {
+    let p0 = gen_random_block();
+    let mut p1 = p0;
+    for pos in diagonal(diag) {
+        p1[pos] ^= thread_rng().gen_range(1..=255);
+    }
+    (p0, p1)
+}
+
+/// The deterministic 4-round yoyo distinguisher: whether plaintext pairs
+/// differing in one diagonal keep differing in only that diagonal under
+/// yoyos. For a random permutation this fails except with probability about
+/// 2^-96 per yoyo.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn is_four_round_aes<E: EncryptionService, D: DecryptionService>(
+    encryption_service: &E,
+    decryption_service: &D,
+) -> bool {
+    (0..4).all(|diag| {
+        let pair = gen_pair(diag);
+        SWAPS.iter().all(|&swap| {
+            let (p0, p1) = yoyo(encryption_service, decryption_service, pair, swap);
+            zero_diagonals(p0, p1) == 0b1111 ^ (1 << diag)
+        })
+    })
+}
+
+/// Row `row` of the difference after SubBytes and MixColumns of the two
+/// columns `a` and `b` under `key`, as a table over each key byte. The
+/// difference is the XOR of the four tables.
+fn difference_tables(sbox: &[u8; 256], a: [u8; 4], b: [u8; 4], row: usize) -> [[u8; 256]; 4] {
+    std::array::from_fn(|i| {
+        std::array::from_fn(|k| {
+            let diff = sbox[(a[i] ^ k as u8) as usize] ^ sbox[(b[i] ^ k as u8) as usize];
+            gf256_mul(MIX_COLUMNS[(4 + i - row) % 4], diff)
+        })
+    })
+}
+
+/// The diagonals of the first round key under which the first round maps
+/// both `pair` and all of `children` to differences with a zero in row `row`.
+/// The condition splits into two halves of 16 key bits, which are joined on
+/// their 16-bit value for the pair and the first child; the roughly 2^16 keys
+/// satisfying both are then filtered by the other children.
+fn diagonal_candidates(
+    sbox: &[u8; 256],
+    pair: ([u8; 4], [u8; 4]),
+    children: &[([u8; 4], [u8; 4])],
+    row: usize,
+) -> Vec<[u8; 4]> {
+    let tables = difference_tables(sbox, pair.0, pair.1, row);
+    let child_tables: Vec<[[u8; 256]; 4]> = children
+        .iter()
+        .map(|&(a, b)| difference_tables(sbox, a, b, row))
+        .collect();
+    let (first, rest) = child_tables.split_first().unwrap();
+    let half =
+        |tables: &[[u8; 256]; 4], i: usize, ka: usize, kb: usize| tables[i][ka] ^ tables[i + 1][kb];
+    let join_key =
+        |i, ka, kb| half(&tables, i, ka, kb) as usize | (half(first, i, ka, kb) as usize) << 8;
+
+    // The right halves with equal join keys are chained through `next`,
+    // starting from `first_half`, indexed by k2 * 256 + k3.
+    const NONE: usize = usize::MAX;
+    let mut first_half = vec![NONE; 1 << 16];
+    let mut next = vec![NONE; 1 << 16];
+    for (right, next) in next.iter_mut().enumerate() {
+        let join_key = join_key(2, right >> 8, right & 0xff);
+        *next = first_half[join_key];
+        first_half[join_key] = right;
+    }
+
+    let mut candidates = vec![];
+    for k0 in 0..256 {
+        for k1 in 0..256 {
+            let mut right = first_half[join_key(0, k0, k1)];
+            while right != NONE {
+                let (k2, k3) = (right >> 8, right & 0xff);
+                right = next[right];
+                if rest
+                    .iter()
+                    .all(|tables| half(tables, 0, k0, k1) == half(tables, 2, k2, k3))
+                {
+                    candidates.push([k0 as u8, k1 as u8, k2 as u8, k3 as u8]);
+                }
+            }
+        }
+    }
+    candidates
+}
+
+fn diagonal_bytes(block: Block, diag: usize) -> [u8; 4] {
+    diagonal(diag).map(|pos| block[pos])
+}
+
+/// Recovers diagonal `diag` of the key of 5-round AES. A pair differing only
+/// in that diagonal differs in one column after the first round. With
+/// probability about 2^-6 that column difference has a zero byte, i.e. one
+/// diagonal of the difference is zero, and since the last four rounds are
+/// the 4-round yoyo, the yoyos of the pair share that zero.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_diagonal<E: EncryptionService, D: DecryptionService>(
+    encryption_service: &E,
+    decryption_service: &D,
+    diag: usize,
+) -> [u8; 4] {
+    let sbox = sbox();
+    loop {
+        let pair = gen_pair(diag);
+        let enc_pair = (
+            encryption_service.encrypt(pair.0),
+            encryption_service.encrypt(pair.1),
+        );
+        let children: Vec<([u8; 4], [u8; 4])> = SWAPS
+            .iter()
+            .map(|&swap| {
+                let (p0, p1) = swap_and_decrypt(decryption_service, enc_pair, swap);
+                (diagonal_bytes(p0, diag), diagonal_bytes(p1, diag))
+            })
+            .collect();
+        let pair = (diagonal_bytes(pair.0, diag), diagonal_bytes(pair.1, diag));
+        for row in 0..4 {
+            if let [key] = diagonal_candidates(&sbox, pair, &children, row)[..] {
+                if confirms_diagonal(encryption_service, decryption_service, &sbox, diag, key) {
+                    return key;
+                }
+            }
+        }
+    }
+}
+
+/// Row `row` of the difference after SubBytes and MixColumns of the two
+/// columns of `pair` under `key`.
+fn row_difference(sbox: &[u8; 256], pair: ([u8; 4], [u8; 4]), key: [u8; 4], row: usize) -> u8 {
+    (0..4).fold(0, |acc, i| {
+        let diff = sbox[(pair.0[i] ^ key[i]) as usize] ^ sbox[(pair.1[i] ^ key[i]) as usize];
+        acc ^ gf256_mul(MIX_COLUMNS[(4 + i - row) % 4], diff)
+    })
+}
+
+/// Whether `key` holds up as diagonal `diag` of the key on a fresh pair: one
+/// which `key` maps to a difference with a zero byte after the first round,
+/// so that its yoyos keep that zero. A wrong key passes with probability
+/// about 2^-40.
+#[target_feature(enable = "avx2,aes")]
+unsafe fn confirms_diagonal<E: EncryptionService, D: DecryptionService>(
+    encryption_service: &E,
+    decryption_service: &D,
+    sbox: &[u8; 256],
+    diag: usize,
+    key: [u8; 4],
+) -> bool {
+    let bytes = |(p0, p1): (Block, Block)| (diagonal_bytes(p0, diag), diagonal_bytes(p1, diag));
+    let (pair, row) = std::iter::repeat_with(|| gen_pair(diag))
+        .find_map(|pair| {
+            (0..4)
+                .find(|&row| row_difference(sbox, bytes(pair), key, row) == 0)
+                .map(|row| (pair, row))
+        })
+        .unwrap();
+    SWAPS.iter().all(|&swap| {
+        let child = yoyo(encryption_service, decryption_service, pair, swap);
+        row_difference(sbox, bytes(child), key, row) == 0
+    })
+}
+
+/// The yoyo key recovery of Rønjom, Bardeh and Helleseth on 5-round AES,
+/// one diagonal of the key at a time. It needs about 2^11 adaptively chosen
+/// plaintexts and ciphertexts and some 2^27 simple operations, against a
+/// similar number of chosen plaintexts but 2^40 guesses per key column for
+/// `crack_key`. Since the first round key is the key itself, no key schedule
+/// inversion is needed.
+#[target_feature(enable = "avx2,aes")]
+pub unsafe fn crack_key_yoyo<E: EncryptionService, D: DecryptionService>(
+    encryption_service: &E,
+    decryption_service: &D,
+) -> Block {
+    let mut key = [0; BLOCK_SIZE];
+    for diag in 0..4 {
+        let key_diagonal = crack_diagonal(encryption_service, decryption_service, diag);
+        for (pos, byte) in diagonal(diag).into_iter().zip(key_diagonal) {
+            key[pos] = byte;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::AES128;
+
+    const KEY: Block = *b"sixteen byte key";
+
+    #[test]
+    fn test_diagonal() {
+        assert_eq!(diagonal(0), [0, 5, 10, 15]);
+        assert_eq!(diagonal(3), [12, 1, 6, 11]);
+        assert_eq!(anti_diagonal(0), [0, 13, 10, 7]);
+    }
+
+    #[test]
+    fn test_four_round_distinguisher() {
+        unsafe {
+            let aes = AES128::new(KEY, 4);
+            assert!(is_four_round_aes(&aes, &aes));
+            let aes = AES128::new(KEY, 5);
+            assert!(!is_four_round_aes(&aes, &aes));
+        }
+    }
+
+    #[test]
+    fn test_diagonal_candidates() {
+        unsafe {
+            let aes = AES128::new(KEY, 5);
+            let sbox = sbox();
+            let key_diagonal = diagonal_bytes(KEY, 0);
+            let row_difference = |pair: ([u8; 4], [u8; 4]), row| {
+                difference_tables(&sbox, pair.0, pair.1, row)
+                    .iter()
+                    .zip(key_diagonal)
+                    .fold(0, |acc, (table, k)| acc ^ table[k as usize])
+            };
+            // Wait for a pair whose difference after the first round has a
+            // zero byte, which the attack cannot tell without the key.
+            let (p0, p1, row) = std::iter::repeat_with(|| gen_pair(0))
+                .find_map(|(p0, p1)| {
+                    let pair = (diagonal_bytes(p0, 0), diagonal_bytes(p1, 0));
+                    (0..4)
+                        .find(|&row| row_difference(pair, row) == 0)
+                        .map(|row| (p0, p1, row))
+                })
+                .unwrap();
+            let pair = (diagonal_bytes(p0, 0), diagonal_bytes(p1, 0));
+            let children: Vec<([u8; 4], [u8; 4])> = SWAPS
+                .iter()
+                .map(|&swap| {
+                    let (p0, p1) = yoyo(&aes, &aes, (p0, p1), swap);
+                    (diagonal_bytes(p0, 0), diagonal_bytes(p1, 0))
+                })
+                .collect();
+            assert_eq!(
+                diagonal_candidates(&sbox, pair, &children, row),
+                vec![key_diagonal]
+            );
+        }
+    }
+
+    #[test]
+    fn test_crack_key_yoyo() {
+        unsafe {
+            let aes = AES128::new(KEY, 5);
+            assert_eq!(crack_key_yoyo(&aes, &aes), KEY);
+        }
+    }
+}